@@ -1,5 +1,44 @@
 use clap::Parser;
-use std::path::PathBuf;
+use std::{ffi::OsString, fmt, path::PathBuf, str::FromStr};
+
+/// The D-Bus bus to find systemd on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Bus {
+    /// The system bus, with the system manager (PID 1).
+    System,
+    /// The session bus, with the calling user's manager (`systemctl --user`).
+    User,
+    /// An explicit D-Bus address, e.g. `unix:path=/run/user/1000/bus`.
+    Address(String),
+    /// The private socket of a systemd manager, connected to directly rather than through a bus,
+    /// e.g. `unix:path=/run/user/1000/systemd/private`.
+    Private(String),
+}
+
+impl FromStr for Bus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Bus::System),
+            "user" => Ok(Bus::User),
+            address if address.contains(':') => Ok(Bus::Address(address.to_string())),
+            _ => Err(format!(
+                "invalid bus '{s}', expected 'system', 'user' or a D-Bus address"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bus::System => write!(f, "system"),
+            Bus::User => write!(f, "user"),
+            Bus::Address(address) | Bus::Private(address) => write!(f, "{address}"),
+        }
+    }
+}
 
 #[derive(Parser, Debug, PartialEq)]
 #[command(version, about, long_about = None)]
@@ -10,15 +49,142 @@ pub struct Cli {
     #[arg(short = 'd', long, env = "TRAEFIK_LOG_HIDE_DATE", global = true)]
     pub log_hide_date: bool,
 
+    /// Bus to connect to: `system`, `user` or a D-Bus address
+    #[arg(
+        short,
+        long,
+        env = "TRAEFIK_BUS",
+        default_value = "system",
+        global = true
+    )]
+    pub bus: Bus,
+
+    /// Also watch the user manager of every user, writing their files to `user-<UID>` subdirectories
+    #[arg(long, env = "TRAEFIK_ALL_USERS", global = true)]
+    pub all_users: bool,
+
+    /// Only track units whose names match one of these globs, e.g. `public-*.service`
+    #[arg(
+        long,
+        value_name = "GLOB",
+        env = "TRAEFIK_INCLUDE",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub include: Vec<String>,
+
+    /// Do not track units whose names match one of these globs
+    #[arg(
+        long,
+        value_name = "GLOB",
+        env = "TRAEFIK_EXCLUDE",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub exclude: Vec<String>,
+
+    /// Only track units in slices matching one of these globs, e.g. `public.slice`
+    #[arg(
+        long,
+        value_name = "GLOB",
+        env = "TRAEFIK_SLICE",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub slice: Vec<String>,
+
+    /// Only track services running as one of these users (their `User=`)
+    #[arg(
+        long,
+        value_name = "USER",
+        env = "TRAEFIK_SERVICE_USER",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub service_user: Vec<String>,
+
+    /// Only track units with one of these unit file states, e.g. `enabled`, `static` or `transient`
+    #[arg(
+        long,
+        value_name = "STATE",
+        env = "TRAEFIK_UNIT_FILE_STATE",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub unit_file_state: Vec<String>,
+
+    /// Only publish units matching this expression, e.g. `Label("traefik.zone", "public") && !Unit("admin-*")`
+    #[arg(
+        long,
+        value_name = "EXPRESSION",
+        env = "TRAEFIK_CONSTRAINTS",
+        global = true
+    )]
+    pub constraints: Option<String>,
+
+    /// Publish units that have no `traefik.enable` label; with false, only units with `traefik.enable=true` are published
+    #[arg(
+        long,
+        value_name = "BOOL",
+        env = "TRAEFIK_EXPOSED_BY_DEFAULT",
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        global = true
+    )]
+    pub exposed_by_default: bool,
+
+    /// Rule of routers without one, a template like `Host(`{{ normalize .Name }}.apps.example.com`)`; without it, units get no implicit router
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        env = "TRAEFIK_DEFAULT_RULE",
+        global = true
+    )]
+    pub default_rule: Option<String>,
+
+    /// YAML file of named label presets units use with `Preset=`, reloaded on SIGHUP
+    #[arg(long, value_name = "FILE", env = "TRAEFIK_PRESETS", global = true)]
+    pub presets: Option<PathBuf>,
+
+    /// Defaults to /etc/traefik/dynamic/units, or $XDG_CONFIG_HOME/traefik/dynamic/units with `--bus user`
     #[arg(
         short,
         long,
         value_name = "FILE",
         env = "TRAEFIK_OUT_DIR",
-        default_value = "/etc/traefik/dynamic/units",
         global = true
     )]
-    pub traefik_out_dir: PathBuf,
+    pub traefik_out_dir: Option<PathBuf>,
+}
+
+impl Cli {
+    pub fn traefik_out_dir(&self) -> Result<PathBuf, String> {
+        self.traefik_out_dir_with_env(|name| std::env::var_os(name))
+    }
+
+    fn traefik_out_dir_with_env(
+        &self,
+        var: impl Fn(&str) -> Option<OsString>,
+    ) -> Result<PathBuf, String> {
+        if let Some(dir) = &self.traefik_out_dir {
+            return Ok(dir.clone());
+        }
+        match self.bus {
+            Bus::User => {
+                let config_home = match var("XDG_CONFIG_HOME") {
+                    Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+                    _ => PathBuf::from(
+                        var("HOME").ok_or("neither XDG_CONFIG_HOME nor HOME are set")?,
+                    )
+                    .join(".config"),
+                };
+                Ok(config_home.join("traefik/dynamic/units"))
+            }
+            Bus::System | Bus::Address(_) | Bus::Private(_) => {
+                Ok(PathBuf::from("/etc/traefik/dynamic/units"))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -34,9 +200,13 @@ mod tests {
         let args = Vec::from(BASIC_ARGS);
 
         let cli = Cli::parse_from(args);
+        assert_eq!(cli.bus, Bus::System);
+        assert!(cli.exposed_by_default);
+        assert_eq!(cli.default_rule, None);
+        assert_eq!(cli.presets, None);
         assert_eq!(
             "/etc/traefik/dynamic/units",
-            cli.traefik_out_dir.to_str().unwrap()
+            cli.traefik_out_dir().unwrap().to_str().unwrap()
         );
     }
 
@@ -47,6 +217,96 @@ mod tests {
             .chain(vec!["--traefik-out-dir", "/tmp/traefik"])
             .collect::<Vec<_>>();
         let cli = Cli::parse_from(args);
-        assert_eq!(cli.traefik_out_dir, PathBuf::from("/tmp/traefik"));
+        assert_eq!(
+            cli.traefik_out_dir().unwrap(),
+            PathBuf::from("/tmp/traefik")
+        );
+    }
+
+    #[test]
+    fn test_cli_with_filters() {
+        let args = Vec::from(BASIC_ARGS)
+            .into_iter()
+            .chain([
+                "--include",
+                "public-*.service,www.service",
+                "--exclude",
+                "public-admin.service",
+                "--slice",
+                "public.slice",
+                "--service-user",
+                "www-data",
+                "--unit-file-state",
+                "enabled",
+                "--unit-file-state",
+                "transient",
+            ])
+            .collect::<Vec<_>>();
+        let cli = Cli::parse_from(args);
+        assert_eq!(cli.include, vec!["public-*.service", "www.service"]);
+        assert_eq!(cli.exclude, vec!["public-admin.service"]);
+        assert_eq!(cli.slice, vec!["public.slice"]);
+        assert_eq!(cli.service_user, vec!["www-data"]);
+        assert_eq!(cli.unit_file_state, vec!["enabled", "transient"]);
+    }
+
+    #[test]
+    fn test_cli_exposed_by_default() {
+        let parse = |value: &str| {
+            Cli::try_parse_from(
+                Vec::from(BASIC_ARGS)
+                    .into_iter()
+                    .chain(["--exposed-by-default", value]),
+            )
+            .map(|c| c.exposed_by_default)
+        };
+        assert!(!parse("false").unwrap());
+        assert!(parse("true").unwrap());
+        assert!(parse("maybe").is_err());
+    }
+
+    #[test]
+    fn test_cli_with_bus() {
+        let parse = |bus: &str| {
+            Cli::try_parse_from(Vec::from(BASIC_ARGS).into_iter().chain(["--bus", bus]))
+                .map(|c| c.bus)
+        };
+        assert_eq!(parse("system").unwrap(), Bus::System);
+        assert_eq!(parse("user").unwrap(), Bus::User);
+        assert_eq!(
+            parse("unix:path=/tmp/dbus-test").unwrap(),
+            Bus::Address("unix:path=/tmp/dbus-test".to_string())
+        );
+        assert!(parse("session").is_err());
+    }
+
+    #[test]
+    fn test_cli_user_bus_defaults_to_xdg_config_home() {
+        let args = Vec::from(BASIC_ARGS)
+            .into_iter()
+            .chain(["--bus", "user"])
+            .collect::<Vec<_>>();
+        let cli = Cli::parse_from(args);
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| OsString::from(v))
+            }
+        };
+        assert_eq!(
+            cli.traefik_out_dir_with_env(env(&[
+                ("XDG_CONFIG_HOME", "/home/alice/.xdg"),
+                ("HOME", "/home/alice")
+            ]))
+            .unwrap(),
+            PathBuf::from("/home/alice/.xdg/traefik/dynamic/units")
+        );
+        assert_eq!(
+            cli.traefik_out_dir_with_env(env(&[("HOME", "/home/alice")]))
+                .unwrap(),
+            PathBuf::from("/home/alice/.config/traefik/dynamic/units")
+        );
+        assert!(cli.traefik_out_dir_with_env(env(&[])).is_err());
     }
 }
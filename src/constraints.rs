@@ -0,0 +1,384 @@
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use std::{iter::Peekable, str::FromStr};
+
+use crate::filter::glob_to_regex;
+
+/// A constraints expression like the ones of Traefik's Docker provider, e.g.
+/// `Label("traefik.zone", "public") && !Unit("admin-*.service")`.
+#[derive(Debug, Clone)]
+pub enum Constraint {
+    /// The label has exactly this value.
+    Label(String, String),
+    /// The label has a value matching the regex.
+    LabelRegex(String, Regex),
+    /// The unit name matches the glob.
+    Unit(Regex),
+    /// The unit's slice matches the glob.
+    Slice(Regex),
+    And(Box<Constraint>, Box<Constraint>),
+    Or(Box<Constraint>, Box<Constraint>),
+    Not(Box<Constraint>),
+}
+
+/// What a constraint is evaluated against.
+pub struct ConstraintTarget<'a> {
+    pub unit: &'a str,
+    pub slice: &'a str,
+    /// `key=value` lines, as read from the `Label=` directives. Later ones win.
+    pub labels: &'a [String],
+}
+
+impl ConstraintTarget<'_> {
+    /// The value of the last label with this key. Keys are case-insensitive, as in the generated
+    /// configuration.
+    fn label(&self, key: &str) -> Option<&str> {
+        self.labels.iter().rev().find_map(|line| {
+            line.split_once('=')
+                .filter(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.trim())
+        })
+    }
+}
+
+impl Constraint {
+    pub fn matches(&self, target: &ConstraintTarget) -> bool {
+        match self {
+            Constraint::Label(key, value) => target.label(key) == Some(value),
+            Constraint::LabelRegex(key, regex) => {
+                target.label(key).is_some_and(|v| regex.is_match(v))
+            }
+            Constraint::Unit(glob) => glob.is_match(target.unit),
+            Constraint::Slice(glob) => glob.is_match(target.slice),
+            Constraint::And(left, right) => left.matches(target) && right.matches(target),
+            Constraint::Or(left, right) => left.matches(target) || right.matches(target),
+            Constraint::Not(inner) => !inner.matches(target),
+        }
+    }
+
+    /// Whether the expression looks at the slice, which has to be read from systemd.
+    pub fn uses_slice(&self) -> bool {
+        match self {
+            Constraint::Slice(_) => true,
+            Constraint::And(left, right) | Constraint::Or(left, right) => {
+                left.uses_slice() || right.uses_slice()
+            }
+            Constraint::Not(inner) => inner.uses_slice(),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Constraint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut tokens = tokenize(s)?.into_iter().peekable();
+        let constraint = parse_or(&mut tokens)?;
+        if let Some(token) = tokens.next() {
+            bail!("unexpected {token:?} in constraints '{s}'");
+        }
+        Ok(constraint)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    LParen,
+    RParen,
+    Comma,
+    And,
+    Or,
+    Not,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            ',' => Token::Comma,
+            '!' => Token::Not,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '"' | '`' => {
+                let quote = c;
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some(c) if c == quote => break,
+                        // backticks are raw, as in Traefik rules
+                        Some('\\') if quote == '"' => match chars.next() {
+                            Some(escaped) => value.push(escaped),
+                            None => bail!("unterminated string in constraints '{s}'"),
+                        },
+                        Some(c) => value.push(c),
+                        None => bail!("unterminated string in constraints '{s}'"),
+                    }
+                }
+                Token::Str(value)
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut ident = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    ident.push(c);
+                }
+                Token::Ident(ident)
+            }
+            c => bail!("unexpected '{c}' in constraints '{s}'"),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+type Tokens = Peekable<std::vec::IntoIter<Token>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Constraint> {
+    let mut left = parse_and(tokens)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        left = Constraint::Or(Box::new(left), Box::new(parse_and(tokens)?));
+    }
+    Ok(left)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Constraint> {
+    let mut left = parse_unary(tokens)?;
+    while tokens.next_if_eq(&Token::And).is_some() {
+        left = Constraint::And(Box::new(left), Box::new(parse_unary(tokens)?));
+    }
+    Ok(left)
+}
+
+fn parse_unary(tokens: &mut Tokens) -> Result<Constraint> {
+    match tokens.next() {
+        Some(Token::Not) => Ok(Constraint::Not(Box::new(parse_unary(tokens)?))),
+        Some(Token::LParen) => {
+            let inner = parse_or(tokens)?;
+            expect(tokens, Token::RParen)?;
+            Ok(inner)
+        }
+        Some(Token::Ident(name)) => parse_call(&name, tokens),
+        Some(token) => bail!("unexpected {token:?}"),
+        None => bail!("unexpected end of constraints"),
+    }
+}
+
+fn parse_call(name: &str, tokens: &mut Tokens) -> Result<Constraint> {
+    expect(tokens, Token::LParen)?;
+    let mut args = vec![];
+    loop {
+        match tokens.next() {
+            Some(Token::Str(arg)) => args.push(arg),
+            token => bail!("expected a string argument to {name}, got {token:?}"),
+        }
+        match tokens.next() {
+            Some(Token::Comma) => continue,
+            Some(Token::RParen) => break,
+            token => bail!("expected ',' or ')' in {name}, got {token:?}"),
+        }
+    }
+    let arity = |n: usize| {
+        if args.len() == n {
+            Ok(())
+        } else {
+            Err(anyhow!("{name} takes {n} argument(s), got {}", args.len()))
+        }
+    };
+    Ok(match name {
+        "Label" => {
+            arity(2)?;
+            Constraint::Label(args[0].clone(), args[1].clone())
+        }
+        "LabelRegex" => {
+            arity(2)?;
+            Constraint::LabelRegex(args[0].clone(), Regex::new(&args[1])?)
+        }
+        "Unit" => {
+            arity(1)?;
+            Constraint::Unit(glob_to_regex(&args[0])?)
+        }
+        "Slice" => {
+            arity(1)?;
+            Constraint::Slice(glob_to_regex(&args[0])?)
+        }
+        _ => bail!("unknown constraint function {name}"),
+    })
+}
+
+fn expect(tokens: &mut Tokens, expected: Token) -> Result<()> {
+    match tokens.next() {
+        Some(token) if token == expected => Ok(()),
+        token => bail!("expected {expected:?}, got {token:?}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(expression: &str, unit: &str, slice: &str, labels: &[&str]) -> bool {
+        let labels = labels.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        expression
+            .parse::<Constraint>()
+            .unwrap()
+            .matches(&ConstraintTarget {
+                unit,
+                slice,
+                labels: &labels,
+            })
+    }
+
+    #[test]
+    fn test_label() {
+        let labels = ["traefik.zone=public", "traefik.tier=web"];
+        assert!(matches(
+            r#"Label("traefik.zone", "public")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(matches(
+            "Label(`traefik.zone`,`public`)",
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(!matches(
+            r#"Label("traefik.zone", "internal")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(!matches(
+            r#"Label("traefik.other", "public")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(matches(
+            r#"Label("traefik.zone", "internal")"#,
+            "a.service",
+            "",
+            &["traefik.zone=public", "traefik.zone=internal"]
+        ));
+        assert!(matches(
+            r#"Label("traefik.zone", "public")"#,
+            "a.service",
+            "",
+            &["Traefik.Zone=public"]
+        ));
+    }
+
+    #[test]
+    fn test_label_regex() {
+        let labels = ["traefik.zone=public-eu"];
+        assert!(matches(
+            r#"LabelRegex("traefik.zone", "^public-.+$")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(!matches(
+            r#"LabelRegex("traefik.zone", "^internal")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+        assert!(!matches(
+            r#"LabelRegex("traefik.other", ".*")"#,
+            "a.service",
+            "",
+            &labels
+        ));
+    }
+
+    #[test]
+    fn test_unit_and_slice() {
+        assert!(matches(
+            r#"Unit("web-*.service")"#,
+            "web-1.service",
+            "",
+            &[]
+        ));
+        assert!(!matches(r#"Unit("web-*.service")"#, "db.service", "", &[]));
+        assert!(matches(
+            r#"Slice("public.slice")"#,
+            "a.service",
+            "public.slice",
+            &[]
+        ));
+        assert!(!matches(
+            r#"Slice("public.slice")"#,
+            "a.service",
+            "system.slice",
+            &[]
+        ));
+    }
+
+    #[test]
+    fn test_operators_and_precedence() {
+        let labels = ["traefik.zone=public"];
+        assert!(matches(
+            r#"Label("traefik.zone", "public") && !Unit("admin*")"#,
+            "web.service",
+            "",
+            &labels
+        ));
+        assert!(!matches(
+            r#"Label("traefik.zone", "public") && !Unit("admin*")"#,
+            "admin.service",
+            "",
+            &labels
+        ));
+        // && binds tighter than ||
+        assert!(matches(
+            r#"Unit("a*") || Unit("b*") && Unit("c*")"#,
+            "a.service",
+            "",
+            &[]
+        ));
+        assert!(!matches(
+            r#"(Unit("a*") || Unit("b*")) && Unit("c*")"#,
+            "a.service",
+            "",
+            &[]
+        ));
+        assert!(matches(r#"!!Unit("a*")"#, "a.service", "", &[]));
+    }
+
+    #[test]
+    fn test_uses_slice() {
+        let uses_slice = |e: &str| e.parse::<Constraint>().unwrap().uses_slice();
+        assert!(!uses_slice(r#"Unit("a") || !Label("a", "b")"#));
+        assert!(uses_slice(
+            r#"Unit("a") || !(Label("a", "b") && Slice("s"))"#
+        ));
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        for expression in [
+            "",
+            "Label(\"a\")",
+            "Label(\"a\", \"b\"",
+            "Label(\"a\", \"b\") &&",
+            "Label(\"a\", \"b\") & Unit(\"c\")",
+            "Host(\"a\")",
+            "LabelRegex(\"a\", \"(\")",
+            "Unit(\"a\") Unit(\"b\")",
+            "Unit(\"unterminated)",
+            "(Unit(\"a\")",
+        ] {
+            assert!(
+                expression.parse::<Constraint>().is_err(),
+                "should not parse: {expression}"
+            );
+        }
+    }
+}
@@ -1,6 +1,20 @@
-use std::{collections::HashMap, path::Path, pin::Pin, sync::Arc};
-
-use crate::{helpers::*, infra::FileSystem};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+};
+
+use crate::{
+    args::Bus,
+    directives::Directives,
+    environment::{Environment, expand, parse_assignments, parse_environment_file},
+    filter::{UnitFilter, UnitMetadata},
+    infra::FileSystem,
+    schema,
+    unit_file::{Specifiers, section_directives, unescape_value},
+    yaml::unit_router_name,
+};
 
 use anyhow::{Context, Result};
 use async_trait::async_trait;
@@ -14,12 +28,33 @@ pub struct DBusContext<'a> {
     conn: Option<Box<Connection>>,
     manager: Arc<dyn SystemdManager + 'a + Send + Sync>,
     fs: Arc<dyn FileSystem>,
+    filter: Arc<UnitFilter>,
+    /// Unit directories of the manager by precedence, where drop-ins of units it has not loaded
+    /// are looked for.
+    unit_dirs: Arc<Vec<String>>,
 }
 
 pub type UnitList = Arc<RwLock<HashMap<String, UnitData>>>;
+/// The `ActiveState` changes of every unit, from `receive_state_changes`.
+pub type StateChanges = Pin<Box<dyn Stream<Item = Result<UnitPropertiesChanged>> + Send>>;
 pub struct UnitData {
     proxy: Box<dyn SystemdUnit>,
     pub name: String,
+    /// Labels and presets read when the unit was last (re)scanned, used to detect configuration
+    /// changes.
+    labels: Vec<String>,
+    presets: Vec<String>,
+    /// Last known `ActiveState`. Units only found as unit files are "inactive".
+    pub active_state: String,
+}
+
+/// Traefik labels of a unit and the unit files they were read from.
+#[derive(Debug, PartialEq)]
+pub struct TraefikConfig {
+    pub source_files: Vec<String>,
+    /// Names of the presets from `Preset=`, whose labels come before `labels`.
+    pub presets: Vec<String>,
+    pub labels: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -28,9 +63,27 @@ pub struct JobEvent {
     pub started: bool,
 }
 
-#[derive(Debug)]
-pub struct NewUnit {
-    pub unit: String,
+/// Changes to the list of watched units, sent from `watch_units` to `get_messages`.
+#[derive(Debug, PartialEq)]
+pub enum UnitEvent {
+    New {
+        unit: String,
+    },
+    /// The unit's configuration changed, or it just became watched while possibly already running.
+    Changed {
+        unit: String,
+    },
+    Removed {
+        unit: String,
+    },
+}
+
+/// Why `get_messages` returned.
+#[derive(Debug, PartialEq)]
+pub enum SessionEnd {
+    Shutdown,
+    /// A stream from systemd ended, so the connection must be set up again.
+    Disconnected,
 }
 
 pub struct NewUnitArgs {
@@ -38,12 +91,27 @@ pub struct NewUnitArgs {
     unit: String,
 }
 
+pub struct UnitRemovedArgs {
+    id: String,
+}
+
+/// A `PropertiesChanged` signal for the `org.freedesktop.systemd1.Unit` interface that carries a new `ActiveState`.
+#[derive(Debug)]
+pub struct UnitPropertiesChanged {
+    path: String,
+    active_state: String,
+}
+
+const UNIT_PATH_PREFIX: &str = "/org/freedesktop/systemd1/unit";
+
 #[cfg_attr(test, mockall::automock)]
 #[async_trait]
 pub trait SystemdManager: Send + Sync {
     #[allow(clippy::type_complexity)]
-    async fn list_units(
+    async fn list_units_by_patterns(
         &self,
+        states: Vec<String>,
+        patterns: Vec<String>,
     ) -> Result<
         Vec<(
             String,
@@ -61,8 +129,28 @@ pub trait SystemdManager: Send + Sync {
     async fn receive_unit_new(
         &self,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<NewUnitArgs>> + Send>>>;
+    async fn receive_unit_removed(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<UnitRemovedArgs>> + Send>>>;
+    /// Yields `true` when systemd starts reloading (e.g. `daemon-reload`) and `false` when it is done.
+    async fn receive_reloading(&self) -> Result<Pin<Box<dyn Stream<Item = Result<bool>> + Send>>>;
+    async fn receive_unit_files_changed(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<()>> + Send>>>;
+    /// Unit files with their enablement state, e.g. `("/etc/systemd/system/app.service", "enabled")`.
+    async fn list_unit_files_by_patterns(
+        &self,
+        states: Vec<String>,
+        patterns: Vec<String>,
+    ) -> Result<Vec<(String, String)>>;
     async fn load_unit(&self, name: &str) -> Result<String>;
     async fn get_unit(&self, path: String) -> Result<Box<dyn SystemdUnit>>;
+    /// Asks systemd to emit unit and job signals, which it otherwise only sends if some client subscribed.
+    async fn subscribe(&self) -> Result<()>;
+    /// A single stream with the `ActiveState` changes of every unit.
+    async fn receive_unit_properties_changed(&self) -> Result<StateChanges>;
+    /// Yields whenever the owner of the systemd bus name changes, e.g. on `daemon-reexec`.
+    async fn receive_manager_restarted(&self) -> Result<Pin<Box<dyn Stream<Item = ()> + Send>>>;
 }
 
 #[cfg_attr(test, mockall::automock)]
@@ -71,20 +159,27 @@ pub trait SystemdUnit: Send + Sync {
     async fn drop_in_paths(&self) -> Result<Vec<String>>;
     async fn fragment_path(&self) -> Result<String>;
     async fn active_state(&self) -> Result<String>;
-    async fn receive_active_state_changed(
-        &self,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send>>>;
+    async fn unit_file_state(&self) -> Result<String>;
+    async fn slice(&self) -> Result<String>;
+    /// The `User=` of the service.
+    async fn user(&self) -> Result<String>;
+    /// The `Environment=` assignments of the service, as `KEY=VALUE`.
+    async fn environment(&self) -> Result<Vec<String>>;
+    /// The `EnvironmentFile=` paths of the service, with whether they may be missing (`-` prefix).
+    async fn environment_files(&self) -> Result<Vec<(String, bool)>>;
 }
 
 impl DBusContext<'static> {
     pub async fn watch_units(
         &self,
         units_lock: UnitList,
-    ) -> (
+    ) -> Result<(
         Vec<tokio::task::JoinHandle<()>>,
-        tokio::sync::mpsc::Receiver<NewUnit>,
-    ) {
-        let (tx_new_unit, rx_new_unit) = tokio::sync::mpsc::channel::<NewUnit>(100);
+        tokio::sync::mpsc::Receiver<UnitEvent>,
+    )> {
+        let (tx_unit_event, rx_unit_event) = tokio::sync::mpsc::channel::<UnitEvent>(100);
+        let tx_removed_unit = tx_unit_event.clone();
+        let tx_rescan = tx_unit_event.clone();
         let units_lock_new_clone = units_lock.clone();
         let self_new_clone = self.clone();
         let h1 = tokio::spawn(async move {
@@ -114,14 +209,15 @@ impl DBusContext<'static> {
                     }
                 }
                 if let Some(unit_data) = self_new_clone
-                    .create_unit(name.clone(), args.unit.clone())
+                    // a unit that was just loaded is not running yet; changes arrive as PropertiesChanged
+                    .create_unit(name.clone(), args.unit.clone(), "inactive".to_string())
                     .await
                 {
                     let mut units = units_lock_new_clone.write().await;
                     let unit_name = unit_data.name.clone();
                     trace!("Adding unit {} to watched list", unit_name);
                     units.insert(unit_name.clone(), unit_data);
-                    if let Err(e) = tx_new_unit.send(NewUnit { unit: unit_name }).await {
+                    if let Err(e) = tx_unit_event.send(UnitEvent::New { unit: unit_name }).await {
                         error!("Error sending new unit event: {:#}", e);
                     }
                 } else {
@@ -129,61 +225,205 @@ impl DBusContext<'static> {
                 }
             }
         });
-        (vec![h1], rx_new_unit)
+        let units_lock_removed_clone = units_lock.clone();
+        let self_removed_clone = self.clone();
+        let h2 = tokio::spawn(async move {
+            let mut unit_removed_stream =
+                match self_removed_clone.manager.receive_unit_removed().await {
+                    Ok(s) => s,
+                    Err(e) => {
+                        error!("Error receiving unit removed stream: {:#}", e);
+                        return;
+                    }
+                };
+            while let Some(unit_res) = unit_removed_stream.next().await {
+                let args = match unit_res {
+                    Ok(args) => args,
+                    Err(e) => {
+                        error!("Error getting unit args: {:#}", e);
+                        continue;
+                    }
+                };
+                if !units_lock_removed_clone.read().await.contains_key(&args.id) {
+                    continue;
+                }
+                // systemd unloads units nothing references, e.g. stopped ones or the ones loaded
+                // to track them as inactive, so keep those still configured in their unit files
+                match self_removed_clone
+                    .unit_file_has_traefik_config(&args.id)
+                    .await
+                {
+                    Ok(true) => {
+                        trace!("Unit {} was unloaded, tracking it as inactive", &args.id);
+                        if let Some(unit) = units_lock_removed_clone.write().await.get_mut(&args.id)
+                        {
+                            unit.active_state = "inactive".to_string();
+                        }
+                        continue;
+                    }
+                    Ok(false) => {}
+                    Err(e) => error!("Error reading unit files of {}: {:#}", &args.id, e),
+                }
+                if units_lock_removed_clone
+                    .write()
+                    .await
+                    .remove(&args.id)
+                    .is_none()
+                {
+                    continue;
+                }
+                trace!("Removed unit {} from watched list", &args.id);
+                if let Err(e) = tx_removed_unit
+                    .send(UnitEvent::Removed { unit: args.id })
+                    .await
+                {
+                    error!("Error sending removed unit event: {:#}", e);
+                }
+            }
+        });
+        let units_lock_rescan_clone = units_lock.clone();
+        let self_rescan_clone = self.clone();
+        let h3 = tokio::spawn(async move {
+            let reloaded = match self_rescan_clone.manager.receive_reloading().await {
+                Ok(s) => s.filter_map(|active| async move {
+                    match active {
+                        Ok(true) => None,
+                        // render all units again, their environment files may have changed
+                        Ok(false) => Some(Ok(("daemon reload", true))),
+                        Err(e) => Some(Err(e)),
+                    }
+                }),
+                Err(e) => {
+                    error!("Error receiving reloading stream: {:#}", e);
+                    return;
+                }
+            };
+            let unit_files_changed =
+                match self_rescan_clone.manager.receive_unit_files_changed().await {
+                    Ok(s) => s.map(|res| res.map(|_| ("unit files changed", false))),
+                    Err(e) => {
+                        error!("Error receiving unit files changed stream: {:#}", e);
+                        return;
+                    }
+                };
+            let mut rescan_stream = futures::stream::select(reloaded.boxed(), unit_files_changed);
+            while let Some(reason) = rescan_stream.next().await {
+                let (reason, render_all) = match reason {
+                    Ok(reason) => reason,
+                    Err(e) => {
+                        error!("Error getting rescan signal: {:#}", e);
+                        continue;
+                    }
+                };
+                debug!("Rescanning units after {reason}");
+                let events = match self_rescan_clone
+                    .rescan_units(&units_lock_rescan_clone, render_all)
+                    .await
+                {
+                    Ok(events) => events,
+                    Err(e) => {
+                        error!("Error rescanning units: {:#}", e);
+                        continue;
+                    }
+                };
+                for event in events {
+                    if let Err(e) = tx_rescan.send(event).await {
+                        error!("Error sending rescanned unit event: {:#}", e);
+                    }
+                }
+            }
+        });
+        Ok((vec![h1, h2, h3], rx_unit_event))
+    }
+
+    /// Re-reads the configuration files of all services, starting to watch units that gained
+    /// an `X-Traefik` section and forgetting the ones that lost it. With `render_all`, units whose
+    /// configuration did not change are rendered again too, e.g. for their environment files.
+    async fn rescan_units(
+        &self,
+        units_lock: &UnitList,
+        render_all: bool,
+    ) -> Result<Vec<UnitEvent>> {
+        let scanned = self.scan_units().await?;
+        let mut units = units_lock.write().await;
+        let mut events = vec![];
+        let removed = units
+            .keys()
+            .filter(|name| !scanned.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+        for unit in removed {
+            info!("Unit {unit} no longer has Traefik configuration");
+            units.remove(&unit);
+            events.push(UnitEvent::Removed { unit });
+        }
+        for (name, unit_data) in scanned {
+            let changed = match units.get(&name) {
+                Some(existing) => {
+                    render_all
+                        || existing.labels != unit_data.labels
+                        || existing.presets != unit_data.presets
+                }
+                None => {
+                    info!("Unit {name} now has Traefik configuration");
+                    true
+                }
+            };
+            if changed {
+                trace!("Configuration of unit {name} changed");
+                units.insert(name.clone(), unit_data);
+                events.push(UnitEvent::Changed { unit: name });
+            }
+        }
+        Ok(events)
     }
 
+    /// Dispatches unit events and state changes until shutdown is requested or the connection to
+    /// systemd is lost, which the caller should handle by reconnecting.
     pub async fn get_messages(
         &self,
         tx_new_job_event: tokio::sync::mpsc::Sender<JobEvent>,
         watched_map: UnitList,
-        mut rx_new_unit: tokio::sync::mpsc::Receiver<NewUnit>,
-    ) -> Result<()> {
-        let units = watched_map.read().await.keys().cloned().collect::<Vec<_>>();
-        let initial_watched_units_count = units.len();
-        debug!("Watching {} units.", initial_watched_units_count);
-        let mut has_initial_units = initial_watched_units_count > 0;
-        let streams_of_changes = units
-            .into_iter()
-            .async_map(|unit_name| async move { self.create_changes_stream(unit_name).await })
-            .await
-            .into_iter()
-            .flatten();
-        let mut changes_stream = futures::stream::select_all(streams_of_changes);
-        let mut done = false;
-        use tokio::signal::unix::{SignalKind, signal};
-        let mut sigint = match signal(SignalKind::interrupt()) {
-            Err(err) => {
-                eprintln!("Error listening for SIGINT (Ctrl+C) signal: {err}");
-                std::process::exit(1);
-            }
-            Ok(sigint) => sigint,
-        };
-        let mut sigterm = match signal(SignalKind::terminate()) {
-            Err(err) => {
-                eprintln!("Error listening for SIGTERM signal: {err}");
-                std::process::exit(1);
-            }
-            Ok(sigterm) => sigterm,
-        };
+        mut changes_stream: StateChanges,
+        mut rx_unit_event: tokio::sync::mpsc::Receiver<UnitEvent>,
+        mut shutdown: tokio::sync::watch::Receiver<bool>,
+    ) -> Result<SessionEnd> {
+        debug!("Watching {} units.", watched_map.read().await.len());
+        let mut manager_restarted = self.manager.receive_manager_restarted().await?;
         loop {
-            if done {
-                break;
-            }
             tokio::select! {
-                event = rx_new_unit.recv() => {
-                    if let Some(event) = event {
-                        info!("New unit being wached: {}", &event.unit);
-                        let new_unit_changes_stream = self.create_changes_stream(event.unit).await;
-                        changes_stream.extend(new_unit_changes_stream);
-                        has_initial_units  = true;
-                    } else {
-                        trace!("New unit channel closed");
-                        done = true;
+                event = rx_unit_event.recv() => {
+                    match event {
+                        Some(UnitEvent::New { unit }) => info!("New unit being wached: {}", &unit),
+                        Some(UnitEvent::Changed { unit }) => {
+                            let started = match self.is_unit_running(unit.clone()).await {
+                                Ok(running) => running,
+                                Err(e) => {
+                                    error!("Error checking if unit {unit} is running: {e}");
+                                    continue;
+                                }
+                            };
+                            let job = JobEvent { unit_name: unit, started };
+                            if let Err(e) = tx_new_job_event.send(job).await {
+                                error!("Error sending message: {:#}", e);
+                            }
+                        }
+                        Some(UnitEvent::Removed { unit }) => {
+                            info!("Unit no longer watched: {}", &unit);
+                            let job = JobEvent { unit_name: unit, started: false };
+                            if let Err(e) = tx_new_job_event.send(job).await {
+                                error!("Error sending message: {:#}", e);
+                            }
+                        }
+                        None => {
+                            trace!("New unit channel closed");
+                            return Ok(SessionEnd::Disconnected);
+                        }
                     }
                 }
-                property_changed_fut_opt = changes_stream.next(), if has_initial_units => {
-                    if let Some(property_changed_fut) = property_changed_fut_opt {
-                        let job = match property_changed_fut.await {
+                property_changed_opt = changes_stream.next() => {
+                    if let Some(property_changed) = property_changed_opt {
+                        let job = match self.job_from_properties_changed(property_changed, &watched_map).await {
                             Some(the_job) => the_job,
                             None => continue,
                         };
@@ -192,34 +432,61 @@ impl DBusContext<'static> {
                             Ok(_) => trace!("Message sent to channel"),
                         }
                     } else {
-                        trace!("Changes streams closed");
-                        done = true;
+                        trace!("Changes stream closed");
+                        return Ok(SessionEnd::Disconnected);
                     }
                 }
-                _ = sigint.recv() => {
-                    trace!("SIGINT (Ctrl+C) received, stopping...");
-                    done = true;
+                _ = manager_restarted.next() => {
+                    info!("systemd manager went away or was re-executed");
+                    return Ok(SessionEnd::Disconnected);
                 }
-                _ = sigterm.recv() => {
-                    trace!("SIGTERM received, stopping...");
-                    done = true;
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        trace!("Shutdown requested");
+                        return Ok(SessionEnd::Shutdown);
+                    }
                 }
             };
         }
-        Ok(())
     }
 }
 
 impl<'a> DBusContext<'a> {
-    pub async fn new() -> Result<Self> {
-        let conn = Connection::system()
-            .await
-            .context("connect to system bus")?;
+    pub async fn new(bus: &Bus, filter: Arc<UnitFilter>) -> Result<Self> {
+        let conn = match bus {
+            Bus::System => Connection::system()
+                .await
+                .context("connect to system bus")?,
+            // the user manager is reachable on the session bus
+            Bus::User => Connection::session()
+                .await
+                .context("connect to session bus")?,
+            Bus::Address(address) => zbus::connection::Builder::address(address.as_str())?
+                .build()
+                .await
+                .with_context(|| format!("connect to bus at {address}"))?,
+            // a manager's private socket has no bus daemon, so there is no Hello and no names
+            Bus::Private(address) => zbus::connection::Builder::address(address.as_str())?
+                .p2p()
+                .build()
+                .await
+                .with_context(|| format!("connect to systemd at {address}"))?,
+        };
         let proxy = crate::manager::ManagerProxy::new(&conn).await?;
+        // user managers search the home and runtime directories of their user, so ask them
+        let unit_dirs = match bus {
+            Bus::System => UNIT_DIRS.map(str::to_string).to_vec(),
+            _ => proxy
+                .unit_path()
+                .await
+                .context("reading the unit search path")?,
+        };
         Ok(Self {
             conn: Some(Box::new(conn)),
             manager: Arc::new(RealSystemdManager { proxy }),
             fs: Arc::new(crate::infra::RealFileSystem),
+            filter,
+            unit_dirs: Arc::new(unit_dirs),
         })
     }
 
@@ -232,20 +499,22 @@ impl<'a> DBusContext<'a> {
             manager,
             fs,
             conn: None,
+            filter: Arc::new(UnitFilter::default()),
+            unit_dirs: Arc::new(UNIT_DIRS.map(str::to_string).to_vec()),
         }
     }
 
-    pub async fn list_units(&self) -> Result<UnitList> {
-        let units = self.manager.list_units().await?;
-        let mut units_map = HashMap::new();
-        for unit in units {
-            let name = unit.0;
-            let object_path = unit.6;
-            if let Some(unit_data) = self.create_unit(name, object_path.to_string()).await {
-                units_map.insert(unit_data.name.clone(), unit_data);
-            }
+    /// Makes the context search `unit_dirs`, e.g. the ones of a user manager.
+    #[cfg(test)]
+    pub fn with_unit_dirs(self, unit_dirs: &[&str]) -> Self {
+        Self {
+            unit_dirs: Arc::new(unit_dirs.iter().map(|dir| dir.to_string()).collect()),
+            ..self
         }
-        let unit_list = Arc::new(RwLock::new(units_map));
+    }
+
+    pub async fn list_units(&self) -> Result<UnitList> {
+        let unit_list = Arc::new(RwLock::new(self.scan_units().await?));
         if log_enabled!(log::Level::Debug) {
             let units = unit_list.read().await;
             let names = units.keys().cloned().collect::<Vec<_>>();
@@ -254,8 +523,116 @@ impl<'a> DBusContext<'a> {
         Ok(unit_list)
     }
 
-    async fn create_unit(&self, name: String, object_path: String) -> Option<UnitData> {
-        if !name.ends_with(".service") {
+    /// Services with Traefik configuration: the loaded ones, plus the ones only found as unit files
+    /// (e.g. enabled but stopped, or never started), which get loaded to read their drop-ins.
+    /// A unit file that is not loaded is found when the `[X-Traefik]` section is in the file itself
+    /// or in one of its drop-ins in the manager's unit directories.
+    async fn scan_units(&self) -> Result<HashMap<String, UnitData>> {
+        let mut units = HashMap::new();
+        let mut loaded = HashSet::new();
+        let units_listed = self
+            .manager
+            .list_units_by_patterns(vec![], self.filter.patterns())
+            .await?;
+        for unit in units_listed {
+            loaded.insert(unit.0.clone());
+            if let Some(unit_data) = self.create_unit(unit.0, unit.6.to_string(), unit.3).await {
+                units.insert(unit_data.name.clone(), unit_data);
+            }
+        }
+        let unit_files = self
+            .manager
+            .list_unit_files_by_patterns(self.filter.unit_file_states(), self.filter.patterns())
+            .await?;
+        for (path, state) in unit_files {
+            let Some(name) = Path::new(&path).file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            // templates cannot run by themselves, only their instances
+            if loaded.contains(name) || name.ends_with("@.service") || state == "masked" {
+                continue;
+            }
+            match self.files_have_traefik_config(&self.unit_file_paths(&path)) {
+                Ok(true) => {}
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("Error reading unit file {path}: {:#}", e);
+                    continue;
+                }
+            }
+            let object_path = match self.manager.load_unit(name).await {
+                Ok(object_path) => object_path,
+                Err(e) => {
+                    error!("Error loading unit {name}: {:#}", e);
+                    continue;
+                }
+            };
+            if let Some(unit_data) = self
+                .create_unit(name.to_string(), object_path, "inactive".to_string())
+                .await
+            {
+                debug!("Found unit {name} that is not loaded, tracking it as inactive");
+                units.insert(unit_data.name.clone(), unit_data);
+            }
+        }
+        Ok(units)
+    }
+
+    /// A unit file and the drop-ins of the unit in the manager's unit directories, as systemd would
+    /// apply them, for units systemd has not loaded.
+    fn unit_file_paths(&self, path: &str) -> Vec<String> {
+        let path = Path::new(path);
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return vec![];
+        };
+        let drop_in_dir = format!("{}.d", name.to_string_lossy());
+        let dirs = std::iter::once(dir).chain(
+            self.unit_dirs
+                .iter()
+                .map(Path::new)
+                .filter(|search_dir| *search_dir != dir),
+        );
+        let mut drop_ins = vec![];
+        for dir in dirs.map(|dir| dir.join(&drop_in_dir)) {
+            if !self.fs.dir_exists(&dir) {
+                continue;
+            }
+            match self.fs.read_dir(&dir) {
+                Ok(files) => drop_ins.extend(
+                    files
+                        .into_iter()
+                        .filter(|file| file.extension().is_some_and(|ext| ext == "conf"))
+                        .map(|file| file.to_string_lossy().into_owned()),
+                ),
+                Err(e) => warn!("Error reading drop-ins in {}: {:#}", dir.display(), e),
+            }
+        }
+        let mut paths = vec![path.to_string_lossy().into_owned()];
+        paths.extend(order_drop_ins(drop_ins, &self.unit_dirs));
+        paths
+    }
+
+    /// Whether the unit file of a unit, with its drop-ins, still has Traefik configuration.
+    async fn unit_file_has_traefik_config(&self, name: &str) -> Result<bool> {
+        let unit_files = self
+            .manager
+            .list_unit_files_by_patterns(vec![], vec![name.to_string()])
+            .await?;
+        for (path, state) in unit_files {
+            if state != "masked" && self.files_have_traefik_config(&self.unit_file_paths(&path))? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn create_unit(
+        &self,
+        name: String,
+        object_path: String,
+        active_state: String,
+    ) -> Option<UnitData> {
+        if !name.ends_with(".service") || !self.filter.matches_name(&name) {
             return None;
         }
         trace!("Creating unit {}", name);
@@ -266,9 +643,25 @@ impl<'a> DBusContext<'a> {
                 return None;
             }
         };
-        let unit_data = UnitData {
+        if self.filter.needs_metadata() {
+            match unit_metadata(proxy.as_ref()).await {
+                Ok(metadata) if self.filter.matches_metadata(&metadata) => {}
+                Ok(metadata) => {
+                    trace!("Unit {name} filtered out: {metadata:?}");
+                    return None;
+                }
+                Err(e) => {
+                    error!("Error reading properties of unit {name}: {:#}", e);
+                    return None;
+                }
+            }
+        }
+        let mut unit_data = UnitData {
             proxy,
             name: name.clone(),
+            labels: vec![],
+            presets: vec![],
+            active_state,
         };
         let is_tracked = match self
             .has_traefik_config_in_configuration_files(&unit_data)
@@ -280,10 +673,26 @@ impl<'a> DBusContext<'a> {
                 return None;
             }
         };
-        if is_tracked {
-            return Some(unit_data);
+        if !is_tracked {
+            return None;
+        }
+        match self
+            .get_traefik_yaml_config_from_configuration_files(&unit_data)
+            .await
+        {
+            Ok(config) => {
+                unit_data.labels = config.labels;
+                unit_data.presets = config.presets;
+            }
+            Err(e) => {
+                error!(
+                    "Error reading Traefik configuration of unit {name}: {:#}",
+                    e
+                );
+                return None;
+            }
         }
-        None
+        Some(unit_data)
     }
 
     pub async fn is_unit_running(&self, unit_name: String) -> Result<bool> {
@@ -297,29 +706,83 @@ impl<'a> DBusContext<'a> {
         Ok(state == "active")
     }
 
+    pub async fn get_unit_slice(&self, unit_data: &UnitData) -> Result<String> {
+        unit_data.proxy.slice().await
+    }
+
     pub async fn get_traefik_yaml_config_from_configuration_files(
         &self,
         unit_data: &UnitData,
-    ) -> Result<Vec<String>> {
+    ) -> Result<TraefikConfig> {
         let files = self.get_config_files_for_unit(unit_data).await?;
-        let lines = self
-            .get_traefik_config_from_configuration_files(files)
-            .await?;
-        Ok(lines)
+        self.get_traefik_config_from_configuration_files(&unit_data.name, files)
+            .await
+    }
+
+    /// Expands the variables of the unit's `Environment=` and `EnvironmentFile=` in the values of
+    /// its labels. Values forced to strings with `!!str` are taken literally.
+    pub async fn expand_environment(
+        &self,
+        unit_data: &UnitData,
+        labels: Vec<String>,
+    ) -> Result<Vec<String>> {
+        let has_variables = |label: &String| {
+            label.split_once('=').is_some_and(|(_, value)| {
+                value.contains('$') && schema::forced_string(value).is_none()
+            })
+        };
+        if !labels.iter().any(has_variables) {
+            return Ok(labels);
+        }
+        let env = self.get_unit_environment(unit_data).await?;
+        labels
+            .into_iter()
+            .map(|label| {
+                let Some((key, value)) = label.split_once('=') else {
+                    return Ok(label);
+                };
+                if schema::forced_string(value).is_some() {
+                    return Ok(label);
+                }
+                let value =
+                    expand(value, &env).with_context(|| format!("expanding label '{label}'"))?;
+                Ok(format!("{key}={value}"))
+            })
+            .collect()
+    }
+
+    /// As in systemd, variables from environment files override the ones from `Environment=`, and
+    /// later files override earlier ones.
+    async fn get_unit_environment(&self, unit_data: &UnitData) -> Result<Environment> {
+        let mut env: Environment = parse_assignments(&unit_data.proxy.environment().await?)
+            .into_iter()
+            .collect();
+        for (file, optional) in unit_data.proxy.environment_files().await? {
+            match self.fs.read_to_string(Path::new(&file)) {
+                Ok(text) => env.extend(parse_environment_file(&text)),
+                Err(e) if optional => trace!("Skipping optional EnvironmentFile {file}: {e:#}"),
+                Err(e) => return Err(e.context(format!("reading EnvironmentFile {file}"))),
+            }
+        }
+        Ok(env)
     }
 
+    /// The unit file first, then its drop-ins in the order systemd applies them, so later files
+    /// override earlier ones.
     async fn get_config_files_for_unit(&self, unit_data: &UnitData) -> Result<Vec<String>> {
-        let mut all_paths: Vec<_> = unit_data
+        let mut all_paths = vec![];
+        let fragment_path = unit_data.proxy.fragment_path().await?;
+        if self.fs.exists(std::path::Path::new(&fragment_path)) {
+            all_paths.push(fragment_path);
+        }
+        let drop_ins = unit_data
             .proxy
             .drop_in_paths()
             .await?
             .into_iter()
             .filter(|p| self.fs.exists(std::path::Path::new(&p)))
             .collect();
-        let fragment_path = unit_data.proxy.fragment_path().await?;
-        if self.fs.exists(std::path::Path::new(&fragment_path)) {
-            all_paths.push(fragment_path);
-        }
+        all_paths.extend(order_drop_ins(drop_ins, &self.unit_dirs));
         if all_paths.is_empty() {
             trace!("No config file for service: {}", unit_data.name);
         } else if all_paths.len() == 1 {
@@ -338,13 +801,16 @@ impl<'a> DBusContext<'a> {
         unit_data: &UnitData,
     ) -> Result<bool> {
         let files = self.get_config_files_for_unit(unit_data).await?;
-        for file in &files {
+        self.files_have_traefik_config(&files)
+    }
+
+    fn files_have_traefik_config(&self, files: &[String]) -> Result<bool> {
+        for file in files {
             trace!("Checking config file {}", file);
             let text = self.fs.read_to_string(Path::new(file))?;
-            let parser = systemd_lsp::SystemdParser::new();
-            let unit_config = parser.parse(&text);
-            if unit_config.sections.contains_key("X-Traefik") {
-                debug!("Found X-Traefik in {} for service {}", file, unit_data.name);
+            // read as the labels are, so a unit is tracked exactly when it has some
+            if section_directives(&text, "X-Traefik").is_some() {
+                debug!("Found X-Traefik in {}", file);
                 return Ok(true);
             }
         }
@@ -353,79 +819,226 @@ impl<'a> DBusContext<'a> {
 
     async fn get_traefik_config_from_configuration_files(
         &self,
+        unit: &str,
         files: Vec<String>,
-    ) -> Result<Vec<String>> {
+    ) -> Result<TraefikConfig> {
+        let specifiers = Specifiers::new(unit, self.fs.as_ref());
+        let mut settings = Directives::default();
         let mut lines = vec![];
+        // where the last Scheme= is, to name it when it is not used
+        let mut scheme_at = None;
         for file in &files {
             let text = self.fs.read_to_string(Path::new(file))?;
-            let parser = systemd_lsp::SystemdParser::new();
-            let unit_config = parser.parse(&text);
-            if let Some(section) = unit_config.sections.get("X-Traefik") {
-                trace!("Found X-Traefik in {}", file);
-                for directive in section.directives.iter().filter(|d| d.key == "Label") {
-                    lines.push(directive.value.to_owned());
-                }
-            } else {
+            let Some(directives) = section_directives(&text, "X-Traefik") else {
                 trace!("Missing X-Traefik section in {}", file);
                 continue;
+            };
+            trace!("Found X-Traefik in {}", file);
+            for directive in &directives {
+                let key = directive.key.as_str();
+                if key != "Label" && !Directives::KEYS.contains(&key) {
+                    warn!(
+                        "Ignoring unknown directive {key}= at {}:{}",
+                        file, directive.line
+                    );
+                    continue;
+                }
+                // as with systemd list settings, an empty assignment resets the list, here with
+                // the directives, which stand for labels too
+                if key == "Label" && directive.value.is_empty() {
+                    lines.clear();
+                    settings = Directives::default();
+                    scheme_at = None;
+                    continue;
+                }
+                let result = unescape_value(&directive.value, &specifiers).and_then(|value| {
+                    match key {
+                        "Label" => lines.push(value),
+                        _ => settings.set(key, &value)?,
+                    }
+                    Ok(())
+                });
+                // systemd ignores assignments it cannot parse, too
+                match result {
+                    Ok(()) if key == "Scheme" => {
+                        scheme_at = Some(format!("{file}:{}", directive.line))
+                    }
+                    Ok(()) => {}
+                    Err(e) => warn!(
+                        "Ignoring invalid {key}= at {}:{}: {:#}",
+                        file, directive.line, e
+                    ),
+                }
             }
         }
-        Ok(lines)
+        if let Some(at) = scheme_at
+            && settings.scheme_without_port()
+        {
+            warn!("Ignoring Scheme= at {at}, as there is no Port= for it");
+        }
+        // raw labels come last, so they override the ones of the directives
+        let mut labels = settings.labels(&unit_router_name(unit));
+        labels.extend(lines);
+        Ok(TraefikConfig {
+            source_files: files,
+            presets: settings.presets().to_vec(),
+            labels,
+        })
+    }
+
+    /// UIDs of the user managers (`user@UID.service`) that are currently running.
+    pub async fn running_user_managers(&self) -> Result<Vec<u32>> {
+        Ok(self
+            .manager
+            .list_units_by_patterns(vec![], vec!["user@*.service".to_string()])
+            .await?
+            .into_iter()
+            .filter(|unit| unit.3 == "active")
+            .filter_map(|unit| uid_from_user_manager_unit(&unit.0))
+            .collect())
+    }
+
+    /// Subscribes to systemd signals and starts receiving the state changes of units. Called before
+    /// listing units, so no change in between is missed.
+    pub async fn receive_state_changes(&self) -> Result<StateChanges> {
+        self.manager
+            .subscribe()
+            .await
+            .context("subscribing to systemd signals")?;
+        self.manager.receive_unit_properties_changed().await
     }
 
-    async fn create_changes_stream(
+    /// Starts and stops of user managers, as `(uid, running)`.
+    pub async fn receive_user_manager_changes(
         &self,
-        unit_name: String,
-    ) -> Option<Pin<Box<dyn Stream<Item = impl Future<Output = Option<JobEvent>>> + Send>>> {
-        let obj_path = match self.manager.load_unit(unit_name.as_str()).await {
-            Ok(obj_path) => obj_path,
-            Err(e) => {
-                error!("Error loading unit: {:#}", e);
-                return None;
+    ) -> Result<Pin<Box<dyn Stream<Item = (u32, bool)> + Send>>> {
+        let stream = self.receive_state_changes().await?;
+        Ok(Box::pin(stream.filter_map(|change| async move {
+            let change = match change {
+                Ok(change) => change,
+                Err(e) => {
+                    error!("Error getting property changed: {:#}", e);
+                    return None;
+                }
+            };
+            let uid = uid_from_user_manager_unit(&unit_name_from_object_path(&change.path)?)?;
+            match change.active_state.as_str() {
+                "active" => Some((uid, true)),
+                "inactive" | "failed" | "deactivating" => Some((uid, false)),
+                _ => None,
             }
-        };
-        let unit_opt = match self.manager.get_unit(obj_path.to_string()).await {
-            Ok(unit) => Some(unit),
+        })))
+    }
+
+    async fn job_from_properties_changed(
+        &self,
+        property_changed: Result<UnitPropertiesChanged>,
+        watched_map: &UnitList,
+    ) -> Option<JobEvent> {
+        let property_changed = match property_changed {
+            Ok(x) => x,
             Err(e) => {
-                error!("Error getting unit: {:#}", e);
+                error!("Error getting property changed: {:#}", e);
                 return None;
             }
         };
-        let unit = match unit_opt {
-            Some(unit) => unit,
-            None => {
-                error!("Error getting unit");
-                return None;
-            }
+        let unit_name = unit_name_from_object_path(&property_changed.path)?;
+        watched_map
+            .write()
+            .await
+            .get_mut(&unit_name)?
+            .active_state
+            .clone_from(&property_changed.active_state);
+        let job = JobEvent {
+            unit_name,
+            started: property_changed.active_state == "active",
         };
-        let stream = match unit.receive_active_state_changed().await {
-            Ok(s) => s,
-            Err(e) => {
-                error!("Error getting active state changed stream: {:#}", e);
-                return None;
+        trace!("New job: {:?}", &job);
+        Some(job)
+    }
+}
+
+async fn unit_metadata(unit: &dyn SystemdUnit) -> Result<UnitMetadata> {
+    Ok(UnitMetadata {
+        slice: unit.slice().await?,
+        user: unit.user().await?,
+        unit_file_state: unit.unit_file_state().await?,
+    })
+}
+
+/// System unit directories by precedence, as systemd searches them: a drop-in in an earlier one
+/// masks drop-ins with the same file name in later ones. User managers report their own with
+/// `UnitPath`.
+const UNIT_DIRS: [&str; 13] = [
+    "/etc/systemd/system.control",
+    "/run/systemd/system.control",
+    "/run/systemd/transient",
+    "/run/systemd/generator.early",
+    "/etc/systemd/system",
+    "/etc/systemd/system.attached",
+    "/run/systemd/system",
+    "/run/systemd/system.attached",
+    "/run/systemd/generator",
+    "/usr/local/lib/systemd/system",
+    "/usr/lib/systemd/system",
+    "/lib/systemd/system",
+    "/run/systemd/generator.late",
+];
+
+/// Orders drop-ins as systemd applies them: by file name, whatever directory they are in, keeping
+/// only the one with the highest precedence for each file name, by the position of its directory in
+/// `unit_dirs`. Drop-ins outside of `unit_dirs` rank last.
+fn order_drop_ins(paths: Vec<String>, unit_dirs: &[String]) -> Vec<String> {
+    let precedence = |path: &str| {
+        unit_dirs
+            .iter()
+            .position(|dir| Path::new(path).starts_with(dir))
+            .unwrap_or(usize::MAX)
+    };
+    let mut by_name: BTreeMap<String, String> = BTreeMap::new();
+    for path in paths {
+        let Some(name) = Path::new(&path).file_name() else {
+            continue;
+        };
+        let name = name.to_string_lossy().into_owned();
+        match by_name.get(&name) {
+            Some(existing) if precedence(existing) <= precedence(&path) => {
+                trace!("Drop-in {path} is masked by {existing}");
             }
-        }
-        .map(move |property_changed| {
-            let unit_name_clone = unit_name.clone();
-            async move {
-                let state = match property_changed {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("Error getting property changed: {:#}", e);
-                        return None;
-                    }
-                };
-                let job = JobEvent {
-                    unit_name: unit_name_clone,
-                    started: state == "active",
-                };
-                trace!("New job: {:?}", &job);
-                Some(job)
+            _ => {
+                by_name.insert(name, path);
             }
-        })
-        .boxed();
-        Some(stream)
+        }
+    }
+    by_name.into_values().collect()
+}
+
+/// Parses the UID out of the name of a user manager unit, e.g. `user@1000.service`.
+pub fn uid_from_user_manager_unit(name: &str) -> Option<u32> {
+    name.strip_prefix("user@")?
+        .strip_suffix(".service")?
+        .parse()
+        .ok()
+}
+
+/// Reverses systemd's bus label escaping of unit object paths, e.g.
+/// `/org/freedesktop/systemd1/unit/sleep_2eservice` is `sleep.service`.
+fn unit_name_from_object_path(path: &str) -> Option<String> {
+    let label = path.strip_prefix(UNIT_PATH_PREFIX)?.strip_prefix('/')?;
+    let bytes = label.as_bytes();
+    let mut name = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'_' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            name.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            name.push(bytes[i]);
+            i += 1;
+        }
     }
+    String::from_utf8(name).ok()
 }
 
 pub struct RealSystemdManager<'a> {
@@ -434,8 +1047,10 @@ pub struct RealSystemdManager<'a> {
 
 #[async_trait]
 impl SystemdManager for RealSystemdManager<'static> {
-    async fn list_units(
+    async fn list_units_by_patterns(
         &self,
+        states: Vec<String>,
+        patterns: Vec<String>,
     ) -> Result<
         Vec<(
             String,
@@ -450,7 +1065,12 @@ impl SystemdManager for RealSystemdManager<'static> {
             zbus::zvariant::OwnedObjectPath,
         )>,
     > {
-        Ok(self.proxy.list_units().await?)
+        let states = states.iter().map(String::as_str).collect::<Vec<_>>();
+        let patterns = patterns.iter().map(String::as_str).collect::<Vec<_>>();
+        Ok(self
+            .proxy
+            .list_units_by_patterns(&states, &patterns)
+            .await?)
     }
 
     async fn receive_unit_new(
@@ -467,22 +1087,121 @@ impl SystemdManager for RealSystemdManager<'static> {
             as Pin<Box<dyn Stream<Item = Result<NewUnitArgs>> + Send>>)
     }
 
-    async fn load_unit(&self, name: &str) -> Result<String> {
-        let path = self.proxy.load_unit(name).await?;
-        Ok(path.to_string())
+    async fn receive_unit_removed(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<UnitRemovedArgs>> + Send>>> {
+        let stream = self.proxy.receive_unit_removed().await?;
+        Ok(Box::pin(stream.map(|msg| {
+            let args = msg.args().map_err(|e| anyhow::anyhow!(e))?;
+            Ok(UnitRemovedArgs {
+                id: args.id().to_string(),
+            })
+        }))
+            as Pin<
+                Box<dyn Stream<Item = Result<UnitRemovedArgs>> + Send>,
+            >)
     }
 
-    async fn get_unit(&self, path: String) -> Result<Box<dyn SystemdUnit>> {
-        let proxy = crate::unit::UnitProxy::builder(self.proxy.as_ref().connection())
+    async fn receive_reloading(&self) -> Result<Pin<Box<dyn Stream<Item = Result<bool>> + Send>>> {
+        let stream = self.proxy.receive_reloading().await?;
+        Ok(Box::pin(stream.map(|msg| {
+            let args = msg.args().map_err(|e| anyhow::anyhow!(e))?;
+            Ok(*args.active())
+        }))
+            as Pin<Box<dyn Stream<Item = Result<bool>> + Send>>)
+    }
+
+    async fn receive_unit_files_changed(
+        &self,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<()>> + Send>>> {
+        let stream = self.proxy.receive_unit_files_changed().await?;
+        Ok(Box::pin(stream.map(|_| Ok(()))) as Pin<Box<dyn Stream<Item = Result<()>> + Send>>)
+    }
+
+    async fn list_unit_files_by_patterns(
+        &self,
+        states: Vec<String>,
+        patterns: Vec<String>,
+    ) -> Result<Vec<(String, String)>> {
+        let states = states.iter().map(String::as_str).collect::<Vec<_>>();
+        let patterns = patterns.iter().map(String::as_str).collect::<Vec<_>>();
+        Ok(self
+            .proxy
+            .list_unit_files_by_patterns(&states, &patterns)
+            .await?)
+    }
+
+    async fn load_unit(&self, name: &str) -> Result<String> {
+        let path = self.proxy.load_unit(name).await?;
+        Ok(path.to_string())
+    }
+
+    async fn get_unit(&self, path: String) -> Result<Box<dyn SystemdUnit>> {
+        // no property caching, as it would add one match rule per unit
+        let connection = self.proxy.as_ref().connection();
+        let proxy = crate::unit::UnitProxy::builder(connection)
+            .path(path.clone())?
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await?;
+        let service = crate::service::ServiceProxy::builder(connection)
             .path(path)?
+            .cache_properties(zbus::proxy::CacheProperties::No)
             .build()
             .await?;
-        Ok(Box::new(RealSystemdUnit { proxy }) as Box<dyn SystemdUnit>)
+        Ok(Box::new(RealSystemdUnit { proxy, service }) as Box<dyn SystemdUnit>)
+    }
+
+    async fn subscribe(&self) -> Result<()> {
+        Ok(self.proxy.subscribe().await?)
+    }
+
+    async fn receive_unit_properties_changed(&self) -> Result<StateChanges> {
+        let rule = zbus::MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender("org.freedesktop.systemd1")?
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .path_namespace(UNIT_PATH_PREFIX)?
+            .build();
+        let stream =
+            zbus::MessageStream::for_match_rule(rule, self.proxy.as_ref().connection(), None)
+                .await?;
+        Ok(Box::pin(stream.filter_map(|msg| async move {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(e) => return Some(Err(anyhow::anyhow!(e))),
+            };
+            let path = msg.header().path()?.to_string();
+            let (interface, changed, _invalidated) = match msg.body().deserialize::<(
+                String,
+                HashMap<String, zbus::zvariant::OwnedValue>,
+                Vec<String>,
+            )>() {
+                Ok(body) => body,
+                Err(e) => return Some(Err(anyhow::anyhow!(e))),
+            };
+            if interface != "org.freedesktop.systemd1.Unit" {
+                return None;
+            }
+            let active_state =
+                String::try_from(changed.get("ActiveState")?.try_clone().ok()?).ok()?;
+            Some(Ok(UnitPropertiesChanged { path, active_state }))
+        }))
+            as Pin<
+                Box<dyn Stream<Item = Result<UnitPropertiesChanged>> + Send>,
+            >)
+    }
+
+    async fn receive_manager_restarted(&self) -> Result<Pin<Box<dyn Stream<Item = ()> + Send>>> {
+        let stream = self.proxy.inner().receive_owner_changed().await?;
+        Ok(Box::pin(stream.map(|_| ())) as Pin<Box<dyn Stream<Item = ()> + Send>>)
     }
 }
 
 pub struct RealSystemdUnit<'a> {
     proxy: crate::unit::UnitProxy<'a>,
+    service: crate::service::ServiceProxy<'a>,
 }
 
 #[async_trait]
@@ -499,22 +1218,31 @@ impl SystemdUnit for RealSystemdUnit<'static> {
         Ok(self.proxy.active_state().await?)
     }
 
-    async fn receive_active_state_changed(
-        &self,
-    ) -> Result<Pin<Box<dyn Stream<Item = Result<String>> + Send>>> {
-        let stream = self.proxy.receive_active_state_changed().await;
-        Ok(Box::pin(stream.then(|msg| async move {
-            let v = msg.get().await.map_err(|e| anyhow::anyhow!(e))?;
-            Ok(v)
-        }))
-            as Pin<Box<dyn Stream<Item = Result<String>> + Send>>)
+    async fn unit_file_state(&self) -> Result<String> {
+        Ok(self.proxy.unit_file_state().await?)
+    }
+
+    async fn slice(&self) -> Result<String> {
+        Ok(self.service.slice().await?)
+    }
+
+    async fn user(&self) -> Result<String> {
+        Ok(self.service.user().await?)
+    }
+
+    async fn environment(&self) -> Result<Vec<String>> {
+        Ok(self.service.environment().await?)
+    }
+
+    async fn environment_files(&self) -> Result<Vec<(String, bool)>> {
+        Ok(self.service.environment_files().await?)
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
-    use crate::infra::tests::MockFileSystem;
+    use crate::infra::{RealFileSystem, tests::MockFileSystem};
     use std::sync::Arc;
 
     #[tokio::test]
@@ -548,183 +1276,1424 @@ mod tests {
         assert!(is_running);
     }
 
-    #[tokio::test]
-    async fn test_list_units() {
-        let mut mock_manager = MockSystemdManager::new();
-        let mock_fs = Arc::new(MockFileSystem::new());
+    #[tokio::test]
+    async fn test_list_units() {
+        let mut mock_manager = MockSystemdManager::new();
+        let mock_fs = Arc::new(MockFileSystem::new());
+
+        let unit_name = "test.service".to_string();
+        let object_path = zbus::zvariant::OwnedObjectPath::try_from(
+            "/org/freedesktop/systemd1/unit/test_service",
+        )
+        .unwrap();
+
+        let unit_name_clone = unit_name.clone();
+        let object_path_clone = object_path.clone();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .return_once(move |_, _| {
+                Ok(vec![(
+                    unit_name_clone,
+                    "loaded".into(),
+                    "active".into(),
+                    "running".into(),
+                    "".into(),
+                    "".into(),
+                    object_path_clone.clone(),
+                    0,
+                    "".into(),
+                    object_path_clone,
+                )])
+            });
+
+        mock_manager.expect_get_unit().returning(move |_| {
+            let mut u = MockSystemdUnit::new();
+            u.expect_drop_in_paths().returning(|| Ok(vec![]));
+            u.expect_fragment_path()
+                .returning(|| Ok("/lib/systemd/system/test.service".to_string()));
+            Ok(Box::new(u))
+        });
+
+        mock_fs.add_file(
+            "/lib/systemd/system/test.service",
+            "[X-Traefik]\nLabel=test",
+        );
+        expect_no_unit_files(&mut mock_manager);
+
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+        let units = context.list_units().await.unwrap();
+        let units = units.read().await;
+        assert!(units.contains_key("test.service"));
+    }
+
+    #[tokio::test]
+    async fn test_list_units_discovers_units_that_are_not_loaded() {
+        let mut mock_manager = MockSystemdManager::new();
+        let mut app = listed_unit("app.service");
+        app.3 = "active".to_string();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .return_once(move |_, _| Ok(vec![app]));
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .with(
+                mockall::predicate::eq(Vec::<String>::new()),
+                mockall::predicate::eq(vec!["*.service".to_string()]),
+            )
+            .return_once(|_, _| {
+                Ok(vec![
+                    ("/etc/systemd/system/app.service".into(), "enabled".into()),
+                    ("/etc/systemd/system/idle.service".into(), "disabled".into()),
+                    ("/etc/systemd/system/plain.service".into(), "enabled".into()),
+                    ("/etc/systemd/system/tmpl@.service".into(), "static".into()),
+                    ("/etc/systemd/system/hidden.service".into(), "masked".into()),
+                ])
+            });
+        mock_manager
+            .expect_load_unit()
+            .with(mockall::predicate::eq("idle.service"))
+            .times(1)
+            .returning(|name| Ok(unit_object_path(name)));
+        expect_units_with_fragments(&mut mock_manager);
+        let mock_fs = Arc::new(MockFileSystem::new());
+        for name in ["app", "idle", "tmpl@", "hidden"] {
+            mock_fs.add_file(
+                format!("/etc/systemd/system/{name}.service"),
+                "[X-Traefik]\nLabel=a=1",
+            );
+        }
+        mock_fs.add_file(
+            "/etc/systemd/system/plain.service",
+            "[Service]\nType=simple",
+        );
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+
+        let units = context.list_units().await.unwrap();
+
+        let units = units.read().await;
+        let mut states = units
+            .values()
+            .map(|u| (u.name.as_str(), u.active_state.as_str()))
+            .collect::<Vec<_>>();
+        states.sort();
+        assert_eq!(
+            states,
+            vec![("app.service", "active"), ("idle.service", "inactive")]
+        );
+        assert_eq!(units["idle.service"].labels, vec!["a=1"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_units_discovers_units_configured_in_drop_ins() {
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .return_once(|_, _| Ok(vec![]));
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .return_once(|_, _| {
+                Ok(vec![(
+                    "/usr/lib/systemd/system/vendor.service".into(),
+                    "enabled".into(),
+                )])
+            });
+        mock_manager
+            .expect_load_unit()
+            .with(mockall::predicate::eq("vendor.service"))
+            .times(1)
+            .returning(|name| Ok(unit_object_path(name)));
+        mock_manager.expect_get_unit().returning(|_| {
+            let mut u = MockSystemdUnit::new();
+            u.expect_drop_in_paths().returning(|| {
+                Ok(vec![
+                    "/etc/systemd/system/vendor.service.d/traefik.conf".to_string(),
+                ])
+            });
+            u.expect_fragment_path()
+                .returning(|| Ok("/usr/lib/systemd/system/vendor.service".to_string()));
+            Ok(Box::new(u))
+        });
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/usr/lib/systemd/system/vendor.service",
+            "[Service]\nType=simple",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/vendor.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=a=1",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/vendor.service.d/notes.txt",
+            "[X-Traefik]\nLabel=b=2",
+        );
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+
+        let units = context.list_units().await.unwrap();
+
+        let units = units.read().await;
+        assert_eq!(units["vendor.service"].active_state, "inactive");
+        assert_eq!(units["vendor.service"].labels, vec!["a=1"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_units_discovers_drop_ins_on_a_real_file_system() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let fragment = temp_dir.path().join("vendor.service");
+        let drop_in = temp_dir.path().join("vendor.service.d/traefik.conf");
+        std::fs::write(&fragment, "[Service]\nType=simple").unwrap();
+        std::fs::create_dir(drop_in.parent().unwrap()).unwrap();
+        std::fs::write(&drop_in, "[X-Traefik]\nLabel=a=1").unwrap();
+        let fragment = fragment.to_string_lossy().into_owned();
+        let drop_in = drop_in.to_string_lossy().into_owned();
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .return_once(|_, _| Ok(vec![]));
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .returning({
+                let fragment = fragment.clone();
+                move |_, _| Ok(vec![(fragment.clone(), "enabled".into())])
+            });
+        mock_manager
+            .expect_load_unit()
+            .returning(|name| Ok(unit_object_path(name)));
+        mock_manager.expect_get_unit().returning(move |_| {
+            let mut u = MockSystemdUnit::new();
+            let (fragment, drop_in) = (fragment.clone(), drop_in.clone());
+            u.expect_drop_in_paths()
+                .returning(move || Ok(vec![drop_in.clone()]));
+            u.expect_fragment_path()
+                .returning(move || Ok(fragment.clone()));
+            Ok(Box::new(u))
+        });
+        let context =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(RealFileSystem));
+
+        let units = context.list_units().await.unwrap();
+
+        assert_eq!(units.read().await["vendor.service"].labels, vec!["a=1"]);
+        // what keeps the unit tracked when systemd unloads it
+        assert!(
+            context
+                .unit_file_has_traefik_config("vendor.service")
+                .await
+                .unwrap()
+        );
+    }
+
+    /// Unit directories of the manager of user 1000, as its `UnitPath` lists them.
+    const USER_UNIT_DIRS: [&str; 12] = [
+        "/home/alice/.config/systemd/user.control",
+        "/run/user/1000/systemd/user.control",
+        "/run/user/1000/systemd/transient",
+        "/run/user/1000/systemd/generator.early",
+        "/home/alice/.config/systemd/user",
+        "/etc/systemd/user",
+        "/run/user/1000/systemd/user",
+        "/run/systemd/user",
+        "/run/user/1000/systemd/generator",
+        "/home/alice/.local/share/systemd/user",
+        "/usr/lib/systemd/user",
+        "/run/user/1000/systemd/generator.late",
+    ];
+
+    #[test]
+    fn test_unit_file_paths_of_user_units_skip_system_drop_ins() {
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/home/alice/.config/systemd/user/app.service",
+            "[Service]\nType=simple",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/app.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=system=1",
+        );
+        mock_fs.add_file(
+            "/usr/lib/systemd/system/app.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=system=2",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/user/app.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=user=1",
+        );
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs)
+            .with_unit_dirs(&USER_UNIT_DIRS);
+
+        assert_eq!(
+            context.unit_file_paths("/home/alice/.config/systemd/user/app.service"),
+            vec![
+                "/home/alice/.config/systemd/user/app.service",
+                "/etc/systemd/user/app.service.d/traefik.conf",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_files_have_traefik_config_reads_sections_as_systemd() {
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/etc/systemd/system/a.service",
+            "[Service]\nType=simple\n[X-Traefik]\n",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/b.service",
+            "[Unit]\nDescription=a \\\n[X-Traefik]\n# [X-Traefik]\n",
+        );
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
+        let has_config = |file: &str| {
+            context
+                .files_have_traefik_config(&[file.to_string()])
+                .unwrap()
+        };
+
+        assert!(has_config("/etc/systemd/system/a.service"));
+        assert!(!has_config("/etc/systemd/system/b.service"));
+    }
+
+    #[tokio::test]
+    async fn test_list_units_applies_filters() {
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .with(
+                mockall::predicate::eq(Vec::<String>::new()),
+                mockall::predicate::eq(vec!["*.service".to_string()]),
+            )
+            .return_once(|_, _| {
+                Ok(vec![
+                    listed_unit("public.service"),
+                    listed_unit("internal-app.service"),
+                    listed_unit("elsewhere.service"),
+                ])
+            });
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .with(
+                mockall::predicate::eq(vec!["enabled".to_string()]),
+                mockall::predicate::eq(vec!["*.service".to_string()]),
+            )
+            .return_once(|_, _| Ok(vec![]));
+        mock_manager.expect_get_unit().returning(|path| {
+            let name = unit_name_from_object_path(&path).unwrap();
+            assert_ne!(
+                name, "internal-app.service",
+                "excluded by name before loading"
+            );
+            let slice = if name == "public.service" {
+                "public.slice"
+            } else {
+                "system.slice"
+            };
+            let mut u = MockSystemdUnit::new();
+            u.expect_drop_in_paths().returning(|| Ok(vec![]));
+            u.expect_fragment_path()
+                .returning(move || Ok(format!("/etc/systemd/system/{name}")));
+            u.expect_slice().returning(move || Ok(slice.to_string()));
+            u.expect_user().returning(|| Ok(String::new()));
+            u.expect_unit_file_state()
+                .returning(|| Ok("enabled".to_string()));
+            Ok(Box::new(u))
+        });
+        let mock_fs = Arc::new(MockFileSystem::new());
+        for name in ["public", "internal-app", "elsewhere"] {
+            mock_fs.add_file(
+                format!("/etc/systemd/system/{name}.service"),
+                "[X-Traefik]\nLabel=a=1",
+            );
+        }
+        let filter = UnitFilter::new(
+            vec![],
+            vec!["internal-*".to_string()],
+            vec!["public*.slice".to_string()],
+            vec![],
+            vec!["enabled".to_string()],
+        )
+        .unwrap();
+        let context = DBusContext {
+            filter: Arc::new(filter),
+            ..DBusContext::new_test_context(Arc::new(mock_manager), mock_fs)
+        };
+
+        let units = context.list_units().await.unwrap();
+
+        let units = units.read().await;
+        assert_eq!(units.keys().collect::<Vec<_>>(), vec!["public.service"]);
+    }
+
+    #[tokio::test]
+    async fn test_get_traefik_yaml_config_from_configuration_files() {
+        let mut mock_unit = MockSystemdUnit::new();
+        mock_unit.expect_drop_in_paths().returning(|| {
+            Ok(vec![
+                "/etc/systemd/system/test.service.d/traefik.conf".to_string(),
+            ])
+        });
+        mock_unit
+            .expect_fragment_path()
+            .returning(|| Ok("/lib/systemd/system/test.service".to_string()));
+
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/etc/systemd/system/test.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=label1",
+        );
+        mock_fs.add_file(
+            "/lib/systemd/system/test.service",
+            "[X-Traefik]\nLabel=label2",
+        );
+
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
+
+        let unit_data = UnitData {
+            proxy: Box::new(mock_unit),
+            name: "test.service".to_string(),
+            labels: vec![],
+            presets: vec![],
+            active_state: "inactive".to_string(),
+        };
+
+        let config = context
+            .get_traefik_yaml_config_from_configuration_files(&unit_data)
+            .await
+            .unwrap();
+        assert_eq!(
+            config,
+            TraefikConfig {
+                source_files: vec![
+                    "/lib/systemd/system/test.service".to_string(),
+                    "/etc/systemd/system/test.service.d/traefik.conf".to_string(),
+                ],
+                presets: vec![],
+                labels: vec!["label2".to_string(), "label1".to_string()],
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_traefik_yaml_config_from_user_unit_files() {
+        let mut mock_unit = MockSystemdUnit::new();
+        mock_unit.expect_drop_in_paths().returning(|| {
+            Ok(vec![
+                "/home/alice/.config/systemd/user/app.service.d/traefik.conf".to_string(),
+                "/run/user/1000/systemd/transient/app.service.d/missing.conf".to_string(),
+            ])
+        });
+        mock_unit
+            .expect_fragment_path()
+            .returning(|| Ok("/home/alice/.config/systemd/user/app.service".to_string()));
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/home/alice/.config/systemd/user/app.service.d/traefik.conf",
+            "[X-Traefik]\nLabel=label1",
+        );
+        mock_fs.add_file(
+            "/home/alice/.config/systemd/user/app.service",
+            "[X-Traefik]\nLabel=label2",
+        );
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
+        let unit_data = UnitData {
+            proxy: Box::new(mock_unit),
+            name: "app.service".to_string(),
+            labels: vec![],
+            presets: vec![],
+            active_state: "inactive".to_string(),
+        };
+
+        assert!(
+            context
+                .has_traefik_config_in_configuration_files(&unit_data)
+                .await
+                .unwrap()
+        );
+        let config = context
+            .get_traefik_yaml_config_from_configuration_files(&unit_data)
+            .await
+            .unwrap();
+        assert_eq!(
+            config.source_files,
+            vec![
+                "/home/alice/.config/systemd/user/app.service",
+                "/home/alice/.config/systemd/user/app.service.d/traefik.conf",
+            ]
+        );
+        assert_eq!(config.labels, vec!["label2", "label1"]);
+    }
+
+    #[tokio::test]
+    async fn test_expand_environment_in_labels() {
+        let unit_data = |files: Vec<(String, bool)>| {
+            let mut mock_unit = MockSystemdUnit::new();
+            mock_unit.expect_environment().returning(|| {
+                Ok(vec![
+                    "PORT=8080".to_string(),
+                    "HOST=app.example.com".to_string(),
+                    "SCHEME=http".to_string(),
+                ])
+            });
+            mock_unit
+                .expect_environment_files()
+                .returning(move || Ok(files.clone()));
+            UnitData {
+                proxy: Box::new(mock_unit),
+                name: "app.service".to_string(),
+                labels: vec![],
+                presets: vec![],
+                active_state: "active".to_string(),
+            }
+        };
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file("/etc/app/env", "PORT=8081\nHOST=wrong.example.com\n");
+        mock_fs.add_file("/etc/app/env.local", "HOST=local.example.com\n");
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
+        let labels = vec![
+            "traefik.http.services.app.loadbalancer.servers[0].url=${SCHEME}://127.0.0.1:${PORT}"
+                .to_string(),
+            "traefik.http.routers.app.rule=Host(`$HOST`)".to_string(),
+        ];
+        let files = vec![
+            ("/etc/app/env".to_string(), false),
+            ("/etc/app/env.local".to_string(), false),
+            ("/etc/app/missing".to_string(), true),
+        ];
+
+        let expanded = context
+            .expand_environment(&unit_data(files), labels.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            expanded,
+            vec![
+                "traefik.http.services.app.loadbalancer.servers[0].url=http://127.0.0.1:8081",
+                "traefik.http.routers.app.rule=Host(`local.example.com`)",
+            ]
+        );
+
+        let required_missing = vec![("/etc/app/missing".to_string(), false)];
+        let error = context
+            .expand_environment(&unit_data(required_missing), labels)
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("reading EnvironmentFile /etc/app/missing"));
+
+        let error = context
+            .expand_environment(&unit_data(vec![]), vec!["a.b=${UNDEFINED}".to_string()])
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "expanding label 'a.b=${UNDEFINED}': variable UNDEFINED is not defined by Environment= \
+             or EnvironmentFile= of the unit, write a literal $ as $$"
+        );
+        let error = context
+            .expand_environment(
+                &unit_data(vec![]),
+                vec![
+                    "traefik.http.middlewares.auth.basicauth.users=admin:$apr1$H6uskkkW"
+                        .to_string(),
+                ],
+            )
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("variable apr1 is not defined"));
+
+        let expanded = context
+            .expand_environment(
+                &unit_data(vec![]),
+                vec![
+                    "traefik.http.middlewares.auth.basicauth.users=admin:$$apr1$$H6uskkkW"
+                        .to_string(),
+                    "traefik.http.middlewares.auth.basicauth.users=!!str admin:$apr1$H6uskkkW"
+                        .to_string(),
+                    "traefik.http.routers.$PORT.rule=Host(`$HOST`)".to_string(),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            expanded,
+            vec![
+                "traefik.http.middlewares.auth.basicauth.users=admin:$apr1$H6uskkkW",
+                "traefik.http.middlewares.auth.basicauth.users=!!str admin:$apr1$H6uskkkW",
+                "traefik.http.routers.$PORT.rule=Host(`app.example.com`)",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_expand_environment_skips_labels_without_variables() {
+        // no expectations: the unit's environment is not read
+        let unit_data = UnitData {
+            proxy: Box::new(MockSystemdUnit::new()),
+            name: "app.service".to_string(),
+            labels: vec![],
+            presets: vec![],
+            active_state: "active".to_string(),
+        };
+        let context = DBusContext::new_test_context(
+            Arc::new(MockSystemdManager::new()),
+            Arc::new(MockFileSystem::new()),
+        );
+        let labels = vec!["traefik.http.routers.app.rule=Host(`app`)".to_string()];
+        assert_eq!(
+            context
+                .expand_environment(&unit_data, labels.clone())
+                .await
+                .unwrap(),
+            labels
+        );
+    }
+
+    #[test]
+    fn test_order_drop_ins() {
+        let paths = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let system_dirs = paths(&UNIT_DIRS);
+        let order_drop_ins = |drop_ins| order_drop_ins(drop_ins, &system_dirs);
+        assert_eq!(
+            order_drop_ins(paths(&[
+                "/etc/systemd/system/app.service.d/50-b.conf",
+                "/run/systemd/system/app.service.d/10-a.conf",
+                "/usr/lib/systemd/system/app.service.d/50-b.conf",
+                "/usr/lib/systemd/system/service.d/90-c.conf",
+                "/usr/lib/systemd/system/app.service.d/10-a.conf",
+            ])),
+            paths(&[
+                "/run/systemd/system/app.service.d/10-a.conf",
+                "/etc/systemd/system/app.service.d/50-b.conf",
+                "/usr/lib/systemd/system/service.d/90-c.conf",
+            ])
+        );
+        assert_eq!(
+            order_drop_ins(paths(&[
+                "/usr/lib/systemd/system/app.service.d/override.conf",
+                "/etc/systemd/system/app.service.d/override.conf",
+            ])),
+            paths(&["/etc/systemd/system/app.service.d/override.conf"])
+        );
+        assert_eq!(
+            order_drop_ins(paths(&[
+                "/etc/systemd/system/app.service.d/50-traefik.conf",
+                "/run/systemd/transient/app.service.d/50-traefik.conf",
+                "/etc/systemd/system.control/app.service.d/60-limits.conf",
+                "/etc/systemd/system/app.service.d/60-limits.conf",
+                "/somewhere/else/app.service.d/70-x.conf",
+                "/run/systemd/system/app.service.d/70-x.conf",
+            ])),
+            paths(&[
+                "/run/systemd/transient/app.service.d/50-traefik.conf",
+                "/etc/systemd/system.control/app.service.d/60-limits.conf",
+                "/run/systemd/system/app.service.d/70-x.conf",
+            ])
+        );
+    }
+
+    #[test]
+    fn test_order_drop_ins_of_user_units() {
+        let paths = |p: &[&str]| p.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        let user_dirs = paths(&USER_UNIT_DIRS);
+        assert_eq!(
+            order_drop_ins(
+                paths(&[
+                    "/usr/lib/systemd/user/app.service.d/10-port.conf",
+                    "/usr/lib/systemd/user/app.service.d/50-traefik.conf",
+                    "/home/alice/.config/systemd/user/app.service.d/50-traefik.conf",
+                    "/etc/systemd/user/app.service.d/10-port.conf",
+                    "/run/user/1000/systemd/transient/app.service.d/60-limits.conf",
+                    "/home/alice/.config/systemd/user/app.service.d/60-limits.conf",
+                ]),
+                &user_dirs
+            ),
+            paths(&[
+                "/etc/systemd/user/app.service.d/10-port.conf",
+                "/home/alice/.config/systemd/user/app.service.d/50-traefik.conf",
+                "/run/user/1000/systemd/transient/app.service.d/60-limits.conf",
+            ])
+        );
+        // the other way around, /usr/lib never masks ~/.config
+        assert_eq!(
+            order_drop_ins(
+                paths(&[
+                    "/home/alice/.config/systemd/user/app.service.d/50-traefik.conf",
+                    "/usr/lib/systemd/user/app.service.d/50-traefik.conf",
+                ]),
+                &user_dirs
+            ),
+            paths(&["/home/alice/.config/systemd/user/app.service.d/50-traefik.conf"])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_drop_ins_override_and_reset_unit_file_labels() {
+        let mut mock_unit = MockSystemdUnit::new();
+        mock_unit.expect_drop_in_paths().returning(|| {
+            Ok(vec![
+                "/etc/systemd/system/app.service.d/20-reset.conf".to_string(),
+                "/usr/lib/systemd/system/app.service.d/10-port.conf".to_string(),
+                "/etc/systemd/system/app.service.d/30-rule.conf".to_string(),
+            ])
+        });
+        mock_unit
+            .expect_fragment_path()
+            .returning(|| Ok("/usr/lib/systemd/system/app.service".to_string()));
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file(
+            "/usr/lib/systemd/system/app.service",
+            "[X-Traefik]\nLabel=traefik.http.routers.app.rule=Host(`old`)",
+        );
+        mock_fs.add_file(
+            "/usr/lib/systemd/system/app.service.d/10-port.conf",
+            "[X-Traefik]\nLabel=traefik.http.services.app.loadbalancer.server.port=80",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/app.service.d/20-reset.conf",
+            "[X-Traefik]\nLabel=\nLabel=traefik.http.services.app.loadbalancer.server.port=8080",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/app.service.d/30-rule.conf",
+            "[X-Traefik]\nLabel=traefik.http.routers.app.rule=Host(`new`)",
+        );
+        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
+        let unit_data = UnitData {
+            proxy: Box::new(mock_unit),
+            name: "app.service".to_string(),
+            labels: vec![],
+            presets: vec![],
+            active_state: "inactive".to_string(),
+        };
+
+        let config = context
+            .get_traefik_yaml_config_from_configuration_files(&unit_data)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            config.source_files,
+            vec![
+                "/usr/lib/systemd/system/app.service",
+                "/usr/lib/systemd/system/app.service.d/10-port.conf",
+                "/etc/systemd/system/app.service.d/20-reset.conf",
+                "/etc/systemd/system/app.service.d/30-rule.conf",
+            ]
+        );
+        assert_eq!(
+            config.labels,
+            vec![
+                "traefik.http.services.app.loadbalancer.server.port=8080",
+                "traefik.http.routers.app.rule=Host(`new`)",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_units() {
+        let mut mock_manager = MockSystemdManager::new();
+        let args = NewUnitArgs {
+            id: "new.service".to_string(),
+            unit: "/obj/path".to_string(),
+        };
+        mock_manager.expect_receive_unit_new().return_once(move || {
+            Ok(Box::pin(futures::stream::iter(vec![Ok(args)]))
+                as Pin<Box<dyn Stream<Item = Result<NewUnitArgs>> + Send>>)
+        });
+        mock_manager
+            .expect_receive_unit_removed()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        expect_no_rescans(&mut mock_manager);
+
+        mock_manager.expect_get_unit().returning(|_| {
+            let mut u = MockSystemdUnit::new();
+            u.expect_drop_in_paths().returning(|| Ok(vec![]));
+            u.expect_fragment_path()
+                .returning(|| Ok("/lib/systemd/system/new.service".to_string()));
+            Ok(Box::new(u))
+        });
+
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file("/lib/systemd/system/new.service", "[X-Traefik]\nLabel=new");
+
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+        let units_lock = Arc::new(RwLock::new(HashMap::new()));
+
+        let (handles, mut rx_unit_event) = context.watch_units(units_lock.clone()).await.unwrap();
+
+        let event = tokio::time::timeout(
+            tokio::time::Duration::from_millis(500),
+            rx_unit_event.recv(),
+        )
+        .await
+        .expect("Timeout waiting for new unit event")
+        .expect("Channel closed before receiving event");
+        assert_eq!(
+            event,
+            UnitEvent::New {
+                unit: "new.service".to_string()
+            }
+        );
+
+        let units = units_lock.read().await;
+        assert!(units.contains_key("new.service"));
+
+        for h in handles {
+            h.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_units_forgets_removed_units() {
+        let mut mock_manager = MockSystemdManager::new();
+        expect_no_unit_files(&mut mock_manager);
+        expect_no_rescans(&mut mock_manager);
+        mock_manager
+            .expect_receive_unit_new()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        mock_manager.expect_receive_unit_removed().return_once(|| {
+            Ok(Box::pin(futures::stream::iter(vec![
+                Ok(UnitRemovedArgs {
+                    id: "unwatched.service".to_string(),
+                }),
+                Ok(UnitRemovedArgs {
+                    id: "old.service".to_string(),
+                }),
+            ])))
+        });
+
+        let context =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
+        let units_lock = Arc::new(RwLock::new(HashMap::from([
+            ("old.service".to_string(), unit_data("old.service")),
+            ("kept.service".to_string(), unit_data("kept.service")),
+        ])));
+
+        let (handles, mut rx_unit_event) = context.watch_units(units_lock.clone()).await.unwrap();
+
+        let event = tokio::time::timeout(
+            tokio::time::Duration::from_millis(500),
+            rx_unit_event.recv(),
+        )
+        .await
+        .expect("Timeout waiting for removed unit event")
+        .expect("Channel closed before receiving event");
+        assert_eq!(
+            event,
+            UnitEvent::Removed {
+                unit: "old.service".to_string()
+            }
+        );
+        let units = units_lock.read().await;
+        assert!(!units.contains_key("old.service"));
+        assert!(units.contains_key("kept.service"));
+
+        for h in handles {
+            h.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch_units_keeps_unloaded_units_with_traefik_config() {
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .return_once(|_, _| Ok(vec![]));
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .returning(|_, _| {
+                Ok(vec![(
+                    "/etc/systemd/system/idle.service".into(),
+                    "disabled".into(),
+                )])
+            });
+        mock_manager
+            .expect_load_unit()
+            .returning(|name| Ok(unit_object_path(name)));
+        expect_units_with_fragments(&mut mock_manager);
+        expect_no_rescans(&mut mock_manager);
+        mock_manager
+            .expect_receive_unit_new()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        // nothing references the unit that was loaded to track it, so systemd unloads it again
+        mock_manager.expect_receive_unit_removed().return_once(|| {
+            Ok(Box::pin(futures::stream::iter(vec![Ok(UnitRemovedArgs {
+                id: "idle.service".to_string(),
+            })])))
+        });
+        let mock_fs = Arc::new(MockFileSystem::new());
+        mock_fs.add_file("/etc/systemd/system/idle.service", "[X-Traefik]\nLabel=a=1");
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+        let units_lock = context.list_units().await.unwrap();
+
+        let (handles, mut rx_unit_event) = context.watch_units(units_lock.clone()).await.unwrap();
+
+        let event = tokio::time::timeout(
+            tokio::time::Duration::from_millis(100),
+            rx_unit_event.recv(),
+        )
+        .await;
+        assert!(event.is_err(), "Unexpected event {event:?}");
+        let units = units_lock.read().await;
+        assert_eq!(units["idle.service"].active_state, "inactive");
+
+        for h in handles {
+            h.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_stops_removed_units() {
+        let (tx_job, mut rx_job) = tokio::sync::mpsc::channel(10);
+        let (tx_unit_event, rx_unit_event) = tokio::sync::mpsc::channel(10);
+
+        let mut mock_manager = MockSystemdManager::new();
+        expect_no_manager_restart(&mut mock_manager);
+        let context =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
+
+        tx_unit_event
+            .send(UnitEvent::Removed {
+                unit: "old.service".to_string(),
+            })
+            .await
+            .unwrap();
+        drop(tx_unit_event);
+
+        let (_tx_shutdown, rx_shutdown) = tokio::sync::watch::channel(false);
+        context
+            .get_messages(
+                tx_job,
+                Arc::new(RwLock::new(HashMap::new())),
+                Box::pin(futures::stream::pending()),
+                rx_unit_event,
+                rx_shutdown,
+            )
+            .await
+            .unwrap();
+
+        let job = rx_job.recv().await.expect("Missing job for removed unit");
+        assert_eq!(job.unit_name, "old.service");
+        assert!(!job.started);
+    }
+
+    /// Mocks `list_unit_files_by_patterns` so that no unit is found only as a unit file.
+    pub fn expect_no_unit_files(mock_manager: &mut MockSystemdManager) {
+        mock_manager
+            .expect_list_unit_files_by_patterns()
+            .returning(|_, _| Ok(vec![]));
+    }
+
+    fn expect_no_manager_restart(mock_manager: &mut MockSystemdManager) {
+        mock_manager
+            .expect_receive_manager_restarted()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+    }
+
+    fn expect_no_rescans(mock_manager: &mut MockSystemdManager) {
+        mock_manager
+            .expect_receive_reloading()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        mock_manager
+            .expect_receive_unit_files_changed()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+    }
+
+    /// Escapes a unit name into its object path the way systemd does.
+    fn unit_object_path(name: &str) -> String {
+        let label = name
+            .bytes()
+            .map(|b| {
+                if b.is_ascii_alphanumeric() {
+                    (b as char).to_string()
+                } else {
+                    format!("_{b:02x}")
+                }
+            })
+            .collect::<String>();
+        format!("{UNIT_PATH_PREFIX}/{label}")
+    }
 
-        let unit_name = "test.service".to_string();
-        let object_path = zbus::zvariant::OwnedObjectPath::try_from(
-            "/org/freedesktop/systemd1/unit/test_service",
-        )
-        .unwrap();
+    pub fn unit_properties_changed(name: &str, active_state: &str) -> UnitPropertiesChanged {
+        UnitPropertiesChanged {
+            path: unit_object_path(name),
+            active_state: active_state.to_string(),
+        }
+    }
 
-        let unit_name_clone = unit_name.clone();
-        let object_path_clone = object_path.clone();
-        mock_manager.expect_list_units().return_once(move || {
-            Ok(vec![(
-                unit_name_clone,
-                "loaded".into(),
-                "active".into(),
-                "running".into(),
-                "".into(),
-                "".into(),
-                object_path_clone.clone(),
-                0,
-                "".into(),
-                object_path_clone,
-            )])
-        });
+    #[allow(clippy::type_complexity)]
+    pub fn listed_unit(
+        name: &str,
+    ) -> (
+        String,
+        String,
+        String,
+        String,
+        String,
+        String,
+        zbus::zvariant::OwnedObjectPath,
+        u32,
+        String,
+        zbus::zvariant::OwnedObjectPath,
+    ) {
+        let path = zbus::zvariant::OwnedObjectPath::try_from(unit_object_path(name)).unwrap();
+        (
+            name.to_string(),
+            "".into(),
+            "loaded".into(),
+            "active".into(),
+            "running".into(),
+            "".into(),
+            path.clone(),
+            0,
+            "".into(),
+            path,
+        )
+    }
 
-        mock_manager.expect_get_unit().returning(move |_| {
+    /// Mocks `get_unit` so that every unit's fragment is `/etc/systemd/system/<unit name>`.
+    fn expect_units_with_fragments(mock_manager: &mut MockSystemdManager) {
+        mock_manager.expect_get_unit().returning(|path| {
+            let name = unit_name_from_object_path(&path).unwrap();
             let mut u = MockSystemdUnit::new();
             u.expect_drop_in_paths().returning(|| Ok(vec![]));
             u.expect_fragment_path()
-                .returning(|| Ok("/lib/systemd/system/test.service".to_string()));
+                .returning(move || Ok(format!("/etc/systemd/system/{name}")));
             Ok(Box::new(u))
         });
-
-        mock_fs.add_file(
-            "/lib/systemd/system/test.service",
-            "[X-Traefik]\nLabel=test",
-        );
-
-        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
-        let units = context.list_units().await.unwrap();
-        let units = units.read().await;
-        assert!(units.contains_key("test.service"));
     }
 
     #[tokio::test]
-    async fn test_get_traefik_yaml_config_from_configuration_files() {
-        let mut mock_unit = MockSystemdUnit::new();
-        mock_unit.expect_drop_in_paths().returning(|| {
-            Ok(vec![
-                "/etc/systemd/system/test.service.d/traefik.conf".to_string(),
-            ])
-        });
-        mock_unit
-            .expect_fragment_path()
-            .returning(|| Ok("/lib/systemd/system/test.service".to_string()));
-
+    async fn test_rescan_units() {
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(|_, _| {
+                Ok(vec![
+                    listed_unit("added.service"),
+                    listed_unit("changed.service"),
+                    listed_unit("unchanged.service"),
+                    listed_unit("lost-section.service"),
+                    listed_unit("plain.service"),
+                ])
+            });
+        expect_no_unit_files(&mut mock_manager);
+        expect_units_with_fragments(&mut mock_manager);
         let mock_fs = Arc::new(MockFileSystem::new());
         mock_fs.add_file(
-            "/etc/systemd/system/test.service.d/traefik.conf",
-            "[X-Traefik]\nLabel=label1",
+            "/etc/systemd/system/added.service",
+            "[X-Traefik]\nLabel=a=1",
         );
         mock_fs.add_file(
-            "/lib/systemd/system/test.service",
-            "[X-Traefik]\nLabel=label2",
+            "/etc/systemd/system/changed.service",
+            "[X-Traefik]\nLabel=b=2",
         );
-
-        let context = DBusContext::new_test_context(Arc::new(MockSystemdManager::new()), mock_fs);
-
-        let unit_data = UnitData {
-            proxy: Box::new(mock_unit),
-            name: "test.service".to_string(),
+        mock_fs.add_file(
+            "/etc/systemd/system/unchanged.service",
+            "[X-Traefik]\nLabel=c=1",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/lost-section.service",
+            "[Service]\nType=simple",
+        );
+        mock_fs.add_file(
+            "/etc/systemd/system/plain.service",
+            "[Service]\nType=simple",
+        );
+        let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
+        let with_labels = |name: &str, labels: &[&str]| UnitData {
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            presets: vec![],
+            ..unit_data(name)
         };
-
-        let config = context
-            .get_traefik_yaml_config_from_configuration_files(&unit_data)
-            .await
-            .unwrap();
-        assert_eq!(config, vec!["label1".to_string(), "label2".to_string()]);
+        let units_lock = Arc::new(RwLock::new(HashMap::from([
+            (
+                "changed.service".to_string(),
+                with_labels("changed.service", &["b=1"]),
+            ),
+            (
+                "unchanged.service".to_string(),
+                with_labels("unchanged.service", &["c=1"]),
+            ),
+            (
+                "lost-section.service".to_string(),
+                with_labels("lost-section.service", &["d=1"]),
+            ),
+        ])));
+
+        let mut events = context.rescan_units(&units_lock, false).await.unwrap();
+
+        events.sort_by_key(|e| format!("{e:?}"));
+        assert_eq!(
+            events,
+            vec![
+                UnitEvent::Changed {
+                    unit: "added.service".to_string()
+                },
+                UnitEvent::Changed {
+                    unit: "changed.service".to_string()
+                },
+                UnitEvent::Removed {
+                    unit: "lost-section.service".to_string()
+                },
+            ]
+        );
+        let units = units_lock.read().await;
+        let mut names = units.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["added.service", "changed.service", "unchanged.service"]
+        );
+        assert_eq!(units["changed.service"].labels, vec!["b=2"]);
+        drop(units);
+
+        let mut events = context.rescan_units(&units_lock, true).await.unwrap();
+        events.sort_by_key(|e| format!("{e:?}"));
+        assert_eq!(
+            events,
+            ["added.service", "changed.service", "unchanged.service"]
+                .into_iter()
+                .map(|unit| UnitEvent::Changed {
+                    unit: unit.to_string()
+                })
+                .collect::<Vec<_>>()
+        );
     }
 
     #[tokio::test]
-    async fn test_watch_units() {
+    async fn test_watch_units_rescans_after_daemon_reload() {
         let mut mock_manager = MockSystemdManager::new();
-
-        let args = NewUnitArgs {
-            id: "new.service".to_string(),
-            unit: "/obj/path".to_string(),
-        };
-        mock_manager.expect_receive_unit_new().return_once(move || {
-            Ok(Box::pin(futures::stream::iter(vec![Ok(args)]))
-                as Pin<Box<dyn Stream<Item = Result<NewUnitArgs>> + Send>>)
-        });
-
-        mock_manager.expect_get_unit().returning(|_| {
-            let mut u = MockSystemdUnit::new();
-            u.expect_drop_in_paths().returning(|| Ok(vec![]));
-            u.expect_fragment_path()
-                .returning(|| Ok("/lib/systemd/system/new.service".to_string()));
-            Ok(Box::new(u))
+        mock_manager
+            .expect_receive_unit_new()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        mock_manager
+            .expect_receive_unit_removed()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        mock_manager.expect_receive_reloading().return_once(|| {
+            Ok(Box::pin(
+                futures::stream::iter(vec![Ok(true), Ok(false)]).chain(futures::stream::pending()),
+            ))
         });
-
+        mock_manager
+            .expect_receive_unit_files_changed()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        mock_manager
+            .expect_list_units_by_patterns()
+            .times(1)
+            .returning(|_, _| Ok(vec![listed_unit("app.service")]));
+        expect_no_unit_files(&mut mock_manager);
+        expect_units_with_fragments(&mut mock_manager);
         let mock_fs = Arc::new(MockFileSystem::new());
-        mock_fs.add_file("/lib/systemd/system/new.service", "[X-Traefik]\nLabel=new");
-
+        mock_fs.add_file("/etc/systemd/system/app.service", "[X-Traefik]\nLabel=a=1");
         let context = DBusContext::new_test_context(Arc::new(mock_manager), mock_fs);
         let units_lock = Arc::new(RwLock::new(HashMap::new()));
 
-        let (handles, mut rx_new_unit) = context.watch_units(units_lock.clone()).await;
-
-        let event =
-            tokio::time::timeout(tokio::time::Duration::from_millis(500), rx_new_unit.recv())
-                .await
-                .expect("Timeout waiting for new unit event")
-                .expect("Channel closed before receiving event");
-        assert_eq!(event.unit, "new.service");
+        let (handles, mut rx_unit_event) = context.watch_units(units_lock.clone()).await.unwrap();
 
-        let units = units_lock.read().await;
-        assert!(units.contains_key("new.service"));
+        let event = tokio::time::timeout(
+            tokio::time::Duration::from_millis(500),
+            rx_unit_event.recv(),
+        )
+        .await
+        .expect("Timeout waiting for rescanned unit event")
+        .expect("Channel closed before receiving event");
+        assert_eq!(
+            event,
+            UnitEvent::Changed {
+                unit: "app.service".to_string()
+            }
+        );
+        assert!(units_lock.read().await.contains_key("app.service"));
 
         for h in handles {
             h.abort();
         }
     }
 
+    /// A unit whose only file is its fragment at `/etc/systemd/system/<name>`, in `system.slice`.
+    pub fn unit_data_with_fragment(name: &str) -> UnitData {
+        let fragment = format!("/etc/systemd/system/{name}");
+        let mut u = MockSystemdUnit::new();
+        u.expect_drop_in_paths().returning(|| Ok(vec![]));
+        u.expect_fragment_path()
+            .returning(move || Ok(fragment.clone()));
+        u.expect_slice()
+            .returning(|| Ok("system.slice".to_string()));
+        UnitData {
+            proxy: Box::new(u),
+            ..unit_data(name)
+        }
+    }
+
+    /// A unit read from its file in /etc/systemd/system that runs with `environment`.
+    pub fn unit_data_with_environment(name: &str, environment: &[&str]) -> UnitData {
+        let fragment = format!("/etc/systemd/system/{name}");
+        let environment = environment
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>();
+        let mut u = MockSystemdUnit::new();
+        u.expect_drop_in_paths().returning(|| Ok(vec![]));
+        u.expect_fragment_path()
+            .returning(move || Ok(fragment.clone()));
+        u.expect_environment()
+            .returning(move || Ok(environment.clone()));
+        u.expect_environment_files().returning(|| Ok(vec![]));
+        UnitData {
+            proxy: Box::new(u),
+            ..unit_data(name)
+        }
+    }
+
+    fn unit_data(name: &str) -> UnitData {
+        UnitData {
+            proxy: Box::new(MockSystemdUnit::new()),
+            name: name.to_string(),
+            labels: vec![],
+            presets: vec![],
+            active_state: "inactive".to_string(),
+        }
+    }
+
+    fn properties_changed_stream(changes: Vec<(&'static str, &'static str)>) -> StateChanges {
+        Box::pin(futures::stream::iter(changes.into_iter().map(
+            |(path, active_state)| {
+                Ok(UnitPropertiesChanged {
+                    path: path.to_string(),
+                    active_state: active_state.to_string(),
+                })
+            },
+        )))
+    }
+
     #[tokio::test]
     async fn test_get_messages() {
         let (tx_job, mut rx_job) = tokio::sync::mpsc::channel(10);
-        let (tx_new_unit, rx_new_unit) = tokio::sync::mpsc::channel(10);
+        let (tx_unit_event, rx_unit_event) = tokio::sync::mpsc::channel(10);
 
         let mut mock_manager = MockSystemdManager::new();
-        mock_manager
-            .expect_load_unit()
-            .returning(|_| Ok("/obj/path/new".to_string()));
+        expect_no_manager_restart(&mut mock_manager);
+        let changes = properties_changed_stream(vec![
+            ("/org/freedesktop/systemd1/unit/other_2eservice", "active"),
+            ("/org/freedesktop/systemd1/unit/new_2eservice", "active"),
+            ("/org/freedesktop/systemd1/unit/new_2eservice", "inactive"),
+        ]);
 
-        mock_manager.expect_get_unit().returning(|_| {
-            let mut u = MockSystemdUnit::new();
-            u.expect_receive_active_state_changed().return_once(|| {
-                Ok(
-                    Box::pin(futures::stream::iter(vec![Ok("active".to_string())]))
-                        as Pin<Box<dyn Stream<Item = Result<String>> + Send>>,
-                )
-            });
-            Ok(Box::new(u))
+        let context =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
+        let units_lock = Arc::new(RwLock::new(HashMap::from([(
+            "new.service".to_string(),
+            unit_data("new.service"),
+        )])));
+
+        let (_tx_shutdown, rx_shutdown) = tokio::sync::watch::channel(false);
+        let context_clone = context.clone();
+        let handle = tokio::spawn(async move {
+            context_clone
+                .get_messages(tx_job, units_lock, changes, rx_unit_event, rx_shutdown)
+                .await
         });
 
+        let job = tokio::time::timeout(tokio::time::Duration::from_millis(500), rx_job.recv())
+            .await
+            .expect("Timeout waiting for job event")
+            .expect("Channel closed before receiving job");
+        assert_eq!(job.unit_name, "new.service");
+        assert!(job.started);
+        let job = tokio::time::timeout(tokio::time::Duration::from_millis(500), rx_job.recv())
+            .await
+            .expect("Timeout waiting for job event")
+            .expect("Channel closed before receiving job");
+        assert_eq!(job.unit_name, "new.service");
+        assert!(!job.started);
+
+        let session_end = handle.await.unwrap().unwrap();
+        assert_eq!(session_end, SessionEnd::Disconnected); // the changes stream ended
+        assert!(rx_job.recv().await.is_none());
+        drop(tx_unit_event);
+    }
+
+    #[tokio::test]
+    async fn test_get_messages_picks_up_units_watched_later() {
+        let (tx_job, mut rx_job) = tokio::sync::mpsc::channel(10);
+        let (tx_unit_event, rx_unit_event) = tokio::sync::mpsc::channel(10);
+        let (tx_changes, rx_changes) =
+            futures::channel::mpsc::unbounded::<Result<UnitPropertiesChanged>>();
+
+        let mut mock_manager = MockSystemdManager::new();
+        expect_no_manager_restart(&mut mock_manager);
+
         let context =
             DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
         let units_lock = Arc::new(RwLock::new(HashMap::new()));
 
-        tx_new_unit
-            .send(NewUnit {
-                unit: "new.service".to_string(),
-            })
-            .await
-            .unwrap();
-
+        let (_tx_shutdown, rx_shutdown) = tokio::sync::watch::channel(false);
         let context_clone = context.clone();
+        let units_lock_clone = units_lock.clone();
         let handle = tokio::spawn(async move {
             context_clone
-                .get_messages(tx_job, units_lock, rx_new_unit)
+                .get_messages(
+                    tx_job,
+                    units_lock_clone,
+                    Box::pin(rx_changes),
+                    rx_unit_event,
+                    rx_shutdown,
+                )
                 .await
         });
 
+        units_lock
+            .write()
+            .await
+            .insert("new.service".to_string(), unit_data("new.service"));
+        tx_unit_event
+            .send(UnitEvent::New {
+                unit: "new.service".to_string(),
+            })
+            .await
+            .unwrap();
+        tx_changes
+            .unbounded_send(Ok(UnitPropertiesChanged {
+                path: "/org/freedesktop/systemd1/unit/new_2eservice".to_string(),
+                active_state: "active".to_string(),
+            }))
+            .unwrap();
+
         let job = tokio::time::timeout(tokio::time::Duration::from_millis(500), rx_job.recv())
             .await
             .expect("Timeout waiting for job event")
             .expect("Channel closed before receiving job");
         assert_eq!(job.unit_name, "new.service");
         assert!(job.started);
+        assert_eq!(
+            units_lock.read().await["new.service"].active_state,
+            "active"
+        );
 
-        drop(tx_new_unit); // Now we can drop it to close rx_new_unit in get_messages
+        drop(tx_unit_event); // Now we can drop it to close rx_unit_event in get_messages
         handle.await.unwrap().unwrap();
     }
 
+    #[tokio::test]
+    async fn test_get_messages_ends_session_on_shutdown_or_manager_restart() {
+        for restart in [false, true] {
+            let (tx_job, _rx_job) = tokio::sync::mpsc::channel(10);
+            let (_tx_unit_event, rx_unit_event) = tokio::sync::mpsc::channel(10);
+            let (tx_shutdown, rx_shutdown) = tokio::sync::watch::channel(false);
+            let mut mock_manager = MockSystemdManager::new();
+            mock_manager
+                .expect_receive_manager_restarted()
+                .return_once(move || {
+                    if restart {
+                        Ok(Box::pin(futures::stream::once(async {})))
+                    } else {
+                        Ok(Box::pin(futures::stream::pending()))
+                    }
+                });
+            let context = DBusContext::new_test_context(
+                Arc::new(mock_manager),
+                Arc::new(MockFileSystem::new()),
+            );
+            if !restart {
+                tx_shutdown.send(true).unwrap();
+            }
+
+            let session_end = tokio::time::timeout(
+                tokio::time::Duration::from_millis(500),
+                context.get_messages(
+                    tx_job,
+                    Arc::new(RwLock::new(HashMap::new())),
+                    Box::pin(futures::stream::pending()),
+                    rx_unit_event,
+                    rx_shutdown,
+                ),
+            )
+            .await
+            .expect("Timeout waiting for the session to end")
+            .unwrap();
+            let expected = if restart {
+                SessionEnd::Disconnected
+            } else {
+                SessionEnd::Shutdown
+            };
+            assert_eq!(session_end, expected);
+        }
+    }
+
+    #[test]
+    fn test_uid_from_user_manager_unit() {
+        assert_eq!(uid_from_user_manager_unit("user@1000.service"), Some(1000));
+        assert_eq!(uid_from_user_manager_unit("user@.service"), None);
+        assert_eq!(uid_from_user_manager_unit("user@alice.service"), None);
+        assert_eq!(
+            uid_from_user_manager_unit("user-runtime-dir@1000.service"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_user_managers() {
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_subscribe()
+            .times(1)
+            .returning(|| Ok(()));
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(|_, _| {
+                let mut stopped = listed_unit("user@1001.service");
+                stopped.3 = "inactive".to_string();
+                Ok(vec![
+                    listed_unit("user@1000.service"),
+                    stopped,
+                    listed_unit("app.service"),
+                ])
+            });
+        mock_manager
+            .expect_receive_unit_properties_changed()
+            .return_once(|| {
+                Ok(Box::pin(futures::stream::iter(vec![
+                    Ok(unit_properties_changed("user@1001.service", "activating")),
+                    Ok(unit_properties_changed("user@1001.service", "active")),
+                    Ok(unit_properties_changed("app.service", "inactive")),
+                    Ok(unit_properties_changed("user@1000.service", "deactivating")),
+                ])))
+            });
+        let context =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
+
+        assert_eq!(context.running_user_managers().await.unwrap(), vec![1000]);
+        let changes = context
+            .receive_user_manager_changes()
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(changes, vec![(1001, true), (1000, false)]);
+    }
+
+    #[test]
+    fn test_unit_name_from_object_path() {
+        assert_eq!(
+            unit_name_from_object_path("/org/freedesktop/systemd1/unit/sleep_2eservice"),
+            Some("sleep.service".to_string())
+        );
+        assert_eq!(
+            unit_name_from_object_path(
+                "/org/freedesktop/systemd1/unit/app_40blue_2dgreen_2eservice"
+            ),
+            Some("app@blue-green.service".to_string())
+        );
+        assert_eq!(
+            unit_name_from_object_path("/org/freedesktop/systemd1/unit/_31foo_5fbar_2eservice"),
+            Some("1foo_bar.service".to_string())
+        );
+        assert_eq!(
+            unit_name_from_object_path("/org/freedesktop/systemd1/job/42"),
+            None
+        );
+        assert_eq!(
+            unit_name_from_object_path("/org/freedesktop/systemd1/unit/bad_2"),
+            None
+        );
+    }
+
     fn setup(
         files_contents: impl IntoIterator<Item = impl Into<String>>,
     ) -> (Vec<String>, DBusContext<'static>) {
@@ -755,9 +2724,10 @@ Label=test.service.label2
 "#]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 2);
         assert_eq!(result[0], "test.service.label1");
@@ -775,9 +2745,10 @@ ExecStart=/usr/bin/test
 "#]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 0);
     }
@@ -795,9 +2766,10 @@ Label=file2.label1
         ]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], "file1.label1");
@@ -811,9 +2783,10 @@ Label=file2.label1
 "#]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 0);
     }
@@ -829,9 +2802,10 @@ Label=traefik.label3
 "#]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 3);
         assert_eq!(result[0], "traefik.label1");
@@ -858,9 +2832,10 @@ WantedBy=multi-user.target
 "#]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 3);
         assert!(result[0].contains("routers.app.rule"));
@@ -875,15 +2850,125 @@ Label=traefik.http.routers.app.rule=Host(`app.example.com`) && PathPrefix(`/api`
 Label=traefik.http.middlewares.app-headers.headers.customrequestheaders.X-Custom-Header=value-with-dash
 "#]);
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 2);
         assert!(result[0].contains("&&"));
         assert!(result[1].contains("X-Custom-Header"));
     }
 
+    #[tokio::test]
+    async fn test_label_values_are_unescaped_like_systemd() {
+        let (files, context) = setup([r#"[X-Traefik]
+Label="traefik.http.routers.app.rule=Host(`a`) && Path(`/b`)"
+Label=traefik.http.routers.app.rule=Host(`a`) \
+  # comments between continuation lines are skipped
+  || Host(`b`)
+Label=traefik.http.middlewares.m.headers.customresponseheaders.X-Percent=100%%\x21
+Label=traefik.http.routers.app.priority=\q
+"#]);
+
+        let result = context
+            .get_traefik_config_from_configuration_files("test.service", files)
+            .await
+            .unwrap()
+            .labels;
+
+        assert_eq!(
+            result,
+            vec![
+                "traefik.http.routers.app.rule=Host(`a`) && Path(`/b`)",
+                "traefik.http.routers.app.rule=Host(`a`) || Host(`b`)",
+                "traefik.http.middlewares.m.headers.customresponseheaders.X-Percent=100%!",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_directives_expand_to_labels_before_raw_labels() {
+        let (files, context) = setup([
+            r#"[X-Traefik]
+Host=%i.example.com
+Port=8080
+EntryPoints=web
+Label=traefik.http.routers.app-blue.priority=10
+Colour=blue
+Port=eighty
+"#,
+            r#"[X-Traefik]
+EntryPoints=
+EntryPoints=websecure
+CertResolver=le
+Label=traefik.http.routers.app-blue.entrypoints=web,websecure
+"#,
+        ]);
+
+        let result = context
+            .get_traefik_config_from_configuration_files("app@blue.service", files)
+            .await
+            .unwrap()
+            .labels;
+
+        assert_eq!(
+            result,
+            vec![
+                "traefik.http.routers.app-blue.rule=Host(`blue.example.com`)",
+                "traefik.http.routers.app-blue.entrypoints=websecure",
+                "traefik.http.routers.app-blue.tls.certresolver=le",
+                "traefik.http.routers.app-blue.service=app-blue",
+                "traefik.http.services.app-blue.loadbalancer.server.port=8080",
+                "traefik.http.routers.app-blue.priority=10",
+                "traefik.http.routers.app-blue.entrypoints=web,websecure",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_empty_label_resets_directives_and_presets() {
+        let (files, context) = setup([
+            "[X-Traefik]\nHost=vendor.example.com\nPort=80\nPreset=vendor\nLabel=a=1\n",
+            "[X-Traefik]\nLabel=\nPort=8080\n",
+        ]);
+
+        let config = context
+            .get_traefik_config_from_configuration_files("app.service", files)
+            .await
+            .unwrap();
+
+        assert_eq!(config.presets, Vec::<String>::new());
+        assert_eq!(
+            config.labels,
+            vec![
+                "traefik.http.routers.app.service=app",
+                "traefik.http.services.app.loadbalancer.server.port=8080",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scheme_without_port_is_ignored() {
+        let (files, context) = setup([
+            "[X-Traefik]\nHost=app.example.com\nScheme=https\n",
+            "[X-Traefik]\nLabel=traefik.http.routers.app.service=api@file\n",
+        ]);
+
+        let config = context
+            .get_traefik_config_from_configuration_files("app.service", files)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            config.labels,
+            vec![
+                "traefik.http.routers.app.rule=Host(`app.example.com`)",
+                "traefik.http.routers.app.service=api@file",
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn test_multiple_files_with_and_without_traefik() {
         let (files, context) = setup([
@@ -899,9 +2984,10 @@ ExecStart=/bin/true
         ]);
 
         let result = context
-            .get_traefik_config_from_configuration_files(files)
+            .get_traefik_config_from_configuration_files("test.service", files)
             .await
-            .unwrap();
+            .unwrap()
+            .labels;
 
         assert_eq!(result.len(), 1);
         assert_eq!(result[0], "app.traefik");
@@ -0,0 +1,262 @@
+use anyhow::{Result, anyhow, bail};
+
+/// Settings of the `[X-Traefik]` section besides `Label=`, which stand for the labels of the
+/// unit's router and service in the most common setups.
+#[derive(Debug, Default, PartialEq)]
+pub struct Directives {
+    hosts: Vec<String>,
+    path_prefix: Option<String>,
+    port: Option<u16>,
+    scheme: Option<String>,
+    entry_points: Vec<String>,
+    cert_resolver: Option<String>,
+    middlewares: Vec<String>,
+    presets: Vec<String>,
+}
+
+impl Directives {
+    /// Names of the directives, as written in unit files.
+    pub const KEYS: [&str; 8] = [
+        "Host",
+        "PathPrefix",
+        "Port",
+        "Scheme",
+        "EntryPoints",
+        "CertResolver",
+        "Middlewares",
+        "Preset",
+    ];
+
+    /// Applies an assignment as systemd does: list directives (`Host=`, `EntryPoints=`,
+    /// `Middlewares=`, `Preset=`) add to the list, the others replace the value, and an empty value
+    /// resets either. Invalid values are an error.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        match key {
+            "Host" => extend(&mut self.hosts, value, is_host)?,
+            "PathPrefix" => {
+                self.path_prefix = optional(value, |prefix| {
+                    prefix.starts_with('/') && !prefix.contains(['`', ' '])
+                })?
+            }
+            "Port" => {
+                self.port = match value {
+                    "" => None,
+                    _ => Some(
+                        value
+                            .parse::<u16>()
+                            .ok()
+                            .filter(|&port| port != 0)
+                            .ok_or_else(|| anyhow!("expected a port number, got '{value}'"))?,
+                    ),
+                }
+            }
+            "Scheme" => {
+                self.scheme = optional(value, |scheme| ["http", "https", "h2c"].contains(&scheme))?
+            }
+            "EntryPoints" => extend(&mut self.entry_points, value, is_name)?,
+            "CertResolver" => self.cert_resolver = optional(value, is_name)?,
+            "Middlewares" => extend(&mut self.middlewares, value, is_name)?,
+            "Preset" => extend(&mut self.presets, value, is_name)?,
+            _ => bail!("unknown directive {key}="),
+        }
+        Ok(())
+    }
+
+    /// Whether `Scheme=` is set without the `Port=` of the server it belongs to, so it is not used.
+    pub fn scheme_without_port(&self) -> bool {
+        self.scheme.is_some() && self.port.is_none()
+    }
+
+    /// Names of the presets whose labels come before the unit's own.
+    pub fn presets(&self) -> &[String] {
+        &self.presets
+    }
+
+    /// The labels the directives stand for, on a router and a service named `name`.
+    pub fn labels(&self, name: &str) -> Vec<String> {
+        let router = format!("traefik.http.routers.{name}");
+        let service = format!("traefik.http.services.{name}");
+        let mut labels = vec![];
+        let mut rule = self
+            .hosts
+            .iter()
+            .map(|host| format!("Host(`{host}`)"))
+            .collect::<Vec<_>>()
+            .join(" || ");
+        if let Some(prefix) = &self.path_prefix {
+            rule = match self.hosts.len() {
+                0 => format!("PathPrefix(`{prefix}`)"),
+                1 => format!("{rule} && PathPrefix(`{prefix}`)"),
+                _ => format!("({rule}) && PathPrefix(`{prefix}`)"),
+            };
+        }
+        if !rule.is_empty() {
+            labels.push(format!("{router}.rule={rule}"));
+        }
+        if !self.entry_points.is_empty() {
+            labels.push(format!(
+                "{router}.entrypoints={}",
+                self.entry_points.join(",")
+            ));
+        }
+        if !self.middlewares.is_empty() {
+            labels.push(format!(
+                "{router}.middlewares={}",
+                self.middlewares.join(",")
+            ));
+        }
+        if let Some(resolver) = &self.cert_resolver {
+            labels.push(format!("{router}.tls.certresolver={resolver}"));
+        }
+        if let Some(port) = self.port {
+            labels.push(format!("{router}.service={name}"));
+            labels.push(format!("{service}.loadbalancer.server.port={port}"));
+            if let Some(scheme) = &self.scheme {
+                labels.push(format!("{service}.loadbalancer.server.scheme={scheme}"));
+            }
+        }
+        labels
+    }
+}
+
+fn is_host(host: &str) -> bool {
+    host.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// Names of entry points, middlewares and resolvers, e.g. `auth@file`.
+fn is_name(name: &str) -> bool {
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.@".contains(c))
+}
+
+fn optional(value: &str, valid: impl Fn(&str) -> bool) -> Result<Option<String>> {
+    match value {
+        "" => Ok(None),
+        _ if valid(value) => Ok(Some(value.to_string())),
+        _ => bail!("invalid value '{value}'"),
+    }
+}
+
+/// Adds the items of a list separated by spaces or commas, or clears it on an empty value.
+fn extend(list: &mut Vec<String>, value: &str, valid: impl Fn(&str) -> bool) -> Result<()> {
+    if value.is_empty() {
+        list.clear();
+        return Ok(());
+    }
+    let items = value
+        .split([' ', ','])
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>();
+    if let Some(invalid) = items.iter().find(|item| !valid(item)) {
+        bail!("invalid value '{invalid}'");
+    }
+    list.extend(items.into_iter().map(str::to_string));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn labels(assignments: &[(&str, &str)]) -> Vec<String> {
+        let mut directives = Directives::default();
+        for (key, value) in assignments {
+            directives.set(key, value).unwrap();
+        }
+        directives.labels("app")
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(
+            labels(&[
+                ("Host", "app.example.com"),
+                ("PathPrefix", "/api"),
+                ("Port", "8080"),
+                ("Scheme", "https"),
+                ("EntryPoints", "websecure"),
+                ("CertResolver", "le"),
+                ("Middlewares", "auth@file, compress"),
+                ("Preset", "secure-web"),
+            ]),
+            vec![
+                "traefik.http.routers.app.rule=Host(`app.example.com`) && PathPrefix(`/api`)",
+                "traefik.http.routers.app.entrypoints=websecure",
+                "traefik.http.routers.app.middlewares=auth@file,compress",
+                "traefik.http.routers.app.tls.certresolver=le",
+                "traefik.http.routers.app.service=app",
+                "traefik.http.services.app.loadbalancer.server.port=8080",
+                "traefik.http.services.app.loadbalancer.server.scheme=https",
+            ]
+        );
+        assert_eq!(labels(&[]), Vec::<String>::new());
+        assert_eq!(
+            labels(&[("PathPrefix", "/api")]),
+            vec!["traefik.http.routers.app.rule=PathPrefix(`/api`)"]
+        );
+    }
+
+    #[test]
+    fn test_scheme_needs_a_port() {
+        let mut directives = Directives::default();
+        directives.set("Scheme", "https").unwrap();
+        assert!(directives.scheme_without_port());
+        assert_eq!(directives.labels("app"), Vec::<String>::new());
+        directives.set("Port", "8443").unwrap();
+        assert!(!directives.scheme_without_port());
+        assert_eq!(
+            directives.labels("app"),
+            vec![
+                "traefik.http.routers.app.service=app",
+                "traefik.http.services.app.loadbalancer.server.port=8443",
+                "traefik.http.services.app.loadbalancer.server.scheme=https",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lists_add_and_reset() {
+        assert_eq!(
+            labels(&[
+                ("Host", "a.example.com"),
+                ("Host", "b.example.com c.example.com"),
+                ("PathPrefix", "/x"),
+                ("EntryPoints", "web"),
+                ("EntryPoints", ""),
+                ("EntryPoints", "websecure"),
+                ("Port", "80"),
+                ("Port", "8080"),
+                ("CertResolver", "le"),
+                ("CertResolver", ""),
+            ]),
+            vec![
+                "traefik.http.routers.app.rule=(Host(`a.example.com`) || Host(`b.example.com`) || \
+                 Host(`c.example.com`)) && PathPrefix(`/x`)",
+                "traefik.http.routers.app.entrypoints=websecure",
+                "traefik.http.routers.app.service=app",
+                "traefik.http.services.app.loadbalancer.server.port=8080",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let mut directives = Directives::default();
+        for (key, value) in [
+            ("Port", "http"),
+            ("Port", "0"),
+            ("Port", "65536"),
+            ("Host", "a.example.com`) || Host(`b"),
+            ("PathPrefix", "api"),
+            ("Scheme", "ftp"),
+            ("EntryPoints", "web;secure"),
+            ("Unknown", "x"),
+        ] {
+            assert!(directives.set(key, value).is_err(), "{key}={value}");
+        }
+        assert_eq!(directives, Directives::default());
+    }
+}
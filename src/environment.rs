@@ -0,0 +1,253 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Variables a unit runs with, from `Environment=` and `EnvironmentFile=`.
+pub type Environment = HashMap<String, String>;
+
+/// Characters a backslash escapes inside double quotes of an environment file, as in systemd.
+const DOUBLE_QUOTE_ESCAPES: &str = "\"\\`$";
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(is_name_char)
+}
+
+/// Splits the `KEY=VALUE` entries of systemd's `Environment` property.
+pub fn parse_assignments(assignments: &[String]) -> Vec<(String, String)> {
+    assignments
+        .iter()
+        .filter_map(|assignment| assignment.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Reads an `EnvironmentFile=` the way systemd does: `KEY=VALUE` lines, `#` and `;` comments,
+/// values in single or double quotes, and a backslash at the end of a line to continue it.
+/// Whitespace around keys and unquoted values is removed and invalid lines are skipped.
+pub fn parse_environment_file(text: &str) -> Vec<(String, String)> {
+    let mut assignments = vec![];
+    let mut chars = text.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(&first) = chars.peek() else {
+            break;
+        };
+        if first == '#' || first == ';' {
+            chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            continue;
+        }
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != '\n') {
+            key.push(c);
+        }
+        let key = key.trim_end();
+        if chars.next_if_eq(&'=').is_none() || !is_name(key) {
+            chars.by_ref().take_while(|&c| c != '\n').for_each(drop);
+            continue;
+        }
+        assignments.push((key.to_string(), read_value(&mut chars)));
+    }
+    assignments
+}
+
+/// Reads a value of an environment file up to the end of its line.
+fn read_value(chars: &mut Peekable<Chars>) -> String {
+    while chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+    let mut value = String::new();
+    match chars.next_if(|&c| c == '"' || c == '\'') {
+        Some('\'') => value.extend(chars.by_ref().take_while(|&c| c != '\'')),
+        Some(_) => {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('\n') | None => {}
+                        Some(c) if DOUBLE_QUOTE_ESCAPES.contains(c) => value.push(c),
+                        Some(c) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                    },
+                    c => value.push(c),
+                }
+            }
+        }
+        None => {}
+    }
+    // unquoted whitespace at the end of the value is not part of it
+    let mut kept = value.len();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => break,
+            '\\' => match chars.next() {
+                Some('\n') | None => {}
+                Some(c) => {
+                    value.push(c);
+                    kept = value.len();
+                }
+            },
+            c => {
+                value.push(c);
+                if !c.is_whitespace() {
+                    kept = value.len();
+                }
+            }
+        }
+    }
+    value.truncate(kept);
+    value
+}
+
+/// Expands `$VAR`, `${VAR}`, `${VAR:-default}` and `${VAR:+alternative}` in a label value as
+/// systemd does in command lines, with `$$` for a literal `$`. Unlike systemd, a variable that is
+/// not defined is an error instead of an empty value.
+pub fn expand(value: &str, env: &Environment) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow!("missing '}}' after '${{{after}'"))?;
+            let expression = &after[..end];
+            rest = &after[end + 1..];
+            let (name, operator, word) = match expression.split_once(':') {
+                Some((name, operation)) => match operation.split_at_checked(1) {
+                    Some((operator @ ("-" | "+"), word)) => (name, Some(operator), word),
+                    _ => bail!("invalid expansion '${{{expression}}}', expected :- or :+"),
+                },
+                None => (expression, None, ""),
+            };
+            if !is_name(name) {
+                bail!("invalid variable name '{name}' in '${{{expression}}}'");
+            }
+            // as in systemd, `:-` and `:+` treat an empty variable like an undefined one
+            let defined = env.get(name).filter(|value| !value.is_empty());
+            match operator {
+                Some("-") => expanded.push_str(defined.map_or(word, String::as_str)),
+                Some(_) if defined.is_some() => expanded.push_str(word),
+                Some(_) => {}
+                None => expanded.push_str(lookup(name, env)?),
+            }
+        } else if rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            expanded.push_str(lookup(&rest[..end], env)?);
+            rest = &rest[end..];
+        } else {
+            // like systemd, a `$` that does not start a variable is kept
+            expanded.push('$');
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+fn lookup<'a>(name: &str, env: &'a Environment) -> Result<&'a str> {
+    env.get(name).map(String::as_str).ok_or_else(|| {
+        anyhow!(
+            "variable {name} is not defined by Environment= or EnvironmentFile= of the unit, \
+             write a literal $ as $$"
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn env(vars: &[(&str, &str)]) -> Environment {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn pairs(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        assert_eq!(
+            parse_assignments(&["PORT=8081".to_string(), "OPTS=a=b".to_string()]),
+            pairs(&[("PORT", "8081"), ("OPTS", "a=b")])
+        );
+    }
+
+    #[test]
+    fn test_parse_environment_file() {
+        let text = r#"
+# a comment
+; another comment
+PORT=8081
+  HOST = example.com
+EMPTY=
+SINGLE='it is $HOME'
+DOUBLE="a \"quoted\" \$value\n"
+LONG=first \
+second
+invalid line
+1INVALID=x
+URL=http://host/#anchor
+"#;
+        assert_eq!(
+            parse_environment_file(text),
+            pairs(&[
+                ("PORT", "8081"),
+                ("HOST", "example.com"),
+                ("EMPTY", ""),
+                ("SINGLE", "it is $HOME"),
+                ("DOUBLE", r#"a "quoted" $value\n"#),
+                ("LONG", "first second"),
+                ("URL", "http://host/#anchor"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_expand() {
+        let env = env(&[("PORT", "8081"), ("HOST", "example.com"), ("EMPTY", "")]);
+        let expand = |value: &str| expand(value, &env).unwrap();
+        assert_eq!(expand("http://127.0.0.1:${PORT}"), "http://127.0.0.1:8081");
+        assert_eq!(expand("Host(`$HOST`)"), "Host(`example.com`)");
+        assert_eq!(expand("$HOST:$PORT/"), "example.com:8081/");
+        assert_eq!(expand("${MISSING:-80}"), "80");
+        assert_eq!(expand("${EMPTY:-80}"), "80");
+        assert_eq!(expand("${PORT:-80}"), "8081");
+        assert_eq!(expand("${PORT:+set}${MISSING:+set}"), "set");
+        assert_eq!(expand("$EMPTY"), "");
+        assert_eq!(expand("costs $$5"), "costs $5");
+        assert_eq!(expand("admin:$$apr1$$H6uskkkW"), "admin:$apr1$H6uskkkW");
+        assert_eq!(expand("PathRegexp(`^/app$`)"), "PathRegexp(`^/app$`)");
+        assert_eq!(expand("no variables"), "no variables");
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let env = env(&[("PORT", "8081")]);
+        let error = |value: &str| format!("{:#}", expand(value, &env).unwrap_err());
+        assert_eq!(
+            error("http://127.0.0.1:${PROT}"),
+            "variable PROT is not defined by Environment= or EnvironmentFile= of the unit, write a \
+             literal $ as $$"
+        );
+        assert!(error("admin:$apr1$H6uskkkW").starts_with("variable apr1 is not defined"));
+        assert!(error("$PORT$H6uskkkW").starts_with("variable H6uskkkW is not defined"));
+        assert!(error("${PORT").contains("missing '}'"));
+        assert!(error("${PORT:=80}").contains("expected :- or :+"));
+        assert!(error("${1PORT}").contains("invalid variable name"));
+    }
+}
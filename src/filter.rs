@@ -0,0 +1,201 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+
+/// Properties of a unit that filters can match, read only when a filter needs them.
+#[derive(Debug, Default, PartialEq)]
+pub struct UnitMetadata {
+    pub slice: String,
+    /// The `User=` of the service, empty when not set.
+    pub user: String,
+    pub unit_file_state: String,
+}
+
+/// Restricts which units are tracked. Empty lists do not restrict anything.
+#[derive(Debug, Default)]
+pub struct UnitFilter {
+    include: Vec<String>,
+    include_regexes: Vec<Regex>,
+    exclude: Vec<Regex>,
+    slices: Vec<Regex>,
+    users: Vec<String>,
+    unit_file_states: Vec<String>,
+}
+
+impl UnitFilter {
+    /// `include`, `exclude` and `slices` are shell-style globs, as in `systemctl list-units PATTERN`.
+    pub fn new(
+        include: Vec<String>,
+        exclude: Vec<String>,
+        slices: Vec<String>,
+        users: Vec<String>,
+        unit_file_states: Vec<String>,
+    ) -> Result<Self> {
+        let globs = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|p| glob_to_regex(p).with_context(|| format!("invalid pattern '{p}'")))
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            include_regexes: globs(&include)?,
+            include,
+            exclude: globs(&exclude)?,
+            slices: globs(&slices)?,
+            users,
+            unit_file_states,
+        })
+    }
+
+    /// Patterns for `ListUnitsByPatterns`/`ListUnitFilesByPatterns`, so systemd already leaves out
+    /// units that are not included.
+    pub fn patterns(&self) -> Vec<String> {
+        if self.include.is_empty() {
+            vec!["*.service".to_string()]
+        } else {
+            self.include.clone()
+        }
+    }
+
+    /// Unit file states for `ListUnitFilesByPatterns`, empty for all of them.
+    pub fn unit_file_states(&self) -> Vec<String> {
+        self.unit_file_states.clone()
+    }
+
+    pub fn matches_name(&self, name: &str) -> bool {
+        (self.include_regexes.is_empty() || self.include_regexes.iter().any(|r| r.is_match(name)))
+            && !self.exclude.iter().any(|r| r.is_match(name))
+    }
+
+    /// Whether `matches_metadata` has anything to check, as reading the metadata costs D-Bus calls.
+    pub fn needs_metadata(&self) -> bool {
+        !self.slices.is_empty() || !self.users.is_empty() || !self.unit_file_states.is_empty()
+    }
+
+    pub fn matches_metadata(&self, metadata: &UnitMetadata) -> bool {
+        (self.slices.is_empty() || self.slices.iter().any(|r| r.is_match(&metadata.slice)))
+            && (self.users.is_empty() || self.users.contains(&metadata.user))
+            && (self.unit_file_states.is_empty()
+                || self.unit_file_states.contains(&metadata.unit_file_state))
+    }
+}
+
+/// Translates a glob with `*`, `?` and `[...]` classes (`[!...]` negates) into an anchored regex.
+pub fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            '[' => {
+                regex.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    regex.push('^');
+                }
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        // characters with a meaning of their own in regex classes
+                        Some(c @ ('\\' | '[' | '&' | '~')) => {
+                            regex.push('\\');
+                            regex.push(c);
+                        }
+                        Some(c) => regex.push(c),
+                        None => anyhow::bail!("unclosed '['"),
+                    }
+                }
+                regex.push(']');
+            }
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(Regex::new(&regex)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |glob: &str, text: &str| glob_to_regex(glob).unwrap().is_match(text);
+        assert!(matches("*.service", "app.service"));
+        assert!(!matches("*.service", "app.socket"));
+        assert!(matches("app-?.service", "app-1.service"));
+        assert!(!matches("app-?.service", "app-12.service"));
+        assert!(matches("app@[ab].service", "app@a.service"));
+        assert!(!matches("app@[!ab].service", "app@a.service"));
+        assert!(matches("app@[!ab].service", "app@c.service"));
+        assert!(matches("a.b", "a.b"));
+        assert!(!matches("a.b", "axb"));
+        assert!(glob_to_regex("app[.service").is_err());
+    }
+
+    #[test]
+    fn test_default_filter_matches_everything() {
+        let filter = UnitFilter::default();
+        assert_eq!(filter.patterns(), vec!["*.service"]);
+        assert!(filter.matches_name("app.service"));
+        assert!(!filter.needs_metadata());
+        assert!(filter.matches_metadata(&UnitMetadata::default()));
+    }
+
+    #[test]
+    fn test_name_filters() {
+        let filter = UnitFilter::new(
+            strings(&["public-*.service", "www.service"]),
+            strings(&["public-admin*"]),
+            vec![],
+            vec![],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(filter.patterns(), vec!["public-*.service", "www.service"]);
+        assert!(filter.matches_name("public-app.service"));
+        assert!(filter.matches_name("www.service"));
+        assert!(!filter.matches_name("internal.service"));
+        assert!(!filter.matches_name("public-admin.service"));
+    }
+
+    #[test]
+    fn test_metadata_filters() {
+        let filter = UnitFilter::new(
+            vec![],
+            vec![],
+            strings(&["public*.slice"]),
+            strings(&["www-data"]),
+            strings(&["enabled", "transient"]),
+        )
+        .unwrap();
+        assert!(filter.needs_metadata());
+        assert_eq!(filter.unit_file_states(), vec!["enabled", "transient"]);
+        let metadata = UnitMetadata {
+            slice: "public-web.slice".to_string(),
+            user: "www-data".to_string(),
+            unit_file_state: "enabled".to_string(),
+        };
+        assert!(filter.matches_metadata(&metadata));
+        assert!(!filter.matches_metadata(&UnitMetadata {
+            slice: "system.slice".to_string(),
+            ..metadata
+        }));
+        let metadata = UnitMetadata {
+            slice: "public-web.slice".to_string(),
+            user: "root".to_string(),
+            unit_file_state: "transient".to_string(),
+        };
+        assert!(!filter.matches_metadata(&metadata));
+        assert!(!filter.matches_metadata(&UnitMetadata {
+            user: "www-data".to_string(),
+            unit_file_state: "static".to_string(),
+            ..metadata
+        }));
+    }
+}
@@ -1,20 +1,197 @@
-use anyhow::Result;
-use std::{path::Path, sync::Arc};
+use anyhow::{Context, Result, bail};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
 
 use crate::{
+    constraints::{Constraint, ConstraintTarget},
     dbus::{DBusContext, JobEvent, UnitData, UnitList},
-    helpers::sanitize_filename,
+    helpers::{fnv1a_64, sanitize_filename},
     infra::FileSystem,
-    yaml::build_traefik_file_yaml,
+    presets::Presets,
+    template::{RuleTemplate, TemplateData},
+    unit_file::unit_name_without_type,
+    yaml::{UnitDefaults, build_traefik_file_yaml, definitions, diff_yaml, is_enabled},
 };
 
+const GENERATOR: &str = env!("CARGO_PKG_NAME");
+
+/// Options that change which units are published and what is generated for them.
+#[derive(Debug, Clone)]
+pub struct GenerationSettings {
+    /// Only units matching these constraints are published.
+    pub constraints: Option<Constraint>,
+    /// Whether units without a `traefik.enable` label are published.
+    pub exposed_by_default: bool,
+    /// Rule of routers without one, rendered for each unit. When empty, units get no router they do
+    /// not define.
+    pub default_rule: RuleTemplate,
+    /// Label bundles units can use with `Preset=`.
+    pub presets: Option<Arc<Presets>>,
+    /// Appended to the names of the routers, services and middlewares of every unit.
+    pub name_suffix: String,
+    /// What the files written so far define, shared by the system and user sessions.
+    pub defined_names: Arc<DefinedNames>,
+}
+
+impl GenerationSettings {
+    /// The settings of the units of a user, whose names get the UID so they do not clash with those
+    /// of other users, e.g. `app-u1000`.
+    pub fn for_user(&self, uid: u32) -> Self {
+        Self {
+            name_suffix: format!("-u{uid}"),
+            ..self.clone()
+        }
+    }
+}
+
+impl Default for GenerationSettings {
+    fn default() -> Self {
+        Self {
+            constraints: None,
+            exposed_by_default: true,
+            default_rule: RuleTemplate::default(),
+            presets: None,
+            name_suffix: String::new(),
+            defined_names: Default::default(),
+        }
+    }
+}
+
+/// Routers, services and other named objects of every generated file, to warn when two files define
+/// the same name differently, e.g. two units with a router called `app`. Traefik's file provider
+/// reads all files as one configuration and keeps only one of them.
+#[derive(Debug, Default)]
+pub struct DefinedNames(Mutex<HashMap<PathBuf, BTreeMap<String, serde_yaml::Value>>>);
+
+impl DefinedNames {
+    /// Records the definitions of a file, warning about those another file defines differently.
+    /// Returns the names of these.
+    fn record(&self, path: &Path, yaml: &str) -> Vec<String> {
+        let definitions = match serde_yaml::from_str(yaml) {
+            Ok(yaml) => definitions(&yaml),
+            Err(e) => {
+                error!("Generated yaml {} is not valid YAML: {e}", path.display());
+                return vec![];
+            }
+        };
+        let mut clashes = vec![];
+        let mut files = self.0.lock().expect("defined names lock poisoned");
+        for (other, other_definitions) in files.iter().filter(|(other, _)| *other != path) {
+            for (name, definition) in &definitions {
+                if other_definitions
+                    .get(name)
+                    .is_some_and(|other_definition| other_definition != definition)
+                {
+                    clashes.push(name.clone());
+                    warn!(
+                        "{name} of {} is defined differently in {}, Traefik will use only one of \
+                         them. Names must be unique across all units and users.",
+                        path.display(),
+                        other.display()
+                    );
+                }
+            }
+        }
+        files.insert(path.to_path_buf(), definitions);
+        clashes
+    }
+
+    fn forget(&self, path: &Path) {
+        self.0
+            .lock()
+            .expect("defined names lock poisoned")
+            .remove(path);
+    }
+
+    /// Forgets the files of a directory, e.g. when those of a user are removed.
+    pub fn forget_dir(&self, dir: &Path) {
+        self.0
+            .lock()
+            .expect("defined names lock poisoned")
+            .retain(|path, _| path.parent() != Some(dir));
+    }
+}
+
+/// Provenance header written at the top of every generated file, used to tell our files apart
+/// from hand-written ones in the same directory.
+#[derive(Debug, PartialEq)]
+struct Provenance {
+    generator: String,
+    source_unit: String,
+    source_files: Vec<String>,
+    hash: String,
+    /// Byte offset of the YAML after the header.
+    body_start: usize,
+}
+
+impl Provenance {
+    /// Source files get a line each, so any path can be read back.
+    fn render(unit: &str, source_files: &[String], yaml: &str) -> String {
+        let source_files = source_files
+            .iter()
+            .map(|file| format!("# source-file: {file}\n"))
+            .collect::<String>();
+        format!(
+            "# generated-by: {GENERATOR}\n# source-unit: {unit}\n{source_files}# hash: {:016x}\n{yaml}",
+            fnv1a_64(yaml.as_bytes())
+        )
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.split_inclusive('\n').peekable();
+        let mut body_start = 0;
+        let mut field = |name: &str| {
+            let value = |line: &str| {
+                line.trim_end_matches(['\n', '\r'])
+                    .strip_prefix("# ")
+                    .and_then(|line| line.strip_prefix(name))
+                    .and_then(|line| line.strip_prefix(": "))
+                    .map(str::to_string)
+            };
+            let line = lines.next_if(|line| value(line).is_some())?;
+            body_start += line.len();
+            value(line)
+        };
+        let generator = field("generated-by")?;
+        let source_unit = field("source-unit")?;
+        let source_files = std::iter::from_fn(|| field("source-file")).collect();
+        let hash = field("hash")?;
+        Some(Self {
+            generator,
+            source_unit,
+            source_files,
+            hash,
+            body_start,
+        })
+    }
+
+    fn is_ours(&self) -> bool {
+        self.generator == GENERATOR
+    }
+
+    /// Whether the YAML of `contents`, the file this was parsed from, changed since it was written.
+    fn is_modified(&self, contents: &str) -> bool {
+        let body = contents.get(self.body_start..).unwrap_or_default();
+        self.hash != format!("{:016x}", fnv1a_64(body.as_bytes()))
+    }
+}
+
+fn unit_yaml_path(unit: &str, traefik_dir: &Path) -> std::path::PathBuf {
+    traefik_dir.join(format!("{}.yml", sanitize_filename(unit)))
+}
+
 pub async fn reconcile(
     dbus: &DBusContext<'_>,
     watched_units: &UnitList,
     fs: &dyn FileSystem,
     traefik_dir: &Path,
+    settings: &GenerationSettings,
 ) -> Result<()> {
     let read = watched_units.read().await;
+    let mut running_units = HashSet::new();
     for (unit_name, unit_data) in read.iter() {
         let started = match dbus.is_unit_running(unit_name.clone()).await {
             Ok(running) => running,
@@ -28,8 +205,11 @@ pub async fn reconcile(
             unit_name,
             if started { "" } else { "not " }
         );
+        if started {
+            running_units.insert(unit_name.clone());
+        }
         if let Err(e) =
-            handle_service_state_changed(dbus, started, unit_data, fs, traefik_dir).await
+            handle_service_state_changed(dbus, started, unit_data, fs, traefik_dir, settings).await
         {
             error!(
                 "Error handling reconciliation of unit {}: {:#}",
@@ -37,6 +217,63 @@ pub async fn reconcile(
             );
         }
     }
+    collect_garbage(&running_units, fs, traefik_dir, &settings.defined_names)?;
+    Ok(())
+}
+
+/// Removes every file we generated in `traefik_dir`, e.g. when its units can no longer be tracked.
+pub fn remove_generated_files(
+    fs: &dyn FileSystem,
+    traefik_dir: &Path,
+    defined_names: &DefinedNames,
+) -> Result<()> {
+    collect_garbage(&HashSet::new(), fs, traefik_dir, defined_names)
+}
+
+/// Removes generated files whose source unit is no longer a tracked running unit.
+/// Files without our provenance header are never touched.
+fn collect_garbage(
+    running_units: &HashSet<String>,
+    fs: &dyn FileSystem,
+    traefik_dir: &Path,
+    defined_names: &DefinedNames,
+) -> Result<()> {
+    let expected_files = running_units
+        .iter()
+        .map(|unit| unit_yaml_path(unit, traefik_dir))
+        .collect::<HashSet<_>>();
+    for path in fs.read_dir(traefik_dir)? {
+        if path.extension().is_none_or(|ext| ext != "yml") || expected_files.contains(&path) {
+            continue;
+        }
+        let contents = match fs.read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!(
+                    "Could not read {} to check ownership: {:#}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        match Provenance::parse(&contents) {
+            Some(provenance) if provenance.is_ours() => {
+                debug!(
+                    "Removing orphaned {} generated from unit {}",
+                    path.display(),
+                    provenance.source_unit
+                );
+                fs.remove_file(&path)?;
+                defined_names.forget(&path);
+                info!("Removed {}", path.display());
+            }
+            _ => trace!(
+                "Not removing {}, it was not generated by us",
+                path.display()
+            ),
+        }
+    }
     Ok(())
 }
 
@@ -45,6 +282,7 @@ pub async fn process_service_change_messages(
     dbus: DBusContext<'static>,
     fs: Arc<dyn FileSystem>,
     traefik_dir: &Path,
+    settings: Arc<GenerationSettings>,
 ) -> Result<(
     tokio::sync::mpsc::Sender<JobEvent>,
     tokio::task::JoinHandle<()>,
@@ -53,10 +291,39 @@ pub async fn process_service_change_messages(
     let dbus = dbus.clone();
     let traefik_dir = traefik_dir.to_owned();
     let handle = tokio::spawn(async move {
-        while let Some(job) = rx.recv().await {
+        let mut presets_reloaded = settings.presets.as_ref().map(|presets| presets.subscribe());
+        loop {
+            // handled here, so files are never written by two tasks at once
+            let job = tokio::select! {
+                job = rx.recv() => match job {
+                    Some(job) => job,
+                    None => break,
+                },
+                () = reloaded(&mut presets_reloaded) => {
+                    info!("Presets reloaded, regenerating the files of all units");
+                    if let Err(e) =
+                        reconcile(&dbus, &watched, fs.as_ref(), &traefik_dir, &settings).await
+                    {
+                        error!("Error reconciling after reloading presets: {:#}", e);
+                    }
+                    continue;
+                }
+            };
             let units = watched.read().await;
             let unit_data = if let Some(unit_data) = units.get(&job.unit_name) {
                 unit_data
+            } else if !job.started {
+                debug!(
+                    "Unit {} is no longer watched, removing its unit yaml",
+                    job.unit_name
+                );
+                if let Err(e) = remove_unit_yaml(&job.unit_name, fs.as_ref(), &traefik_dir) {
+                    error!("Error removing unit yaml for {}: {:#}", job.unit_name, e);
+                }
+                settings
+                    .defined_names
+                    .forget(&unit_yaml_path(&job.unit_name, &traefik_dir));
+                continue;
             } else {
                 error!(
                     "Not handling PropertiesChanged for unit {}, missing unit data.",
@@ -70,6 +337,7 @@ pub async fn process_service_change_messages(
                 unit_data,
                 fs.as_ref(),
                 &traefik_dir,
+                &settings,
             )
             .await
             {
@@ -82,51 +350,189 @@ pub async fn process_service_change_messages(
     Ok((tx, handle))
 }
 
+/// Resolves when the presets are reloaded, never when there are none.
+async fn reloaded(presets_reloaded: &mut Option<tokio::sync::watch::Receiver<()>>) {
+    if let Some(rx) = presets_reloaded
+        && rx.changed().await.is_ok()
+    {
+        return;
+    }
+    std::future::pending().await
+}
+
 pub async fn handle_service_state_changed(
     dbus: &DBusContext<'_>,
     started: bool,
     unit_data: &UnitData,
     fs: &dyn FileSystem,
     traefik_dir: &Path,
+    settings: &GenerationSettings,
 ) -> Result<()> {
     trace!(
         "Handling start/stop for unit {}, started={started}",
         &unit_data.name
     );
-    if started {
-        let lines = dbus
-            .get_traefik_yaml_config_from_configuration_files(unit_data)
-            .await?;
-        let yaml_config = build_traefik_file_yaml(lines)?;
-        write_unit_yaml(&unit_data.name, yaml_config, fs, traefik_dir)?;
-    } else {
-        remove_unit_yaml(&unit_data.name, fs, traefik_dir)?;
+    let dest = unit_yaml_path(&unit_data.name, traefik_dir);
+    settings.defined_names.forget(&dest);
+    if !started {
+        return remove_unit_yaml(&unit_data.name, fs, traefik_dir);
+    }
+    match render_unit_yaml(dbus, unit_data, settings).await {
+        Ok(Some((contents, yaml_config))) => {
+            write_unit_yaml(&unit_data.name, contents, fs, traefik_dir)?;
+            settings.defined_names.record(&dest, &yaml_config);
+        }
+        Ok(None) => remove_unit_yaml(&unit_data.name, fs, traefik_dir)?,
+        // the file written before, e.g. with presets that are gone after a reload, is out of date
+        Err(e) => {
+            remove_unit_yaml(&unit_data.name, fs, traefik_dir)?;
+            return Err(e);
+        }
     }
     Ok(())
 }
 
+/// Renders the file of a started unit, returning its contents and the YAML in it, or `None` when
+/// the unit gets no file.
+async fn render_unit_yaml(
+    dbus: &DBusContext<'_>,
+    unit_data: &UnitData,
+    settings: &GenerationSettings,
+) -> Result<Option<(String, String)>> {
+    let mut config = dbus
+        .get_traefik_yaml_config_from_configuration_files(unit_data)
+        .await?;
+    let name = unit_name_without_type(&unit_data.name);
+    let instance = name.split_once('@').map_or("", |(_, instance)| instance);
+    // presets come first, so the unit's own labels override them
+    let mut labels = match &settings.presets {
+        Some(presets) => presets.expand(
+            &config.presets,
+            &TemplateData {
+                name,
+                instance,
+                labels: &config.labels,
+            },
+        )?,
+        None if config.presets.is_empty() => vec![],
+        None => bail!("Preset= needs a presets file, set with --presets"),
+    };
+    labels.extend(config.labels);
+    // variables are expanded in the labels of presets too, once they are rendered for the unit
+    config.labels = dbus.expand_environment(unit_data, labels).await?;
+    if !is_enabled(&config.labels, settings.exposed_by_default)? {
+        debug!("Unit {} is not enabled for Traefik", unit_data.name);
+        return Ok(None);
+    }
+    if let Some(constraints) = &settings.constraints {
+        let slice = if constraints.uses_slice() {
+            dbus.get_unit_slice(unit_data).await?
+        } else {
+            String::new()
+        };
+        let target = ConstraintTarget {
+            unit: &unit_data.name,
+            slice: &slice,
+            labels: &config.labels,
+        };
+        if !constraints.matches(&target) {
+            debug!("Unit {} does not match the constraints", unit_data.name);
+            return Ok(None);
+        }
+    }
+    let rule = settings
+        .default_rule
+        .render(&TemplateData {
+            name,
+            instance,
+            labels: &config.labels,
+        })
+        .context("rendering the default rule")?;
+    let yaml_config = build_traefik_file_yaml(
+        config.labels,
+        Some(&UnitDefaults::new(&unit_data.name, rule).with_name_suffix(&settings.name_suffix)),
+    )?;
+    let contents = Provenance::render(&unit_data.name, &config.source_files, &yaml_config);
+    Ok(Some((contents, yaml_config)))
+}
+
 fn write_unit_yaml(
     unit: &str,
     yaml: String,
     fs: &dyn FileSystem,
     traefik_dir: &Path,
 ) -> Result<()> {
-    let sanitized_filename = sanitize_filename(unit);
-    let dest = traefik_dir.join(format!("{}.yml", sanitized_filename));
+    let dest = unit_yaml_path(unit, traefik_dir);
 
     if fs.exists(&dest) {
-        return Ok(());
+        match fs.read_to_string(&dest) {
+            Ok(existing) if existing == yaml => {
+                trace!("Unit yaml for {} at {} is up to date", unit, dest.display());
+                return Ok(());
+            }
+            Ok(existing) => log_unit_yaml_changes(unit, &dest, &existing, &yaml),
+            Err(e) => warn!(
+                "Could not read existing unit yaml at {}, rewriting it: {:#}",
+                dest.display(),
+                e
+            ),
+        }
     }
 
     trace!("Unit yaml for {} at {} is {yaml}", unit, dest.display());
-    fs.write(&dest, &yaml)?;
+    fs.write_atomic(&dest, &yaml)?;
     info!("Wrote {}", dest.display());
     Ok(())
 }
 
+fn log_unit_yaml_changes(unit: &str, dest: &Path, existing: &str, yaml: &str) {
+    if let Some(provenance) = Provenance::parse(existing)
+        && provenance.is_modified(existing)
+    {
+        warn!(
+            "Unit yaml at {} was modified outside of {GENERATOR}, overwriting it",
+            dest.display()
+        );
+    }
+    let old = match serde_yaml::from_str::<serde_yaml::Value>(existing) {
+        Ok(old) => old,
+        Err(e) => {
+            warn!(
+                "Existing unit yaml at {} is not valid YAML, replacing it: {}",
+                dest.display(),
+                e
+            );
+            return;
+        }
+    };
+    let new = match serde_yaml::from_str::<serde_yaml::Value>(yaml) {
+        Ok(new) => new,
+        Err(e) => {
+            error!("Generated unit yaml for {unit} is not valid YAML: {e}");
+            return;
+        }
+    };
+    let diff = diff_yaml(&old, &new);
+    if diff.is_empty() {
+        debug!(
+            "Unit yaml for {} at {} only changed formatting",
+            unit,
+            dest.display()
+        );
+    } else {
+        info!(
+            unit = unit,
+            added:? = diff.added,
+            changed:? = diff.changed,
+            removed:? = diff.removed;
+            "Updating {}",
+            dest.display()
+        );
+    }
+}
+
 fn remove_unit_yaml(unit: &str, fs: &dyn FileSystem, traefik_dir: &Path) -> Result<()> {
-    let safe = sanitize_filename(unit);
-    let dest = traefik_dir.join(format!("{}.yml", safe));
+    let dest = unit_yaml_path(unit, traefik_dir);
     if !fs.exists(&dest) {
         return Ok(());
     }
@@ -143,11 +549,41 @@ mod tests {
     use std::path::PathBuf;
 
     use super::*;
-    use crate::infra::tests::MockFileSystem;
+    use crate::dbus::tests::{unit_data_with_environment, unit_data_with_fragment};
+    use crate::infra::tests::{FsOperation, MockFileSystem};
     use pretty_assertions::assert_eq;
     use serial_test::serial;
     use tempfile::TempDir;
 
+    /// Asserts `path` only ever received content by renaming a complete hidden temporary file
+    /// over it, so a reader never observes a partially written file.
+    fn assert_never_written_in_place(fs: &MockFileSystem, path: &str) {
+        let operations = fs.operations();
+        assert!(
+            !operations.contains(&FsOperation::Write(path.to_string())),
+            "{path} was written in place: {operations:?}"
+        );
+        let renames = operations
+            .iter()
+            .filter_map(|op| match op {
+                FsOperation::Rename { from, to } if to == path => Some(from),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert!(!renames.is_empty(), "{path} was never renamed into place");
+        for from in renames {
+            let temp_name = Path::new(from).file_name().unwrap().to_str().unwrap();
+            assert!(
+                temp_name.starts_with('.'),
+                "temp file is not hidden: {from}"
+            );
+            assert!(
+                !fs.file_exists_in_memory(from),
+                "temp file left behind: {from}"
+            );
+        }
+    }
+
     #[test]
     #[serial]
     fn test_write_unit_yaml_creates_file() {
@@ -164,6 +600,7 @@ mod tests {
         );
         let content = fs.get_file_content(yaml_path.to_str().unwrap()).unwrap();
         assert_eq!(content, "foo");
+        assert_never_written_in_place(&fs, yaml_path.to_str().unwrap());
     }
 
     #[test]
@@ -202,6 +639,556 @@ mod tests {
         assert_eq!(content1, content2);
     }
 
+    #[test]
+    #[serial]
+    fn test_write_unit_yaml_rewrites_changed_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let canonical_temp_path = temp_dir.path().canonicalize().unwrap();
+        let fs = MockFileSystem::new();
+        let yaml_path = canonical_temp_path.join("test.service.yml");
+        fs.add_file(
+            yaml_path.to_str().unwrap(),
+            "http:\n  routers:\n    r1:\n      rule: Host(`old`)\n",
+        );
+
+        write_unit_yaml(
+            "test.service",
+            "http:\n  routers:\n    r1:\n      rule: Host(`new`)\n".to_string(),
+            &fs,
+            &canonical_temp_path,
+        )
+        .unwrap();
+
+        let content = fs.get_file_content(yaml_path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            content,
+            "http:\n  routers:\n    r1:\n      rule: Host(`new`)\n"
+        );
+        assert_never_written_in_place(&fs, yaml_path.to_str().unwrap());
+    }
+
+    #[test]
+    #[serial]
+    fn test_write_unit_yaml_skips_unchanged_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let canonical_temp_path = temp_dir.path().canonicalize().unwrap();
+        let fs = MockFileSystem::new();
+        let yaml_path = canonical_temp_path.join("test.service.yml");
+        fs.add_file(yaml_path.to_str().unwrap(), "foo: bar\n");
+
+        write_unit_yaml(
+            "test.service",
+            "foo: bar\n".to_string(),
+            &fs,
+            &canonical_temp_path,
+        )
+        .unwrap();
+
+        assert!(fs.operations().is_empty());
+        let content = fs.get_file_content(yaml_path.to_str().unwrap()).unwrap();
+        assert_eq!(content, "foo: bar\n");
+    }
+
+    #[test]
+    #[serial]
+    fn test_write_unit_yaml_replaces_corrupted_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let canonical_temp_path = temp_dir.path().canonicalize().unwrap();
+        let fs = MockFileSystem::new();
+        let yaml_path = canonical_temp_path.join("test.service.yml");
+        fs.add_file(yaml_path.to_str().unwrap(), "http: [unclosed\n  : {");
+
+        write_unit_yaml(
+            "test.service",
+            "foo: bar\n".to_string(),
+            &fs,
+            &canonical_temp_path,
+        )
+        .unwrap();
+
+        let content = fs.get_file_content(yaml_path.to_str().unwrap()).unwrap();
+        assert_eq!(content, "foo: bar\n");
+    }
+
+    #[test]
+    fn test_provenance_round_trip() {
+        let contents = Provenance::render(
+            "app.service",
+            &[
+                "/etc/systemd/system/app.service".to_string(),
+                "/etc/systemd/system/app.service.d/a, b.conf".to_string(),
+            ],
+            "http: {}\n",
+        );
+
+        let provenance = Provenance::parse(&contents).unwrap();
+
+        assert!(provenance.is_ours());
+        assert_eq!(provenance.source_unit, "app.service");
+        assert_eq!(
+            provenance.source_files,
+            vec![
+                "/etc/systemd/system/app.service",
+                "/etc/systemd/system/app.service.d/a, b.conf"
+            ]
+        );
+        assert_eq!(
+            Provenance::parse(&Provenance::render("app.service", &[], "http: {}\n"))
+                .unwrap()
+                .source_files,
+            Vec::<String>::new()
+        );
+        assert_eq!(provenance.hash, format!("{:016x}", fnv1a_64(b"http: {}\n")));
+        assert!(contents.ends_with("\nhttp: {}\n"));
+        assert_eq!(
+            serde_yaml::from_str::<serde_yaml::Value>(&contents).unwrap(),
+            serde_yaml::from_str::<serde_yaml::Value>("http: {}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_provenance_detects_modified_yaml_with_any_number_of_source_files() {
+        let source_files = [
+            "/etc/systemd/system/app.service".to_string(),
+            "/etc/systemd/system/app.service.d/override.conf".to_string(),
+        ];
+        for source_files in [&source_files[..0], &source_files[..1], &source_files[..]] {
+            let contents = Provenance::render("app.service", source_files, "http: {}\n");
+            let provenance = Provenance::parse(&contents).unwrap();
+            assert!(!provenance.is_modified(&contents), "{contents}");
+
+            let modified = contents.replace("http: {}", "http: {routers: {}}");
+            assert!(provenance.is_modified(&modified), "{modified}");
+        }
+    }
+
+    #[test]
+    fn test_provenance_missing_in_hand_written_file() {
+        assert_eq!(Provenance::parse("http:\n  routers: {}\n"), None);
+        assert_eq!(Provenance::parse("# my routers\nhttp: {}\n"), None);
+    }
+
+    #[test]
+    fn test_collect_garbage_removes_only_orphaned_generated_files() {
+        let fs = MockFileSystem::new();
+        let dir = Path::new("/out");
+        fs.add_file(
+            "/out/running.service.yml",
+            Provenance::render("running.service", &[], "a: 1\n"),
+        );
+        fs.add_file(
+            "/out/gone.service.yml",
+            Provenance::render("gone.service", &[], "a: 1\n"),
+        );
+        fs.add_file("/out/hand-written.yml", "http: {}\n");
+        fs.add_file(
+            "/out/other-generator.yml",
+            "# generated-by: something-else\n# source-unit: x\n# hash: 0\n",
+        );
+        fs.add_file(
+            "/out/gone.service.yml.bak",
+            Provenance::render("gone.service", &[], "a: 1\n"),
+        );
+        fs.add_file(
+            "/elsewhere/gone.service.yml",
+            Provenance::render("gone.service", &[], "a: 1\n"),
+        );
+
+        let names = DefinedNames::default();
+        let app = |host: &str| format!("http:\n  routers:\n    app:\n      rule: Host(`{host}`)\n");
+        names.record(Path::new("/out/gone.service.yml"), &app("a"));
+
+        collect_garbage(
+            &HashSet::from(["running.service".to_string()]),
+            &fs,
+            dir,
+            &names,
+        )
+        .unwrap();
+
+        assert!(fs.file_exists_in_memory("/out/running.service.yml"));
+        assert!(!fs.file_exists_in_memory("/out/gone.service.yml"));
+        assert!(fs.file_exists_in_memory("/out/hand-written.yml"));
+        assert!(fs.file_exists_in_memory("/out/other-generator.yml"));
+        assert!(fs.file_exists_in_memory("/out/gone.service.yml.bak"));
+        assert!(fs.file_exists_in_memory("/elsewhere/gone.service.yml"));
+        assert_eq!(
+            names.record(Path::new("/out/running.service.yml"), &app("b")),
+            Vec::<String>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_service_change_messages_removes_yaml_of_unwatched_unit() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file("/out/old.service.yml", "foo: bar\n");
+        let settings = Arc::new(GenerationSettings::default());
+        let app = |host: &str| format!("http:\n  routers:\n    app:\n      rule: Host(`{host}`)\n");
+        settings
+            .defined_names
+            .record(Path::new("/out/old.service.yml"), &app("a"));
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let watched: UnitList = Arc::new(tokio::sync::RwLock::new(Default::default()));
+
+        let (tx, handle) = process_service_change_messages(
+            watched,
+            dbus,
+            fs.clone(),
+            Path::new("/out"),
+            settings.clone(),
+        )
+        .await
+        .unwrap();
+        tx.send(JobEvent {
+            unit_name: "old.service".to_string(),
+            started: false,
+        })
+        .await
+        .unwrap();
+        drop(tx);
+        handle.await.unwrap();
+
+        assert!(!fs.file_exists_in_memory("/out/old.service.yml"));
+        assert_eq!(
+            settings
+                .defined_names
+                .record(Path::new("/out/new.service.yml"), &app("b")),
+            Vec::<String>::new()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_skips_units_not_matching_constraints() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/public.service",
+            "[X-Traefik]\nLabel=traefik.zone=public\nLabel=traefik.http.routers.a.rule=Host(`a`)",
+        );
+        fs.add_file(
+            "/etc/systemd/system/internal.service",
+            "[X-Traefik]\nLabel=traefik.zone=internal\nLabel=traefik.http.routers.b.rule=Host(`b`)",
+        );
+        fs.add_file("/out/internal.service.yml", "stale: true\n");
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings {
+            constraints: Some(
+                r#"Label("traefik.zone", "public") && Slice("system.slice")"#
+                    .parse()
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+
+        for unit in ["public.service", "internal.service"] {
+            handle_service_state_changed(
+                &dbus,
+                true,
+                &unit_data_with_fragment(unit),
+                fs.as_ref(),
+                Path::new("/out"),
+                &settings,
+            )
+            .await
+            .unwrap();
+        }
+
+        assert!(fs.file_exists_in_memory("/out/public.service.yml"));
+        assert!(!fs.file_exists_in_memory("/out/internal.service.yml"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_honors_traefik_enable() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/enabled.service",
+            "[X-Traefik]\nLabel=traefik.enable=true\nLabel=traefik.http.routers.a.rule=Host(`a`)",
+        );
+        fs.add_file(
+            "/etc/systemd/system/disabled.service",
+            "[X-Traefik]\nLabel=traefik.enable=false\nLabel=traefik.http.routers.b.rule=Host(`b`)",
+        );
+        fs.add_file(
+            "/etc/systemd/system/implicit.service",
+            "[X-Traefik]\nLabel=traefik.http.routers.c.rule=Host(`c`)",
+        );
+        fs.add_file("/out/disabled.service.yml", "stale: true\n");
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings {
+            exposed_by_default: false,
+            ..Default::default()
+        };
+
+        for unit in ["enabled.service", "disabled.service", "implicit.service"] {
+            handle_service_state_changed(
+                &dbus,
+                true,
+                &unit_data_with_fragment(unit),
+                fs.as_ref(),
+                Path::new("/out"),
+                &settings,
+            )
+            .await
+            .unwrap();
+        }
+
+        let enabled = fs.get_file_content("/out/enabled.service.yml").unwrap();
+        assert!(!enabled.contains("enable:"));
+        assert!(!fs.file_exists_in_memory("/out/disabled.service.yml"));
+        assert!(!fs.file_exists_in_memory("/out/implicit.service.yml"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_adds_no_router_without_default_rule() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/app.service",
+            "[X-Traefik]\nLabel=traefik.http.services.app.loadbalancer.server.port=8080",
+        );
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+
+        handle_service_state_changed(
+            &dbus,
+            true,
+            &unit_data_with_fragment("app.service"),
+            fs.as_ref(),
+            Path::new("/out"),
+            &GenerationSettings::default(),
+        )
+        .await
+        .unwrap();
+
+        // units that only define a service stay unrouted, as before default rules existed
+        let yaml = fs.get_file_content("/out/app.service.yml").unwrap();
+        let yaml = serde_yaml::from_str::<serde_yaml::Value>(&yaml).unwrap();
+        assert_eq!(yaml["http"].get("routers"), None);
+        assert!(yaml["http"]["services"].get("app").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_renders_default_rule() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/app@blue.service",
+            "[X-Traefik]\nLabel=app.domain=example.com\n\
+             Label=traefik.http.services.app.loadbalancer.server.port=8080",
+        );
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings {
+            default_rule: "Host(`{{ normalize .Name }}.{{ index .Labels \"app.domain\" }}`)"
+                .parse()
+                .unwrap(),
+            ..Default::default()
+        };
+
+        handle_service_state_changed(
+            &dbus,
+            true,
+            &unit_data_with_fragment("app@blue.service"),
+            fs.as_ref(),
+            Path::new("/out"),
+            &settings,
+        )
+        .await
+        .unwrap();
+
+        let yaml = fs.get_file_content("/out/app_blue.service.yml").unwrap();
+        let yaml = serde_yaml::from_str::<serde_yaml::Value>(&yaml).unwrap();
+        assert_eq!(
+            yaml["http"]["routers"]["app-blue"],
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "rule: Host(`app-blue.example.com`)\nservice: app"
+            )
+            .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_applies_presets() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/traefik/presets.yml",
+            "secure-web:\n\
+             - traefik.http.routers.{{ normalize .Name }}.entrypoints=websecure\n\
+             - traefik.http.routers.{{ normalize .Name }}.tls.certresolver=le\n",
+        );
+        fs.add_file(
+            "/etc/systemd/system/web.service",
+            "[X-Traefik]\nPreset=secure-web\nPort=8080\n\
+             Label=traefik.http.routers.web.tls.certresolver=internal",
+        );
+        fs.add_file(
+            "/etc/systemd/system/broken.service",
+            "[X-Traefik]\nPreset=secure\nPort=8081",
+        );
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings {
+            presets: Some(Arc::new(
+                Presets::load(PathBuf::from("/etc/traefik/presets.yml"), fs.as_ref()).unwrap(),
+            )),
+            ..Default::default()
+        };
+        let handle = |unit: &'static str| {
+            let (dbus, fs, settings) = (&dbus, fs.clone(), &settings);
+            async move {
+                handle_service_state_changed(
+                    dbus,
+                    true,
+                    &unit_data_with_fragment(unit),
+                    fs.as_ref(),
+                    Path::new("/out"),
+                    settings,
+                )
+                .await
+            }
+        };
+
+        let error = handle("broken.service").await.unwrap_err();
+        assert!(format!("{error:#}").contains("unknown preset secure"));
+        assert!(!fs.file_exists_in_memory("/out/broken.service.yml"));
+        handle("web.service").await.unwrap();
+
+        let yaml = fs.get_file_content("/out/web.service.yml").unwrap();
+        let yaml = serde_yaml::from_str::<serde_yaml::Value>(&yaml).unwrap();
+        assert_eq!(
+            yaml["http"]["routers"]["web"],
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "entryPoints: [websecure]\ntls:\n  certResolver: internal\nservice: web"
+            )
+            .unwrap()
+        );
+
+        fs.add_file(
+            "/etc/traefik/presets.yml",
+            "secure:\n- traefik.http.routers.{{ normalize .Name }}.entrypoints=websecure\n",
+        );
+        settings
+            .presets
+            .as_ref()
+            .unwrap()
+            .reload(fs.as_ref())
+            .unwrap();
+        let error = handle("web.service").await.unwrap_err();
+        assert!(format!("{error:#}").contains("unknown preset secure-web"));
+        assert!(!fs.file_exists_in_memory("/out/web.service.yml"));
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_expands_environment_in_presets() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/traefik/presets.yml",
+            "local:\n\
+             - traefik.http.services.{{ normalize .Name }}.loadbalancer.server.port=${PORT}\n",
+        );
+        fs.add_file(
+            "/etc/systemd/system/web.service",
+            "[X-Traefik]\nPreset=local\nLabel=traefik.http.routers.web.rule=Host(`$HOST`)",
+        );
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings {
+            presets: Some(Arc::new(
+                Presets::load(PathBuf::from("/etc/traefik/presets.yml"), fs.as_ref()).unwrap(),
+            )),
+            ..Default::default()
+        };
+        let unit_data =
+            unit_data_with_environment("web.service", &["PORT=8080", "HOST=web.example.com"]);
+
+        handle_service_state_changed(
+            &dbus,
+            true,
+            &unit_data,
+            fs.as_ref(),
+            Path::new("/out"),
+            &settings,
+        )
+        .await
+        .unwrap();
+
+        let yaml = fs.get_file_content("/out/web.service.yml").unwrap();
+        let yaml = serde_yaml::from_str::<serde_yaml::Value>(&yaml).unwrap();
+        assert_eq!(
+            yaml["http"],
+            serde_yaml::from_str::<serde_yaml::Value>(
+                "routers:\n  web:\n    rule: Host(`web.example.com`)\n    service: web\n\
+                 services:\n  web:\n    loadBalancer:\n      servers:\n\
+                 \x20       - url: http://127.0.0.1:8080"
+            )
+            .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_service_state_changed_removes_the_file_of_a_failing_unit() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/web.service",
+            "[X-Traefik]\nPort=8080\nLabel=traefik.http.routers.web.priority=10",
+        );
+        let dbus = DBusContext::new_test_context(
+            Arc::new(crate::dbus::MockSystemdManager::new()),
+            fs.clone(),
+        );
+        let settings = GenerationSettings::default();
+        let unit_data = unit_data_with_fragment("web.service");
+        let handle = || {
+            handle_service_state_changed(
+                &dbus,
+                true,
+                &unit_data,
+                fs.as_ref(),
+                Path::new("/out"),
+                &settings,
+            )
+        };
+
+        handle().await.unwrap();
+        assert!(fs.file_exists_in_memory("/out/web.service.yml"));
+        fs.add_file(
+            "/etc/systemd/system/web.service",
+            "[X-Traefik]\nPort=8080\nLabel=traefik.http.routers.web.priority=high",
+        );
+        let error = handle().await.unwrap_err();
+        assert!(format!("{error:#}").contains("invalid value"), "{error:#}");
+        assert!(!fs.file_exists_in_memory("/out/web.service.yml"));
+    }
+
+    #[test]
+    fn test_defined_names_finds_clashes_between_files() {
+        let names = DefinedNames::default();
+        let app = |host: &str| format!("http:\n  routers:\n    app:\n      rule: Host(`{host}`)\n");
+        let first = Path::new("/out/user-1000/app.service.yml");
+        let second = Path::new("/out/user-1001/app.service.yml");
+
+        assert_eq!(names.record(first, &app("a")), Vec::<String>::new());
+        assert_eq!(names.record(first, &app("b")), Vec::<String>::new());
+        assert_eq!(names.record(second, &app("b")), Vec::<String>::new());
+        assert_eq!(names.record(second, &app("c")), vec!["http.routers.app"]);
+        names.forget_dir(Path::new("/out/user-1000"));
+        assert_eq!(names.record(second, &app("d")), Vec::<String>::new());
+    }
+
     #[test]
     #[serial]
     fn test_remove_unit_yaml_deletes_file() {
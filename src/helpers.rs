@@ -21,18 +21,30 @@ pub fn sanitize_filename(s: &str) -> String {
     }
 }
 
-pub trait AsyncMap {
-    async fn async_map<F, Fut, T, U>(self, f: F) -> Vec<U>
-    where
-        F: Fn(T) -> Fut,
-        Fut: Future<Output = U>,
-        Self: Sized + IntoIterator<Item = T>,
-    {
-        futures::future::join_all(self.into_iter().map(f)).await
+/// Turns a name into something usable as a router name or host name label, like Traefik's
+/// `normalize`: every run of characters other than ASCII letters and digits becomes a dash.
+pub fn normalize(s: &str) -> String {
+    let normalized = sanitize_filename(s)
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if normalized.is_empty() {
+        "untitled".to_string()
+    } else {
+        normalized
     }
 }
 
-impl<T, I> AsyncMap for I where I: IntoIterator<Item = T> {}
+/// 64-bit FNV-1a hash. Stable across builds and platforms, so it can be persisted.
+pub fn fnv1a_64(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
 
 #[cfg(test)]
 mod tests {
@@ -62,6 +74,21 @@ mod tests {
         assert_eq!(sanitize_filename("@#$%"), "untitled");
     }
 
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("app@blue"), "app-blue");
+        assert_eq!(normalize("my_app.v2"), "my-app-v2");
+        assert_eq!(normalize("café--bar"), "cafe-bar");
+        assert_eq!(normalize("._."), "untitled");
+    }
+
+    #[test]
+    fn test_fnv1a_64_known_values() {
+        assert_eq!(fnv1a_64(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a_64(b"foobar"), 0x85944171f73967e8);
+    }
+
     #[test]
     fn sanitize_empty_string() {
         let result = sanitize_filename("");
@@ -105,6 +132,13 @@ mod proptests {
             prop_assert_eq!(first, second, "Sanitization should be idempotent");
         }
 
+        #[test]
+        fn prop_normalize_output_is_a_host_label(s in ".*") {
+            let result = normalize(&s);
+            prop_assert!(!result.starts_with('-') && !result.ends_with('-'));
+            prop_assert!(result.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        }
+
         #[test]
         fn prop_sanitize_output_is_safe(s in ".*") {
             let result = sanitize_filename(&s);
@@ -1,14 +1,44 @@
-use anyhow::{Result, anyhow};
-use std::{fs, path::Path};
+use anyhow::{Context, Result, anyhow};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 pub trait FileSystem: Send + Sync {
     fn read_to_string(&self, path: &Path) -> Result<String>;
-    fn write(&self, path: &Path, contents: &str) -> Result<()>;
+    /// Writes `contents` to a hidden temporary file next to `path` and renames it over `path`,
+    /// so readers either see the previous file or the complete new one.
+    fn write_atomic(&self, path: &Path, contents: &str) -> Result<()>;
+    /// Whether `path` is a regular file.
     fn exists(&self, path: &Path) -> bool;
+    fn dir_exists(&self, path: &Path) -> bool;
+    /// Lists the regular files directly inside `path`.
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>>;
+    /// Lists the directories directly inside `path`.
+    fn read_subdirs(&self, path: &Path) -> Result<Vec<PathBuf>>;
     fn remove_file(&self, path: &Path) -> Result<()>;
     fn create_dir_all(&self, path: &Path) -> Result<()>;
 }
 
+/// Temporary file used by `write_atomic`. It is hidden and does not end in `.yml`/`.yaml`/`.toml`,
+/// so Traefik's file provider ignores it. Every call gets a new one, so concurrent writes of the
+/// same file, e.g. by the system and user sessions, do not write to each other's.
+pub fn atomic_temp_path(path: &Path) -> Result<PathBuf> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Path has no file name: {}", path.display()))?;
+    let temp_name = format!(
+        ".{}.{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    Ok(path.with_file_name(temp_name))
+}
+
 pub struct RealFileSystem;
 
 impl FileSystem for RealFileSystem {
@@ -16,8 +46,31 @@ impl FileSystem for RealFileSystem {
         Ok(std::fs::read_to_string(path)?)
     }
 
-    fn write(&self, path: &Path, contents: &str) -> Result<()> {
-        Ok(std::fs::write(path, contents)?)
+    fn write_atomic(&self, path: &Path, contents: &str) -> Result<()> {
+        let temp_path = atomic_temp_path(path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // never reuse a file left behind, which another writer may still hold
+        let mut file = fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .with_context(|| format!("creating {}", temp_path.display()))?;
+        let result = (|| -> Result<()> {
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            fs::rename(&temp_path, path).with_context(|| {
+                format!("renaming {} to {}", temp_path.display(), path.display())
+            })?;
+            fs::File::open(dir)?.sync_all()?;
+            Ok(())
+        })();
+        if result.is_err() && temp_path.exists() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        result
     }
 
     fn exists(&self, path: &Path) -> bool {
@@ -33,6 +86,18 @@ impl FileSystem for RealFileSystem {
         false
     }
 
+    fn dir_exists(&self, path: &Path) -> bool {
+        path.is_dir()
+    }
+
+    fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        read_entries(path, |file_type| file_type.is_file())
+    }
+
+    fn read_subdirs(&self, path: &Path) -> Result<Vec<PathBuf>> {
+        read_entries(path, |file_type| file_type.is_dir())
+    }
+
     fn remove_file(&self, path: &Path) -> Result<()> {
         if path.exists() {
             match std::fs::remove_file(path) {
@@ -52,24 +117,64 @@ impl FileSystem for RealFileSystem {
     }
 }
 
+fn read_entries(path: &Path, wanted: impl Fn(fs::FileType) -> bool) -> Result<Vec<PathBuf>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(path).with_context(|| format!("reading {}", path.display()))? {
+        let entry = entry?;
+        if wanted(entry.file_type()?) {
+            entries.push(entry.path());
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
 #[cfg(test)]
 pub mod tests {
     use super::*;
     use anyhow::bail;
-    use std::collections::HashMap;
+    use std::collections::{BTreeSet, HashMap};
     use std::sync::{Arc, Mutex};
 
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum FsOperation {
+        Write(String),
+        Rename { from: String, to: String },
+        Remove(String),
+    }
+
     pub struct MockFileSystem {
         files: Arc<Mutex<HashMap<String, String>>>,
+        dirs: Arc<Mutex<BTreeSet<PathBuf>>>,
+        operations: Arc<Mutex<Vec<FsOperation>>>,
     }
 
     impl MockFileSystem {
         pub fn new() -> Self {
             Self {
                 files: Arc::new(Mutex::new(HashMap::new())),
+                dirs: Arc::new(Mutex::new(BTreeSet::new())),
+                operations: Arc::new(Mutex::new(Vec::new())),
             }
         }
 
+        /// Operations done through the `FileSystem` trait, in order. Files added with `add_file` are not included.
+        pub fn operations(&self) -> Vec<FsOperation> {
+            self.operations.lock().unwrap().clone()
+        }
+
+        fn record(&self, operation: FsOperation) {
+            self.operations.lock().unwrap().push(operation);
+        }
+
+        fn write(&self, path: &Path, contents: &str) -> Result<()> {
+            let mut files = self.files.lock().unwrap();
+            let path_str = path.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
+            files.insert(path_str.to_string(), contents.to_string());
+            self.record(FsOperation::Write(path_str.to_string()));
+            Ok(())
+        }
+
         pub fn add_file(&self, path: impl Into<String>, content: impl Into<String>) {
             self.files
                 .lock()
@@ -84,6 +189,19 @@ pub mod tests {
         pub fn file_exists_in_memory(&self, path: impl AsRef<str>) -> bool {
             self.files.lock().unwrap().contains_key(path.as_ref())
         }
+
+        /// Directories made with `create_dir_all` and the ones holding files, with their parents.
+        fn dirs(&self) -> BTreeSet<PathBuf> {
+            let files = self.files.lock().unwrap();
+            let dirs = self.dirs.lock().unwrap();
+            files
+                .keys()
+                .filter_map(|file| Path::new(file).parent())
+                .chain(dirs.iter().map(PathBuf::as_path))
+                .flat_map(Path::ancestors)
+                .map(Path::to_path_buf)
+                .collect()
+        }
     }
 
     impl FileSystem for MockFileSystem {
@@ -96,10 +214,20 @@ pub mod tests {
             }
         }
 
-        fn write(&self, path: &Path, contents: &str) -> Result<()> {
+        fn write_atomic(&self, path: &Path, contents: &str) -> Result<()> {
+            let temp_path = atomic_temp_path(path)?;
+            self.write(&temp_path, contents)?;
             let mut files = self.files.lock().unwrap();
-            let path_str = path.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
-            files.insert(path_str.to_string(), contents.to_string());
+            let from = temp_path.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
+            let to = path.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
+            let contents = files
+                .remove(from)
+                .ok_or_else(|| anyhow!("File not found: {:?}", temp_path))?;
+            files.insert(to.to_string(), contents);
+            self.record(FsOperation::Rename {
+                from: from.to_string(),
+                to: to.to_string(),
+            });
             Ok(())
         }
 
@@ -112,15 +240,158 @@ pub mod tests {
             files.contains_key(path_str)
         }
 
+        fn dir_exists(&self, path: &Path) -> bool {
+            self.dirs().contains(path)
+        }
+
+        fn read_dir(&self, path: &Path) -> Result<Vec<PathBuf>> {
+            let files = self.files.lock().unwrap();
+            let mut paths = files
+                .keys()
+                .map(PathBuf::from)
+                .filter(|p| p.parent() == Some(path))
+                .collect::<Vec<_>>();
+            paths.sort();
+            Ok(paths)
+        }
+
+        fn read_subdirs(&self, path: &Path) -> Result<Vec<PathBuf>> {
+            Ok(self
+                .dirs()
+                .into_iter()
+                .filter(|dir| dir.parent() == Some(path))
+                .collect())
+        }
+
         fn remove_file(&self, path: &Path) -> Result<()> {
             let mut files = self.files.lock().unwrap();
             let path_str = path.to_str().ok_or_else(|| anyhow!("Invalid path"))?;
-            files.remove(path_str);
+            if files.remove(path_str).is_some() {
+                self.record(FsOperation::Remove(path_str.to_string()));
+            }
             Ok(())
         }
 
-        fn create_dir_all(&self, _path: &Path) -> Result<()> {
+        fn create_dir_all(&self, path: &Path) -> Result<()> {
+            self.dirs.lock().unwrap().insert(path.to_path_buf());
             Ok(())
         }
     }
+
+    #[test]
+    fn test_atomic_temp_path_is_hidden_and_not_yaml() {
+        let temp_path = atomic_temp_path(Path::new("/etc/traefik/units/app.service.yml")).unwrap();
+        assert_eq!(temp_path.parent(), Some(Path::new("/etc/traefik/units")));
+        let name = temp_path.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".app.service.yml."), "{name}");
+        assert!(name.ends_with(".tmp"), "{name}");
+        assert_ne!(
+            atomic_temp_path(Path::new("/etc/traefik/units/app.service.yml")).unwrap(),
+            temp_path
+        );
+    }
+
+    #[test]
+    fn test_real_write_atomic_of_the_same_file_at_once() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.service.yml");
+        let contents = (0..8)
+            .map(|i| format!("{i}").repeat(100_000))
+            .collect::<Vec<_>>();
+
+        std::thread::scope(|scope| {
+            for contents in &contents {
+                let path = &path;
+                scope.spawn(move || RealFileSystem.write_atomic(path, contents).unwrap());
+            }
+        });
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains(&written), "file has mixed contents");
+        let entries = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec!["app.service.yml"]);
+    }
+
+    #[test]
+    fn test_real_write_atomic_replaces_file_and_leaves_no_temp_file() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("app.service.yml");
+        std::fs::write(&path, "old").unwrap();
+
+        RealFileSystem.write_atomic(&path, "new").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        let entries = std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(entries, vec!["app.service.yml"]);
+    }
+
+    #[test]
+    fn test_real_dirs_are_not_files() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let dir = temp_dir.path().join("user-1000");
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(temp_dir.path().join("app.service.yml"), "").unwrap();
+
+        assert!(!RealFileSystem.exists(&dir));
+        assert!(RealFileSystem.dir_exists(&dir));
+        assert!(!RealFileSystem.dir_exists(&temp_dir.path().join("app.service.yml")));
+        assert!(!RealFileSystem.dir_exists(&temp_dir.path().join("missing")));
+        assert_eq!(
+            RealFileSystem.read_subdirs(temp_dir.path()).unwrap(),
+            vec![dir]
+        );
+    }
+
+    #[test]
+    fn test_mock_dirs_are_not_files() {
+        let fs = MockFileSystem::new();
+        fs.add_file("/out/user-1000/app.service.yml", "");
+        fs.create_dir_all(Path::new("/out/user-1001")).unwrap();
+
+        assert!(!fs.exists(Path::new("/out/user-1000")));
+        assert!(fs.dir_exists(Path::new("/out/user-1000")));
+        assert!(fs.dir_exists(Path::new("/out/user-1001")));
+        assert!(!fs.dir_exists(Path::new("/out/user-1000/app.service.yml")));
+        assert_eq!(
+            fs.read_subdirs(Path::new("/out")).unwrap(),
+            vec![
+                PathBuf::from("/out/user-1000"),
+                PathBuf::from("/out/user-1001")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mock_write_atomic_goes_through_rename() {
+        let fs = MockFileSystem::new();
+        let path = Path::new("/out/app.service.yml");
+
+        fs.write_atomic(path, "content").unwrap();
+
+        let operations = fs.operations();
+        let Some(FsOperation::Write(temp_path)) = operations.first() else {
+            panic!("no temp file written: {operations:?}");
+        };
+        assert_eq!(
+            fs.operations(),
+            vec![
+                FsOperation::Write(temp_path.to_string()),
+                FsOperation::Rename {
+                    from: temp_path.to_string(),
+                    to: "/out/app.service.yml".to_string()
+                },
+            ]
+        );
+        assert!(!fs.file_exists_in_memory(temp_path));
+        assert_eq!(
+            fs.get_file_content("/out/app.service.yml").unwrap(),
+            "content"
+        );
+    }
 }
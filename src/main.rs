@@ -1,5 +1,9 @@
 mod args;
+mod constraints;
 mod dbus;
+mod directives;
+mod environment;
+mod filter;
 mod generation_engine;
 mod helpers;
 mod infra;
@@ -7,20 +11,30 @@ mod logger;
 // auto-generated with: zbus-xmlgen system org.freedesktop.systemd1 /org/freedesktop/systemd1
 #[allow(clippy::all)]
 mod manager;
+mod presets;
+mod schema;
 // auto-generated with: zbus-xmlgen system org.freedesktop.systemd1 /org/freedesktop/systemd1/unit/sleep_2eservice
 #[allow(clippy::all)]
 mod service;
+mod supervisor;
+mod template;
 // auto-generated with: zbus-xmlgen system org.freedesktop.systemd1 /org/freedesktop/systemd1/unit/sleep_2eservice
 #[allow(clippy::all)]
 mod unit;
+mod unit_file;
+mod users;
 mod yaml;
 
 #[macro_use]
 extern crate log;
 use crate::{
     dbus::DBusContext,
-    generation_engine::{process_service_change_messages, reconcile},
+    filter::UnitFilter,
+    generation_engine::GenerationSettings,
     infra::{FileSystem, RealFileSystem},
+    presets::Presets,
+    supervisor::{Backoff, reload_presets_on_hangup, shutdown_on_signals, supervise},
+    users::{UserManagers, user_bus},
 };
 
 use anyhow::{Context, Result};
@@ -32,7 +46,51 @@ async fn main() -> std::result::Result<(), String> {
     let args = args::Cli::parse();
     let _logger_handle = logger::start(args.verbosity.log_level_filter(), args.log_hide_date)
         .map_err(|e| format!("Error starting logger: {e}"))?;
-    if let Err(e) = run(args.traefik_out_dir).await.map_err(|e| e.to_string()) {
+    let traefik_dir = args.traefik_out_dir()?;
+    let filter = UnitFilter::new(
+        args.include,
+        args.exclude,
+        args.slice,
+        args.service_user,
+        args.unit_file_state,
+    )
+    .map_err(|e| format!("Invalid unit filter: {e:#}"))?;
+    let constraints = args
+        .constraints
+        .as_deref()
+        .map(str::parse)
+        .transpose()
+        .map_err(|e: anyhow::Error| format!("Invalid constraints: {e:#}"))?;
+    let default_rule = args
+        .default_rule
+        .as_deref()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e: anyhow::Error| format!("Invalid default rule: {e:#}"))?;
+    let presets = args
+        .presets
+        .map(|path| Presets::load(path, &RealFileSystem))
+        .transpose()
+        .map_err(|e| format!("Invalid presets file: {e:#}"))?
+        .map(Arc::new);
+    let settings = GenerationSettings {
+        constraints,
+        exposed_by_default: args.exposed_by_default,
+        default_rule,
+        presets,
+        name_suffix: String::new(),
+        defined_names: Default::default(),
+    };
+    if let Err(e) = run(
+        args.bus,
+        args.all_users,
+        traefik_dir,
+        Arc::new(filter),
+        Arc::new(settings),
+    )
+    .await
+    .map_err(|e| e.to_string())
+    {
         error!("Got an error: {}", e);
         eprintln!("Got an error: {}", e);
         return Err(e);
@@ -40,42 +98,60 @@ async fn main() -> std::result::Result<(), String> {
     Ok(())
 }
 
-async fn run(traefik_dir: std::path::PathBuf) -> Result<()> {
+async fn run(
+    bus: args::Bus,
+    all_users: bool,
+    traefik_dir: std::path::PathBuf,
+    filter: Arc<UnitFilter>,
+    settings: Arc<GenerationSettings>,
+) -> Result<()> {
+    if all_users && bus == args::Bus::User {
+        anyhow::bail!("--all-users needs the system manager, it cannot be used with --bus user");
+    }
     let fs = Arc::new(RealFileSystem);
     fs.create_dir_all(&traefik_dir)
         .context("creating traefik dynamic output dir")?;
     info!("Traefik dynamic output dir: {}", traefik_dir.display());
+    info!("Connecting to systemd on bus: {bus}");
 
-    let dbus = DBusContext::new().await?;
-    let watched = dbus.list_units().await?;
-    if log_enabled!(log::Level::Info) {
-        let read = watched.read().await;
-        let watched_units = read.keys().cloned().collect::<Vec<_>>();
-        if watched_units.is_empty() {
-            info!("No units initially being watched. They might all be stopped.");
-        } else {
-            info!("Initial watched units: {}", watched_units.join(", "));
-        }
+    let shutdown = shutdown_on_signals()?;
+    if let Some(presets) = &settings.presets {
+        reload_presets_on_hangup(presets.clone(), fs.clone())?;
     }
-    let (watch_join_handles, rx_new_unit) = dbus.watch_units(watched.clone()).await;
-
-    if let Err(e) = reconcile(&dbus, &watched, fs.as_ref(), &traefik_dir).await {
-        error!("initial reconcile error: {:#}", e);
+    let system = supervise(
+        || DBusContext::new(&bus, filter.clone()),
+        fs.clone(),
+        &traefik_dir,
+        settings.clone(),
+        Backoff::default(),
+        shutdown.clone(),
+    );
+    if all_users {
+        let users = UserManagers::new(
+            Arc::new({
+                let filter = filter.clone();
+                move |uid| {
+                    let filter = filter.clone();
+                    Box::pin(async move { DBusContext::new(&user_bus(uid), filter).await })
+                }
+            }),
+            fs,
+            traefik_dir.clone(),
+            settings,
+            Backoff::default(),
+            shutdown,
+        );
+        tokio::try_join!(
+            system,
+            users.supervise(
+                || DBusContext::new(&bus, Arc::new(UnitFilter::default())),
+                Backoff::default()
+            )
+        )?;
+    } else {
+        system.await?;
     }
-
-    let (tx_new_job_event, process_msgs_join_handle) =
-        process_service_change_messages(watched.clone(), dbus.clone(), fs.clone(), &traefik_dir)
-            .await?;
-    dbus.get_messages(tx_new_job_event, watched, rx_new_unit)
-        .await?; // will block
-
     trace!("Shutting down");
-    for handle in watch_join_handles
-        .into_iter()
-        .chain([process_msgs_join_handle])
-    {
-        handle.abort();
-    }
     Ok(())
 }
 
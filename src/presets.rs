@@ -0,0 +1,190 @@
+use anyhow::{Context, Result, anyhow};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::RwLock,
+};
+use tokio::sync::watch;
+
+use crate::{
+    infra::FileSystem,
+    template::{RuleTemplate, TemplateData},
+};
+
+/// Named bundles of labels that units pull in with `Preset=`, read from a YAML file mapping each
+/// name to its labels, which are templates like the default rule:
+///
+/// ```yaml
+/// secure-web:
+///   - traefik.http.routers.{{ normalize .Name }}.entrypoints=websecure
+///   - traefik.http.routers.{{ normalize .Name }}.tls.certresolver=le
+/// ```
+#[derive(Debug)]
+pub struct Presets {
+    path: PathBuf,
+    presets: RwLock<BTreeMap<String, Vec<RuleTemplate>>>,
+    reloaded: watch::Sender<()>,
+}
+
+impl Presets {
+    pub fn load(path: PathBuf, fs: &dyn FileSystem) -> Result<Self> {
+        let presets = read(&path, fs)?;
+        Ok(Self {
+            path,
+            presets: RwLock::new(presets),
+            reloaded: watch::channel(()).0,
+        })
+    }
+
+    /// Reads the file again and notifies the subscribers. When it is invalid, the presets loaded
+    /// before are kept.
+    pub fn reload(&self, fs: &dyn FileSystem) -> Result<()> {
+        let presets = read(&self.path, fs)?;
+        info!(
+            "Loaded {} preset(s) from {}",
+            presets.len(),
+            self.path.display()
+        );
+        *self.presets.write().expect("presets lock poisoned") = presets;
+        self.reloaded.send_replace(());
+        Ok(())
+    }
+
+    /// Changes every time the presets are reloaded.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.reloaded.subscribe()
+    }
+
+    /// The labels of the named presets, in order, rendered for a unit. An unknown preset is an
+    /// error.
+    pub fn expand(&self, names: &[String], data: &TemplateData) -> Result<Vec<String>> {
+        let presets = self.presets.read().expect("presets lock poisoned");
+        let mut labels = vec![];
+        for name in names {
+            let templates = presets.get(name).ok_or_else(|| {
+                anyhow!(
+                    "unknown preset {name}, {} defines: {}",
+                    self.path.display(),
+                    presets.keys().cloned().collect::<Vec<_>>().join(", ")
+                )
+            })?;
+            for template in templates {
+                labels.push(
+                    template
+                        .render(data)
+                        .with_context(|| format!("rendering preset {name}"))?,
+                );
+            }
+        }
+        Ok(labels)
+    }
+}
+
+fn read(path: &Path, fs: &dyn FileSystem) -> Result<BTreeMap<String, Vec<RuleTemplate>>> {
+    let text = fs
+        .read_to_string(path)
+        .with_context(|| format!("reading presets file {}", path.display()))?;
+    let raw: BTreeMap<String, Vec<String>> = serde_yaml::from_str(&text)
+        .with_context(|| format!("parsing presets file {}", path.display()))?;
+    raw.into_iter()
+        .map(|(name, labels)| {
+            let templates = labels
+                .iter()
+                .map(|label| label.parse())
+                .collect::<Result<Vec<RuleTemplate>>>()
+                .with_context(|| format!("invalid label in preset {name}"))?;
+            Ok((name, templates))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infra::tests::MockFileSystem;
+    use pretty_assertions::assert_eq;
+
+    const PRESETS: &str = r#"
+secure-web:
+  - traefik.http.routers.{{ normalize .Name }}.entrypoints=websecure
+  - traefik.http.routers.{{ normalize .Name }}.tls.certresolver=le
+instance-host:
+  - "traefik.http.routers.{{ normalize .Name }}.rule=Host(`{{ .Instance }}.example.com`)"
+"#;
+
+    fn expand(presets: &Presets, names: &[&str]) -> Result<Vec<String>> {
+        let names = names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        presets.expand(
+            &names,
+            &TemplateData {
+                name: "app@blue",
+                instance: "blue",
+                labels: &[],
+            },
+        )
+    }
+
+    fn load(contents: &str) -> (Presets, MockFileSystem) {
+        let fs = MockFileSystem::new();
+        fs.add_file("/etc/traefik/presets.yml", contents);
+        let presets = Presets::load(PathBuf::from("/etc/traefik/presets.yml"), &fs).unwrap();
+        (presets, fs)
+    }
+
+    #[test]
+    fn test_expand_presets() {
+        let (presets, _) = load(PRESETS);
+        assert_eq!(
+            expand(&presets, &["secure-web", "instance-host"]).unwrap(),
+            vec![
+                "traefik.http.routers.app-blue.entrypoints=websecure",
+                "traefik.http.routers.app-blue.tls.certresolver=le",
+                "traefik.http.routers.app-blue.rule=Host(`blue.example.com`)",
+            ]
+        );
+        assert_eq!(expand(&presets, &[]).unwrap(), Vec::<String>::new());
+        assert_eq!(
+            format!("{:#}", expand(&presets, &["secure"]).unwrap_err()),
+            "unknown preset secure, /etc/traefik/presets.yml defines: instance-host, secure-web"
+        );
+    }
+
+    #[test]
+    fn test_invalid_presets_file() {
+        let fs = MockFileSystem::new();
+        let load = |contents: &str| {
+            fs.add_file("/presets.yml", contents);
+            Presets::load(PathBuf::from("/presets.yml"), &fs).map(|_| ())
+        };
+        assert!(format!("{:#}", load("web: [a=1").unwrap_err()).contains("parsing presets file"));
+        assert!(format!("{:#}", load("web: a=1").unwrap_err()).contains("parsing presets file"));
+        assert!(
+            format!("{:#}", load("web: ['a={{ .Unit }}']").unwrap_err())
+                .contains("invalid label in preset web")
+        );
+        assert!(Presets::load(PathBuf::from("/missing.yml"), &fs).is_err());
+    }
+
+    #[test]
+    fn test_reload_keeps_valid_presets_and_notifies() {
+        let (presets, fs) = load(PRESETS);
+        let reloaded = presets.subscribe();
+
+        fs.add_file("/etc/traefik/presets.yml", "secure-web: [oops");
+        assert!(presets.reload(&fs).is_err());
+        assert!(!reloaded.has_changed().unwrap());
+        assert_eq!(expand(&presets, &["secure-web"]).unwrap().len(), 2);
+
+        fs.add_file(
+            "/etc/traefik/presets.yml",
+            "secure-web: ['traefik.http.routers.x.entrypoints=web']",
+        );
+        presets.reload(&fs).unwrap();
+        assert!(reloaded.has_changed().unwrap());
+        assert_eq!(
+            expand(&presets, &["secure-web"]).unwrap(),
+            vec!["traefik.http.routers.x.entrypoints=web"]
+        );
+        assert!(expand(&presets, &["instance-host"]).is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use anyhow::{Result, anyhow, bail};
+use regex::Regex;
+use serde_yaml::Value;
+use std::sync::LazyLock;
+
+use crate::yaml::PathItem;
+
+/// Prefix of a label value that is written as a string whatever type the schema has for its key,
+/// as with YAML's `!!str` tag, e.g. `traefik.http.routers.app.service=!!str 0123`.
+pub const FORCE_STRING: &str = "!!str";
+
+/// The rest of a value that starts with `FORCE_STRING`.
+pub fn forced_string(value: &str) -> Option<&str> {
+    value
+        .trim_start()
+        .strip_prefix(FORCE_STRING)
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        .map(str::trim_start)
+}
+
+/// Type of a value in Traefik's dynamic configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    String,
+    Int,
+    Bool,
+    /// Seconds as an integer, or a Go duration like `1m30s`.
+    Duration,
+    /// Comma separated values, written as a list.
+    StringList,
+}
+
+#[derive(Debug)]
+enum Segment {
+    Name(String),
+    /// `*`, any name.
+    Any,
+    /// `name[]`, any index of a list.
+    Index(String),
+    /// `**`, the last segment: any path below, written as it is, e.g. the settings of a plugin.
+    Rest,
+}
+
+#[derive(Debug)]
+struct Entry {
+    segments: Vec<Segment>,
+    /// `None` for `any`: values are typed as YAML would.
+    value_type: Option<ValueType>,
+}
+
+static SCHEMA: LazyLock<Vec<Entry>> = LazyLock::new(|| {
+    parse_schema(include_str!("traefik_schema.txt")).expect("embedded Traefik schema is invalid")
+});
+
+static DURATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^[-+]?((\d+(\.\d*)?|\.\d+)(ns|us|µs|ms|s|m|h))+$").expect("valid regex")
+});
+
+fn parse_schema(text: &str) -> Result<Vec<Entry>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (path, value_type) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("missing type in schema line '{line}'"))?;
+            let value_type = match value_type.trim() {
+                "string" => Some(ValueType::String),
+                "int" => Some(ValueType::Int),
+                "bool" => Some(ValueType::Bool),
+                "duration" => Some(ValueType::Duration),
+                "[]string" => Some(ValueType::StringList),
+                "any" => None,
+                other => bail!("unknown type '{other}' in schema line '{line}'"),
+            };
+            let segments = path
+                .split('.')
+                .map(|segment| match segment {
+                    "*" => Segment::Any,
+                    "**" => Segment::Rest,
+                    _ => match segment.strip_suffix("[]") {
+                        Some(name) => Segment::Index(name.to_string()),
+                        None => Segment::Name(segment.to_string()),
+                    },
+                })
+                .collect::<Vec<_>>();
+            if segments[..segments.len() - 1]
+                .iter()
+                .any(|segment| matches!(segment, Segment::Rest))
+            {
+                bail!("** is not the last segment in schema line '{line}'");
+            }
+            Ok(Entry {
+                segments,
+                value_type,
+            })
+        })
+        .collect()
+}
+
+fn segment_matches(segment: &Segment, item: &PathItem) -> bool {
+    match (segment, item) {
+        (Segment::Any, PathItem::Key(_)) | (Segment::Rest, _) => true,
+        (Segment::Name(name), PathItem::Key(key)) => name.eq_ignore_ascii_case(key),
+        (Segment::Index(name), PathItem::KeyIndex(key, _)) => name.eq_ignore_ascii_case(key),
+        _ => false,
+    }
+}
+
+/// Whether `path` is the path of the entry or one of its parents, or below a `**`. An index into a
+/// `[]string`, e.g. `entryPoints[1]`, matches it too.
+fn entry_matches(entry: &Entry, path: &[PathItem]) -> bool {
+    let last = entry.segments.len() - 1;
+    (path.len() <= entry.segments.len() || is_subtree(entry))
+        && entry
+            .segments
+            .iter()
+            .zip(path)
+            .enumerate()
+            .all(|(i, (segment, item))| match (segment, item) {
+                (Segment::Name(name), PathItem::KeyIndex(key, _)) => {
+                    i == last
+                        && entry.value_type == Some(ValueType::StringList)
+                        && name.eq_ignore_ascii_case(key)
+                }
+                _ => segment_matches(segment, item),
+            })
+}
+
+fn is_subtree(entry: &Entry) -> bool {
+    matches!(entry.segments.last(), Some(Segment::Rest))
+}
+
+/// Where a label path starts below the `traefik` root, if it has one.
+fn root_len(path: &[PathItem]) -> usize {
+    usize::from(matches!(path.first(), Some(PathItem::Key(root)) if root == "traefik"))
+}
+
+/// The type the schema has for a label path, with or without the `traefik` root. An index into a
+/// `[]string`, e.g. `entryPoints[1]`, is a string.
+pub fn value_type(path: &[PathItem]) -> Option<ValueType> {
+    let path = &path[root_len(path)..];
+    let entry = SCHEMA.iter().find(|entry| {
+        let is_value = if is_subtree(entry) {
+            path.len() >= entry.segments.len()
+        } else {
+            path.len() == entry.segments.len()
+        };
+        is_value && entry_matches(entry, path)
+    })?;
+    match (path.last(), entry.value_type) {
+        (Some(PathItem::KeyIndex(..)), Some(ValueType::StringList)) => Some(ValueType::String),
+        (_, value_type) => value_type,
+    }
+}
+
+/// Rewrites the names in a label path to Traefik's canonical casing, e.g. `loadbalancer` to
+/// `loadBalancer`, keeping the ones matched by `*` or `**`, like router names. Returns false when the path
+/// is not in the schema, neither as a value nor as one of its parents.
+pub fn canonicalize(path: &mut [PathItem]) -> bool {
+    let start = root_len(path);
+    let path = &mut path[start..];
+    let Some(entry) = SCHEMA.iter().find(|entry| entry_matches(entry, path)) else {
+        return false;
+    };
+    for (segment, item) in entry.segments.iter().zip(path.iter_mut()) {
+        match (segment, item) {
+            (Segment::Name(name) | Segment::Index(name), PathItem::Key(key))
+            | (Segment::Name(name) | Segment::Index(name), PathItem::KeyIndex(key, _)) => {
+                key.clone_from(name)
+            }
+            _ => {}
+        }
+    }
+    true
+}
+
+impl ValueType {
+    /// Converts a raw label value to this type. Quotes are part of the value, as the ones meant
+    /// for systemd were removed when the unit file was read.
+    pub fn parse(self, raw: &str) -> Result<Value> {
+        let value = raw.trim().to_string();
+        Ok(match self {
+            ValueType::String => Value::String(value),
+            ValueType::Int => Value::Number(
+                value
+                    .parse::<i64>()
+                    .map_err(|_| anyhow!("expected an integer, got '{value}'"))?
+                    .into(),
+            ),
+            ValueType::Bool => match value.to_ascii_lowercase().as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => bail!("expected true or false, got '{value}'"),
+            },
+            ValueType::Duration => match value.parse::<i64>() {
+                Ok(seconds) => Value::Number(seconds.into()),
+                Err(_) if DURATION.is_match(&value) => Value::String(value),
+                Err(_) => bail!("expected a duration like 10s or 1m30s, got '{value}'"),
+            },
+            ValueType::StringList => {
+                let items = match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+                    // a YAML flow list, e.g. `[web, websecure]`
+                    Some(inner) => inner,
+                    None => &value,
+                };
+                Value::Sequence(
+                    items
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|item| !item.is_empty())
+                        .map(|item| Value::String(item.to_string()))
+                        .collect(),
+                )
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::yaml::Index;
+    use pretty_assertions::assert_eq;
+
+    fn path(items: &[&str]) -> Vec<PathItem> {
+        items
+            .iter()
+            .map(
+                |item| match item.strip_suffix(']').and_then(|i| i.split_once('[')) {
+                    Some((name, idx)) => {
+                        PathItem::KeyIndex(name.to_string(), Index::At(idx.parse().unwrap()))
+                    }
+                    None => PathItem::Key(item.to_string()),
+                },
+            )
+            .collect()
+    }
+
+    #[test]
+    fn test_embedded_schema_parses() {
+        assert!(!SCHEMA.is_empty());
+    }
+
+    #[test]
+    fn test_value_type_lookup() {
+        let lookup = |p: &[&str]| value_type(&path(p));
+        assert_eq!(
+            lookup(&["traefik", "http", "routers", "app", "priority"]),
+            Some(ValueType::Int)
+        );
+        assert_eq!(
+            lookup(&["http", "routers", "app", "entrypoints"]),
+            Some(ValueType::StringList)
+        );
+        assert_eq!(
+            lookup(&["http", "routers", "app", "entryPoints[1]"]),
+            Some(ValueType::String)
+        );
+        assert_eq!(
+            lookup(&[
+                "http",
+                "services",
+                "app",
+                "loadbalancer",
+                "servers[0]",
+                "url"
+            ]),
+            Some(ValueType::String)
+        );
+        assert_eq!(
+            lookup(&[
+                "http",
+                "middlewares",
+                "h",
+                "headers",
+                "customRequestHeaders",
+                "X-A"
+            ]),
+            Some(ValueType::String)
+        );
+        assert_eq!(lookup(&["http", "routers", "app", "unknown"]), None);
+        assert_eq!(lookup(&["http", "routers", "priority"]), None);
+    }
+
+    #[test]
+    fn test_canonicalize() {
+        let canonical = |p: &[&str]| {
+            let mut path = path(p);
+            let known = canonicalize(&mut path);
+            let names = path
+                .into_iter()
+                .map(|item| match item {
+                    PathItem::Key(key) => key,
+                    PathItem::KeyIndex(key, Index::At(idx)) => format!("{key}[{idx}]"),
+                    PathItem::KeyIndex(key, index) => format!("{key}[{index:?}]"),
+                })
+                .collect::<Vec<_>>();
+            (known, names.join("."))
+        };
+        assert_eq!(
+            canonical(&[
+                "traefik",
+                "http",
+                "services",
+                "App",
+                "loadbalancer",
+                "passhostheader"
+            ]),
+            (
+                true,
+                "traefik.http.services.App.loadBalancer.passHostHeader".to_string()
+            )
+        );
+        assert_eq!(
+            canonical(&["http", "routers", "app", "TLS", "certresolver"]),
+            (true, "http.routers.app.tls.certResolver".to_string())
+        );
+        assert_eq!(
+            canonical(&["http", "routers", "app", "entrypoints[0]"]),
+            (true, "http.routers.app.entryPoints[0]".to_string())
+        );
+        assert_eq!(
+            canonical(&["http", "routers", "app", "tls"]),
+            (true, "http.routers.app.tls".to_string())
+        );
+        assert_eq!(
+            canonical(&["http", "routers", "app", "prority"]),
+            (false, "http.routers.app.prority".to_string())
+        );
+        assert_eq!(
+            canonical(&[
+                "http",
+                "middlewares",
+                "m",
+                "Plugin",
+                "myPlugin",
+                "Headers[0]",
+                "Name"
+            ]),
+            (
+                true,
+                "http.middlewares.m.plugin.myPlugin.Headers[0].Name".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_subtrees_are_typed_as_yaml() {
+        assert_eq!(
+            value_type(&path(&["http", "middlewares", "m", "plugin", "p", "max"])),
+            None
+        );
+        assert_eq!(
+            value_type(&path(&["http", "middlewares", "m", "plugin"])),
+            None
+        );
+        let error = parse_schema("a.**.b string").unwrap_err();
+        assert!(error.to_string().contains("** is not the last segment"));
+        assert!(parse_schema("a.** any").is_ok());
+    }
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(
+            ValueType::String.parse("0123").unwrap(),
+            Value::String("0123".to_string())
+        );
+        assert_eq!(
+            ValueType::String.parse(r#""x""#).unwrap(),
+            Value::String(r#""x""#.to_string())
+        );
+        assert_eq!(
+            ValueType::Int.parse("10").unwrap(),
+            Value::Number(10.into())
+        );
+        assert!(ValueType::Int.parse("ten").is_err());
+        assert_eq!(ValueType::Bool.parse("True").unwrap(), Value::Bool(true));
+        assert!(ValueType::Bool.parse("yes").is_err());
+        assert_eq!(
+            ValueType::Duration.parse("1m30s").unwrap(),
+            Value::String("1m30s".to_string())
+        );
+        assert_eq!(
+            ValueType::Duration.parse("42").unwrap(),
+            Value::Number(42.into())
+        );
+        assert!(ValueType::Duration.parse("soon").is_err());
+        let list = |items: &[&str]| {
+            Value::Sequence(items.iter().map(|i| Value::String(i.to_string())).collect())
+        };
+        assert_eq!(
+            ValueType::StringList.parse("web, websecure").unwrap(),
+            list(&["web", "websecure"])
+        );
+        assert_eq!(
+            ValueType::StringList.parse("[web, websecure]").unwrap(),
+            list(&["web", "websecure"])
+        );
+        assert_eq!(
+            ValueType::StringList.parse("[web, 'websecure']").unwrap(),
+            list(&["web", "'websecure'"])
+        );
+        assert_eq!(
+            ValueType::StringList
+                .parse("admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/")
+                .unwrap(),
+            list(&["admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/"])
+        );
+    }
+}
@@ -0,0 +1,481 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{future::Future, path::Path, sync::Arc, time::Duration};
+use tokio::{sync::watch, time::Instant};
+
+use crate::{
+    dbus::{DBusContext, SessionEnd},
+    generation_engine::{GenerationSettings, process_service_change_messages, reconcile},
+    infra::FileSystem,
+    presets::Presets,
+};
+
+/// Exponential delay between reconnection attempts.
+#[derive(Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = (self.current * 2).min(self.max);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// Starts over from the initial delay after a session that stayed up at least as long as the
+    /// longest delay. Shorter ones keep the delay growing, so a connection that drops right after
+    /// it is set up is not retried at the initial delay forever.
+    pub fn session_ended(&mut self, lasted: Duration) {
+        if lasted >= self.max {
+            self.reset();
+        }
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Returns a receiver that becomes `true` once SIGINT or SIGTERM is received.
+pub fn shutdown_on_signals() -> Result<watch::Receiver<bool>> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sigint =
+        signal(SignalKind::interrupt()).context("listening for SIGINT (Ctrl+C) signal")?;
+    let mut sigterm = signal(SignalKind::terminate()).context("listening for SIGTERM signal")?;
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigint.recv() => trace!("SIGINT (Ctrl+C) received, stopping..."),
+            _ = sigterm.recv() => trace!("SIGTERM received, stopping..."),
+        }
+        let _ = tx.send(true);
+    });
+    Ok(rx)
+}
+
+/// Reloads the presets file on SIGHUP, e.g. from `systemctl reload`, so the files of all units
+/// are regenerated with the new presets.
+pub fn reload_presets_on_hangup(presets: Arc<Presets>, fs: Arc<dyn FileSystem>) -> Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sighup = signal(SignalKind::hangup()).context("listening for SIGHUP signal")?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            trace!("SIGHUP received, reloading presets...");
+            if let Err(e) = presets.reload(fs.as_ref()) {
+                error!(
+                    "Error reloading presets, keeping the previous ones: {:#}",
+                    e
+                );
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Work done over one connection to systemd, until it is lost or shutdown is requested.
+#[async_trait]
+pub trait Session: Send {
+    /// Runs on a new connection.
+    async fn run(&mut self, dbus: DBusContext<'static>) -> Result<SessionEnd>;
+}
+
+/// Keeps a session with systemd running until shutdown, connecting again with backoff whenever the
+/// connection is lost (e.g. the bus went away or systemd re-executed). Every new session re-lists
+/// units, re-subscribes and reconciles, so state changes missed while disconnected are applied.
+pub async fn supervise<C, Fut>(
+    connect: C,
+    fs: Arc<dyn FileSystem>,
+    traefik_dir: &Path,
+    settings: Arc<GenerationSettings>,
+    backoff: Backoff,
+    shutdown: watch::Receiver<bool>,
+) -> Result<()>
+where
+    C: Fn() -> Fut,
+    Fut: Future<Output = Result<DBusContext<'static>>>,
+{
+    let mut session = UnitsSession {
+        fs,
+        traefik_dir,
+        settings,
+        shutdown: shutdown.clone(),
+    };
+    reconnecting(connect, &mut session, backoff, shutdown).await
+}
+
+/// Runs `session` on a new connection every time the previous one is lost, until shutdown.
+pub async fn reconnecting<C, Fut>(
+    connect: C,
+    session: &mut impl Session,
+    mut backoff: Backoff,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()>
+where
+    C: Fn() -> Fut,
+    Fut: Future<Output = Result<DBusContext<'static>>>,
+{
+    loop {
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+        match connect().await {
+            Ok(dbus) => {
+                let started = Instant::now();
+                match session.run(dbus).await {
+                    Ok(SessionEnd::Shutdown) => return Ok(()),
+                    Ok(SessionEnd::Disconnected) => warn!("Lost connection to systemd"),
+                    Err(e) => error!("Error watching systemd: {:#}", e),
+                }
+                backoff.session_ended(started.elapsed());
+            }
+            Err(e) => error!("Error connecting to systemd: {:#}", e),
+        }
+        let delay = backoff.next_delay();
+        info!("Reconnecting to systemd in {delay:?}");
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            res = shutdown.changed() => {
+                if res.is_err() || *shutdown.borrow() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Watches the units with Traefik configuration and writes their files.
+struct UnitsSession<'a> {
+    fs: Arc<dyn FileSystem>,
+    traefik_dir: &'a Path,
+    settings: Arc<GenerationSettings>,
+    shutdown: watch::Receiver<bool>,
+}
+
+#[async_trait]
+impl Session for UnitsSession<'_> {
+    async fn run(&mut self, dbus: DBusContext<'static>) -> Result<SessionEnd> {
+        run_session(
+            dbus,
+            self.fs.clone(),
+            self.traefik_dir,
+            self.settings.clone(),
+            self.shutdown.clone(),
+        )
+        .await
+    }
+}
+
+async fn run_session(
+    dbus: DBusContext<'static>,
+    fs: Arc<dyn FileSystem>,
+    traefik_dir: &Path,
+    settings: Arc<GenerationSettings>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<SessionEnd> {
+    // listen before listing, so no start or stop in between is missed
+    let state_changes = dbus.receive_state_changes().await?;
+    let watched = dbus.list_units().await?;
+    if log_enabled!(log::Level::Info) {
+        let read = watched.read().await;
+        let watched_units = read
+            .values()
+            .map(|unit| format!("{} ({})", unit.name, unit.active_state))
+            .collect::<Vec<_>>();
+        if watched_units.is_empty() {
+            info!("No units initially being watched. They might all be stopped.");
+        } else {
+            info!("Initial watched units: {}", watched_units.join(", "));
+        }
+    }
+    let (watch_join_handles, rx_unit_event) = dbus.watch_units(watched.clone()).await?;
+
+    if let Err(e) = reconcile(&dbus, &watched, fs.as_ref(), traefik_dir, &settings).await {
+        error!("initial reconcile error: {:#}", e);
+    }
+
+    let (tx_new_job_event, process_msgs_join_handle) =
+        process_service_change_messages(watched.clone(), dbus.clone(), fs, traefik_dir, settings)
+            .await?;
+    let session_end = dbus
+        .get_messages(
+            tx_new_job_event,
+            watched,
+            state_changes,
+            rx_unit_event,
+            shutdown,
+        )
+        .await; // will block
+
+    trace!("Ending session");
+    for handle in watch_join_handles
+        .into_iter()
+        .chain([process_msgs_join_handle])
+    {
+        handle.abort();
+    }
+    session_end
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::dbus::tests::{expect_no_unit_files, listed_unit};
+    use crate::dbus::{MockSystemdManager, MockSystemdUnit};
+    use crate::infra::tests::MockFileSystem;
+    use futures::stream;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_backoff_doubles_up_to_max_and_resets() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(2));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(5));
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+        backoff.next_delay();
+        backoff.session_ended(Duration::from_secs(4));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(4));
+        backoff.session_ended(Duration::from_secs(5));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    /// A systemd manager with a single `app.service` unit in `active_state`. When `disconnecting`,
+    /// all its signal streams end right away, as they do when the bus connection drops.
+    pub fn session(
+        active_state: &'static str,
+        disconnecting: bool,
+        fs: Arc<dyn FileSystem>,
+    ) -> DBusContext<'static> {
+        fn signals<T: Send + 'static>(
+            disconnecting: bool,
+        ) -> std::pin::Pin<Box<dyn futures::Stream<Item = T> + Send>> {
+            if disconnecting {
+                Box::pin(stream::empty())
+            } else {
+                Box::pin(stream::pending())
+            }
+        }
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(|_, _| Ok(vec![listed_unit("app.service")]));
+        expect_no_unit_files(&mut mock_manager);
+        mock_manager.expect_subscribe().returning(|| Ok(()));
+        mock_manager
+            .expect_receive_unit_new()
+            .returning(move || Ok(signals(disconnecting)));
+        mock_manager
+            .expect_receive_unit_removed()
+            .returning(move || Ok(signals(disconnecting)));
+        mock_manager
+            .expect_receive_reloading()
+            .returning(move || Ok(signals(disconnecting)));
+        mock_manager
+            .expect_receive_unit_files_changed()
+            .returning(move || Ok(signals(disconnecting)));
+        mock_manager
+            .expect_receive_unit_properties_changed()
+            .returning(move || Ok(signals(disconnecting)));
+        mock_manager
+            .expect_receive_manager_restarted()
+            .returning(|| Ok(signals(false)));
+        mock_manager
+            .expect_load_unit()
+            .returning(|_| Ok("/org/freedesktop/systemd1/unit/app_2eservice".to_string()));
+        mock_manager.expect_get_unit().returning(move |_| {
+            let mut u = MockSystemdUnit::new();
+            u.expect_drop_in_paths().returning(|| Ok(vec![]));
+            u.expect_fragment_path()
+                .returning(|| Ok("/etc/systemd/system/app.service".to_string()));
+            u.expect_active_state()
+                .returning(move || Ok(active_state.to_string()));
+            Ok(Box::new(u))
+        });
+        DBusContext::new_test_context(Arc::new(mock_manager), fs)
+    }
+
+    #[tokio::test]
+    async fn test_supervise_reconnects_and_reconciles_after_streams_end() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/app.service",
+            "[X-Traefik]\nLabel=traefik.http.routers.app.rule=Host(`app`)",
+        );
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let connect = {
+            let fs = fs.clone();
+            let attempts = attempts.clone();
+            move || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                let fs = fs.clone();
+                async move {
+                    match attempt {
+                        1 => anyhow::bail!("bus unavailable"),
+                        // the unit starts while the connection is down, so only a reconcile sees it
+                        2 => Ok(session("inactive", true, fs)),
+                        _ => Ok(session("active", false, fs)),
+                    }
+                }
+            }
+        };
+        let supervisor = {
+            let fs = fs.clone();
+            tokio::spawn(async move {
+                supervise(
+                    connect,
+                    fs,
+                    Path::new("/out"),
+                    Default::default(),
+                    Backoff::new(Duration::from_millis(1), Duration::from_millis(5)),
+                    rx_shutdown,
+                )
+                .await
+            })
+        };
+
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while !fs.file_exists_in_memory("/out/app.service.yml") {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("Timeout waiting for the unit to be reconciled after reconnecting");
+        tx_shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), supervisor)
+            .await
+            .expect("Timeout waiting for the supervisor to stop")
+            .unwrap()
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_session_listens_before_listing_units() {
+        let calls = Arc::new(std::sync::Mutex::new(vec![]));
+        let mut mock_manager = MockSystemdManager::new();
+        let record = |call: &'static str| {
+            let calls = calls.clone();
+            move || calls.lock().unwrap().push(call)
+        };
+        let subscribed = record("subscribe");
+        mock_manager.expect_subscribe().returning(move || {
+            subscribed();
+            Ok(())
+        });
+        let listening = record("listen");
+        mock_manager
+            .expect_receive_unit_properties_changed()
+            .returning(move || {
+                listening();
+                Ok(Box::pin(stream::pending()))
+            });
+        let listed = record("list");
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(move |_, _| {
+                listed();
+                anyhow::bail!("stop here")
+            });
+        let dbus =
+            DBusContext::new_test_context(Arc::new(mock_manager), Arc::new(MockFileSystem::new()));
+        let (_tx_shutdown, rx_shutdown) = watch::channel(false);
+
+        let result = run_session(
+            dbus,
+            Arc::new(MockFileSystem::new()),
+            Path::new("/out"),
+            Default::default(),
+            rx_shutdown,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(*calls.lock().unwrap(), vec!["subscribe", "listen", "list"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_supervise_backs_off_when_the_connection_keeps_dropping() {
+        let fs = Arc::new(MockFileSystem::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let connect = {
+            let fs = fs.clone();
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                let fs = fs.clone();
+                // connects, then the bus drops right away
+                async move { Ok(session("active", true, fs)) }
+            }
+        };
+        let start = Instant::now();
+        let supervisor = tokio::spawn(async move {
+            supervise(
+                connect,
+                fs,
+                Path::new("/out"),
+                Default::default(),
+                Backoff::new(Duration::from_millis(10), Duration::from_secs(10)),
+                rx_shutdown,
+            )
+            .await
+        });
+
+        // delays of 10, 20, 40, 80 and 160ms: attempts at 0, 10, 30, 70, 150 and 310ms
+        for (at, expected) in [(5, 1), (25, 2), (65, 3), (145, 4), (305, 5)] {
+            tokio::time::sleep_until(start + Duration::from_millis(at)).await;
+            assert_eq!(attempts.load(Ordering::SeqCst), expected, "at {at}ms");
+        }
+        tx_shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), supervisor)
+            .await
+            .expect("Timeout waiting for the supervisor to stop")
+            .unwrap()
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 5);
+    }
+
+    #[tokio::test]
+    async fn test_supervise_stops_while_waiting_to_reconnect() {
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let supervisor = tokio::spawn(async move {
+            supervise(
+                || async { anyhow::bail!("bus unavailable") },
+                Arc::new(MockFileSystem::new()),
+                Path::new("/out"),
+                Default::default(),
+                Backoff::new(Duration::from_secs(60), Duration::from_secs(60)),
+                rx_shutdown,
+            )
+            .await
+        });
+        tx_shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), supervisor)
+            .await
+            .expect("Timeout waiting for the supervisor to stop")
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -0,0 +1,345 @@
+use anyhow::{Result, anyhow, bail};
+use std::{iter::Peekable, str::FromStr};
+
+use crate::helpers::normalize;
+
+/// A template like Traefik's `defaultRule`, used for the default rule and the labels of presets.
+/// It is a subset of Go templates: text with actions such as `{{ normalize .Name }}` or
+/// `{{ index .Labels "app.domain" | lower }}`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RuleTemplate {
+    parts: Vec<Part>,
+}
+
+/// What a template is rendered with.
+pub struct TemplateData<'a> {
+    /// The unit name without its type, e.g. `app@blue`.
+    pub name: &'a str,
+    /// The instance of a template unit, e.g. `blue`, or empty.
+    pub instance: &'a str,
+    /// `key=value` lines, as read from the `Label=` directives. Later ones win.
+    pub labels: &'a [String],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    /// Commands separated by `|`, each one's result being the last argument of the next one.
+    Action(Vec<Command>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Command {
+    function: Option<Function>,
+    args: Vec<Operand>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Function {
+    Normalize,
+    Index,
+    Lower,
+    Upper,
+}
+
+impl Function {
+    fn name(self) -> &'static str {
+        match self {
+            Function::Normalize => "normalize",
+            Function::Index => "index",
+            Function::Lower => "lower",
+            Function::Upper => "upper",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Name,
+    Instance,
+    Labels,
+    Str(String),
+}
+
+enum Value<'a> {
+    Str(String),
+    Labels(&'a [String]),
+}
+
+impl FromStr for RuleTemplate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = vec![];
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            let mut text = &rest[..start];
+            let mut action = &rest[start + 2..];
+            if let Some(trimmed) = action.strip_prefix('-') {
+                text = text.trim_end();
+                action = trimmed;
+            }
+            let end = action
+                .find("}}")
+                .ok_or_else(|| anyhow!("unterminated action in template '{s}'"))?;
+            rest = &action[end + 2..];
+            let mut action = &action[..end];
+            if let Some(trimmed) = action.strip_suffix('-') {
+                action = trimmed;
+                rest = rest.trim_start();
+            }
+            if !text.is_empty() {
+                parts.push(Part::Text(text.to_string()));
+            }
+            let commands = parse_action(action)
+                .map_err(|e| anyhow!("{e} in template action '{{{{{action}}}}}'"))?;
+            parts.push(Part::Action(commands));
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        let template = Self { parts };
+        // functions get arguments of the wrong type or count only when rendering
+        template.render(&TemplateData {
+            name: "app",
+            instance: "",
+            labels: &[],
+        })?;
+        Ok(template)
+    }
+}
+
+impl RuleTemplate {
+    pub fn render(&self, data: &TemplateData) -> Result<String> {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => rendered.push_str(text),
+                Part::Action(commands) => {
+                    let mut piped = None;
+                    for command in commands {
+                        piped = Some(command.run(data, piped)?);
+                    }
+                    match piped {
+                        Some(Value::Str(value)) => rendered.push_str(&value),
+                        _ => bail!(".Labels cannot be written into a rule, use index"),
+                    }
+                }
+            }
+        }
+        Ok(rendered)
+    }
+}
+
+impl Command {
+    fn run<'a>(&self, data: &TemplateData<'a>, piped: Option<Value<'a>>) -> Result<Value<'a>> {
+        let mut args = self
+            .args
+            .iter()
+            .map(|arg| match arg {
+                Operand::Name => Value::Str(data.name.to_string()),
+                Operand::Instance => Value::Str(data.instance.to_string()),
+                Operand::Labels => Value::Labels(data.labels),
+                Operand::Str(value) => Value::Str(value.clone()),
+            })
+            .collect::<Vec<_>>();
+        args.extend(piped);
+        let Some(function) = self.function else {
+            return match <[Value; 1]>::try_from(args) {
+                Ok([value]) => Ok(value),
+                Err(_) => bail!("only functions take arguments"),
+            };
+        };
+        Ok(Value::Str(match (function, args.as_slice()) {
+            (Function::Normalize, [Value::Str(value)]) => normalize(value),
+            (Function::Lower, [Value::Str(value)]) => value.to_lowercase(),
+            (Function::Upper, [Value::Str(value)]) => value.to_uppercase(),
+            (Function::Index, [Value::Labels(labels), Value::Str(key)]) => label(labels, key),
+            (Function::Index, _) => bail!("index expects .Labels and a label name"),
+            (function, [_]) => bail!("{} expects a string, got .Labels", function.name()),
+            (function, _) => bail!("{} expects one argument", function.name()),
+        }))
+    }
+}
+
+/// The value of a label, or empty when it is not set, as `index` of a missing map key in Go. Keys
+/// match case-insensitively, as in constraints and `traefik.enable`.
+fn label(labels: &[String], key: &str) -> String {
+    labels
+        .iter()
+        .rev()
+        .find_map(|line| {
+            line.split_once('=')
+                .filter(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+                .map(|(_, v)| v.trim().to_string())
+        })
+        .unwrap_or_default()
+}
+
+fn parse_action(action: &str) -> Result<Vec<Command>> {
+    let mut chars = action.chars().peekable();
+    let mut commands = vec![];
+    let mut command = Command::default();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        let Some(c) = chars.next() else {
+            break;
+        };
+        match c {
+            '|' => {
+                let first = commands.is_empty();
+                commands.push(finish(std::mem::take(&mut command), first)?);
+            }
+            '.' => command.args.push(match identifier(&mut chars).as_str() {
+                "Name" => Operand::Name,
+                "Instance" => Operand::Instance,
+                "Labels" => Operand::Labels,
+                other => bail!("unknown field .{other}, expected .Name, .Instance or .Labels"),
+            }),
+            '"' => command.args.push(Operand::Str(quoted(&mut chars)?)),
+            '`' => command.args.push(Operand::Str(raw_quoted(&mut chars)?)),
+            c if c.is_ascii_alphabetic() => {
+                let name = format!("{c}{}", identifier(&mut chars));
+                if command.function.is_some() || !command.args.is_empty() {
+                    bail!("unexpected {name}, functions take only strings and fields");
+                }
+                command.function = Some(match name.as_str() {
+                    "normalize" => Function::Normalize,
+                    "index" => Function::Index,
+                    "lower" => Function::Lower,
+                    "upper" => Function::Upper,
+                    _ => bail!("unknown function {name}"),
+                });
+            }
+            c => bail!("unexpected '{c}'"),
+        }
+    }
+    commands.push(finish(command, commands.is_empty())?);
+    Ok(commands)
+}
+
+/// Checks a command of a pipeline: only the first one can be a plain value.
+fn finish(command: Command, first: bool) -> Result<Command> {
+    match (&command.function, command.args.len()) {
+        (None, 0) => bail!("empty command"),
+        (None, _) if !first => bail!("only functions can follow a |"),
+        _ => Ok(command),
+    }
+}
+
+fn identifier(chars: &mut Peekable<impl Iterator<Item = char>>) -> String {
+    let mut name = String::new();
+    while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    name
+}
+
+/// Reads a double quoted string, with backslash escapes, after its opening quote.
+fn quoted(chars: &mut impl Iterator<Item = char>) -> Result<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(value),
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => bail!("unterminated string"),
+            },
+            Some(c) => value.push(c),
+            None => bail!("unterminated string"),
+        }
+    }
+}
+
+/// Reads a raw string, without escapes as in Go, after its opening backtick.
+fn raw_quoted(chars: &mut impl Iterator<Item = char>) -> Result<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('`') => return Ok(value),
+            Some(c) => value.push(c),
+            None => bail!("unterminated string"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn render(template: &str, name: &str, instance: &str, labels: &[&str]) -> String {
+        let labels = labels.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        template
+            .parse::<RuleTemplate>()
+            .unwrap()
+            .render(&TemplateData {
+                name,
+                instance,
+                labels: &labels,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_docker_default_rule() {
+        assert_eq!(
+            render("Host(`{{ normalize .Name }}`)", "app@blue", "blue", &[]),
+            "Host(`app-blue`)"
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let labels = ["app.domain=Example.com", "app.domain=Example.org"];
+        assert_eq!(
+            render(
+                "Host(`{{ .Instance }}.{{ index .Labels \"app.domain\" | lower }}`)",
+                "app@blue",
+                "blue",
+                &labels
+            ),
+            "Host(`blue.example.org`)"
+        );
+        assert_eq!(
+            render(
+                "Host(`{{ .Name | normalize | upper }}.apps`)",
+                "my_app",
+                "",
+                &[]
+            ),
+            "Host(`MY-APP.apps`)"
+        );
+        assert_eq!(
+            render("{{ index .Labels \"App.Domain\" }}", "app", "", &labels),
+            "Example.org"
+        );
+        assert_eq!(
+            render("Host(`{{ index .Labels `missing` }}x`)", "app", "", &labels),
+            "Host(`x`)"
+        );
+        assert_eq!(
+            render("Host(`a`)  {{- \"\" -}}  || Host(`b`)", "app", "", &[]),
+            "Host(`a`)|| Host(`b`)"
+        );
+        assert_eq!(render("", "app", "", &[]), "");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let error = |template: &str| format!("{:#}", template.parse::<RuleTemplate>().unwrap_err());
+        assert!(error("Host(`{{ .Name }`)").contains("unterminated action"));
+        assert!(error("{{ .Unit }}").contains("unknown field .Unit"));
+        assert!(error("{{ title .Name }}").contains("unknown function title"));
+        assert!(error("{{ .Labels }}").contains("use index"));
+        assert!(error("{{ normalize .Labels }}").contains("expects a string"));
+        assert!(error("{{ normalize .Name .Instance }}").contains("expects one argument"));
+        assert!(error("{{ index .Name \"a\" }}").contains("index expects .Labels"));
+        assert!(error("{{ .Name | .Instance }}").contains("only functions can follow"));
+        assert!(error("{{ }}").contains("empty command"));
+        assert!(error("{{ \"a }}").contains("unterminated string"));
+        assert!(error("{{ index .Labels `a }}").contains("unterminated string"));
+    }
+}
@@ -0,0 +1,473 @@
+use anyhow::{Context, Result, anyhow, bail};
+use std::path::Path;
+
+use crate::infra::FileSystem;
+
+/// Characters that separate words in a value, as in systemd.
+const WHITESPACE: [char; 4] = [' ', '\t', '\n', '\r'];
+
+/// An assignment in a section of a unit file.
+#[derive(Debug, PartialEq)]
+pub struct Directive {
+    pub key: String,
+    /// The raw value, with continuation lines joined.
+    pub value: String,
+    /// Line of the unit file the directive starts at, from 1.
+    pub line: usize,
+}
+
+/// Reads the directives of a section the way systemd reads unit files: lines starting with `#` or
+/// `;` are comments, also between continuation lines, and a line ending with a backslash continues
+/// on the next one, the backslash becoming a space. Sections with the same name are merged. `None`
+/// when the file has no such section.
+pub fn section_directives(text: &str, section: &str) -> Option<Vec<Directive>> {
+    let mut directives = None::<Vec<Directive>>;
+    let mut in_section = false;
+    let mut continuation: Option<(usize, String)> = None;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim_matches(WHITESPACE);
+        if line.starts_with(['#', ';']) || (continuation.is_none() && line.is_empty()) {
+            continue;
+        }
+        let (start, mut line) = match continuation.take() {
+            Some((start, mut joined)) => {
+                joined.push_str(line);
+                (start, joined)
+            }
+            None => (number + 1, line.to_string()),
+        };
+        if line.chars().rev().take_while(|&c| c == '\\').count() % 2 == 1 {
+            line.pop();
+            line.push(' ');
+            continuation = Some((start, line));
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_section = name == section;
+            if in_section {
+                directives.get_or_insert_default();
+            }
+        } else if in_section && let Some((key, value)) = line.split_once('=') {
+            directives.get_or_insert_default().push(Directive {
+                key: key.trim_matches(WHITESPACE).to_string(),
+                value: value.trim_matches(WHITESPACE).to_string(),
+                line: start,
+            });
+        }
+    }
+    directives
+}
+
+/// Unescapes a value as systemd does with the words of settings like `Environment=`: quotes are
+/// removed, C escapes like `\n`, `\x41` or `\u00e9` are resolved and specifiers like `%i` are
+/// expanded. Unlike there, the words make a single value, joined with a space.
+pub fn unescape_value(value: &str, specifiers: &Specifiers) -> Result<String> {
+    let mut words = vec![];
+    let mut chars = value.chars().peekable();
+    loop {
+        while chars.next_if(|c| WHITESPACE.contains(c)).is_some() {}
+        if chars.peek().is_none() {
+            break;
+        }
+        let mut word = vec![];
+        let mut quote = None;
+        while let Some(c) = chars.next() {
+            match (quote, c) {
+                (_, '\\') => unescape_one(&mut chars, &mut word)?,
+                (None, c) if WHITESPACE.contains(&c) => break,
+                (None, '"' | '\'') => quote = Some(c),
+                (Some(q), c) if c == q => quote = None,
+                (_, c) => word.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        if let Some(q) = quote {
+            bail!("missing closing {q} in '{value}'");
+        }
+        let word = String::from_utf8(word)
+            .map_err(|_| anyhow!("escapes in '{value}' are not valid UTF-8"))?;
+        words.push(specifiers.expand(&word)?);
+    }
+    Ok(words.join(" "))
+}
+
+/// Resolves the C escape after a backslash, as systemd's `cunescape_one`.
+fn unescape_one(chars: &mut impl Iterator<Item = char>, out: &mut Vec<u8>) -> Result<()> {
+    let byte = match chars.next() {
+        None => bail!("value ends with a backslash"),
+        Some('a') => 0x07,
+        Some('b') => 0x08,
+        Some('f') => 0x0c,
+        Some('n') => b'\n',
+        Some('r') => b'\r',
+        Some('t') => b'\t',
+        Some('v') => 0x0b,
+        Some('s') => b' ',
+        Some(c @ ('\\' | '"' | '\'')) => c as u8,
+        Some('x') => match digits(chars, 2, 16)? {
+            0 => bail!("invalid escape \\x00"),
+            byte => byte as u8,
+        },
+        Some(c @ ('u' | 'U')) => {
+            let code = digits(chars, if c == 'u' { 4 } else { 8 }, 16)?;
+            match char::from_u32(code).filter(|&c| c != '\0') {
+                Some(c) => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                None => bail!("invalid escape \\{c}{code:x}"),
+            }
+            return Ok(());
+        }
+        Some(c @ '0'..='7') => {
+            let code = (c as u32 - '0' as u32) * 64 + digits(chars, 2, 8)?;
+            match code {
+                1..=255 => code as u8,
+                _ => bail!("invalid escape \\{code:o}"),
+            }
+        }
+        Some(c) => bail!("invalid escape \\{c}"),
+    };
+    out.push(byte);
+    Ok(())
+}
+
+fn digits(chars: &mut impl Iterator<Item = char>, count: usize, radix: u32) -> Result<u32> {
+    (0..count).try_fold(0, |acc, _| {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(radix))
+            .ok_or_else(|| anyhow!("invalid escape, expected {count} digits"))?;
+        Ok(acc * radix + digit)
+    })
+}
+
+/// Resolves the specifiers of a unit, e.g. `%i` for its instance, reading host information only
+/// when it is used.
+pub struct Specifiers<'a> {
+    unit: &'a str,
+    fs: &'a dyn FileSystem,
+}
+
+impl<'a> Specifiers<'a> {
+    pub fn new(unit: &'a str, fs: &'a dyn FileSystem) -> Self {
+        Self { unit, fs }
+    }
+
+    /// Replaces the specifiers in a word, as systemd does. Unknown ones are an error.
+    pub fn expand(&self, word: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(word.len());
+        let mut chars = word.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some(specifier) => expanded.push_str(&self.resolve(specifier)?),
+                None => bail!("'{word}' ends with a lone %"),
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn resolve(&self, specifier: char) -> Result<String> {
+        let name = unit_name_without_type(self.unit);
+        let (prefix, instance) = match name.split_once('@') {
+            Some((prefix, instance)) => (prefix, Some(instance)),
+            None => (name, None),
+        };
+        // the part of the prefix after its last dash
+        let last_component = prefix.rsplit('-').next().unwrap_or(prefix);
+        Ok(match specifier {
+            'n' => self.unit.to_string(),
+            'N' => name.to_string(),
+            'p' => prefix.to_string(),
+            'P' => unescape_unit_name(prefix)?,
+            'i' => instance.unwrap_or_default().to_string(),
+            'I' => unescape_unit_name(instance.unwrap_or_default())?,
+            'j' => last_component.to_string(),
+            'J' => unescape_unit_name(last_component)?,
+            'f' => unescape_unit_path(instance.unwrap_or(prefix))?,
+            'H' => self.read_host_file("/proc/sys/kernel/hostname")?,
+            'l' => {
+                let hostname = self.read_host_file("/proc/sys/kernel/hostname")?;
+                hostname.split('.').next().unwrap_or_default().to_string()
+            }
+            'm' => self.read_host_file("/etc/machine-id")?,
+            'b' => self
+                .read_host_file("/proc/sys/kernel/random/boot_id")?
+                .replace('-', ""),
+            '%' => "%".to_string(),
+            c => bail!("unknown specifier %{c}"),
+        })
+    }
+
+    fn read_host_file(&self, path: &str) -> Result<String> {
+        Ok(self
+            .fs
+            .read_to_string(Path::new(path))
+            .with_context(|| format!("reading {path}"))?
+            .trim()
+            .to_string())
+    }
+}
+
+/// The unit name without its type, e.g. `app@blue` for `app@blue.service`.
+pub fn unit_name_without_type(unit: &str) -> &str {
+    unit.rsplit_once('.').map_or(unit, |(name, _)| name)
+}
+
+/// Reverses systemd's unit name escaping (`systemd-escape --unescape`): `-` is a `/` and `\xHH`
+/// is the byte HH.
+pub fn unescape_unit_name(escaped: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '-' => bytes.push(b'/'),
+            '\\' if chars.next() == Some('x') => bytes.push(digits(&mut chars, 2, 16)? as u8),
+            '\\' => bail!("invalid escape in unit name '{escaped}'"),
+            c => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow!("unit name '{escaped}' is not valid UTF-8"))
+}
+
+/// Reverses systemd's path escaping (`systemd-escape --path --unescape`), e.g. `home-user` is
+/// `/home/user`.
+fn unescape_unit_path(escaped: &str) -> Result<String> {
+    match escaped {
+        "" => bail!("empty path in unit name"),
+        "-" => Ok("/".to_string()),
+        _ => Ok(format!("/{}", unescape_unit_name(escaped)?)),
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::infra::tests::MockFileSystem;
+    use pretty_assertions::assert_eq;
+
+    /// Unescapes as for `app@blue.service`, with no host information.
+    pub fn unescape(value: &str) -> Result<String> {
+        unescape_value(
+            value,
+            &Specifiers::new("app@blue.service", &MockFileSystem::new()),
+        )
+    }
+
+    /// Values and what systemd makes of them, `None` when it rejects them.
+    pub const FIXTURES: &[(&str, Option<&str>)] = &[
+        ("hello", Some("hello")),
+        ("\"hello world\"", Some("hello world")),
+        ("'hello world'", Some("hello world")),
+        ("a\"b c\"d", Some("ab cd")),
+        ("\"a \\\"b\\\"\"", Some("a \"b\"")),
+        ("'it''s'", Some("its")),
+        ("\"it's\"", Some("it's")),
+        ("\"\"", Some("")),
+        ("\\x41\\102", Some("AB")),
+        ("\\xc3\\xa9", Some("é")),
+        ("\\u00e9", Some("é")),
+        ("\\U0001F600", Some("😀")),
+        ("a\\nb\\tc", Some("a\nb\tc")),
+        ("a\\sb", Some("a b")),
+        ("C:\\\\dir", Some("C:\\dir")),
+        ("100%%", Some("100%")),
+        ("Host(`a.com`)", Some("Host(`a.com`)")),
+        (
+            "PathRegexp(`^/api/(v1|v2)`)",
+            Some("PathRegexp(`^/api/(v1|v2)`)"),
+        ),
+        (
+            "admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/",
+            Some("admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/"),
+        ),
+        ("\"unterminated", None),
+        ("'unterminated", None),
+        ("trailing\\", None),
+        ("\\q", None),
+        ("\\x4", None),
+        ("\\x00", None),
+        ("\\000", None),
+        ("\\400", None),
+        ("\\u0000", None),
+        ("\\ud800", None),
+        ("\\xe9", None),
+    ];
+
+    #[test]
+    fn test_unescape_value_fixtures() {
+        for (value, expected) in FIXTURES {
+            let result = unescape(value);
+            match expected {
+                Some(expected) => assert_eq!(
+                    result.as_deref().ok(),
+                    Some(*expected),
+                    "unescaping {value:?}: {result:?}"
+                ),
+                None => assert!(result.is_err(), "{value:?} should not unescape: {result:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_unescape_value_joins_words() {
+        assert_eq!(
+            unescape("  rule=Host(`a`)   &&\tPath(`/b`)  ").unwrap(),
+            "rule=Host(`a`) && Path(`/b`)"
+        );
+        assert_eq!(unescape("a \"\" b").unwrap(), "a  b");
+        assert_eq!(unescape("").unwrap(), "");
+    }
+
+    #[test]
+    fn test_specifiers() {
+        let fs = MockFileSystem::new();
+        fs.add_file("/proc/sys/kernel/hostname", "web1.example.com\n");
+        fs.add_file("/etc/machine-id", "0123456789abcdef0123456789abcdef\n");
+        fs.add_file(
+            "/proc/sys/kernel/random/boot_id",
+            "01234567-89ab-cdef-0123-456789abcdef\n",
+        );
+        let expand = |unit: &str, word: &str| Specifiers::new(unit, &fs).expand(word);
+
+        let unit = r"my\x2dapp-web@srv-www\x2d1.service";
+        assert_eq!(expand(unit, "%n").unwrap(), unit);
+        assert_eq!(expand(unit, "%N").unwrap(), r"my\x2dapp-web@srv-www\x2d1");
+        assert_eq!(expand(unit, "%p").unwrap(), r"my\x2dapp-web");
+        assert_eq!(expand(unit, "%P").unwrap(), "my-app/web");
+        assert_eq!(expand(unit, "%i").unwrap(), r"srv-www\x2d1");
+        assert_eq!(expand(unit, "%I").unwrap(), "srv/www-1");
+        assert_eq!(expand(unit, "%j").unwrap(), "web");
+        assert_eq!(expand(unit, "%J").unwrap(), "web");
+        assert_eq!(expand(unit, "%f").unwrap(), "/srv/www-1");
+        assert_eq!(expand("app.service", "%p-%i-%f").unwrap(), "app--/app");
+        assert_eq!(expand("-.mount", "%f").unwrap(), "/");
+        assert_eq!(expand(unit, "%H %l").unwrap(), "web1.example.com web1");
+        assert_eq!(
+            expand(unit, "%m %b").unwrap(),
+            "0123456789abcdef0123456789abcdef 0123456789abcdef0123456789abcdef"
+        );
+        assert_eq!(expand(unit, "100%%").unwrap(), "100%");
+        assert!(expand(unit, "%z").is_err());
+        assert!(expand(unit, "100%").is_err());
+        assert!(
+            Specifiers::new(unit, &MockFileSystem::new())
+                .expand("%H")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_specifiers_in_template_labels() {
+        let fs = MockFileSystem::new();
+        let specifiers = Specifiers::new("app@blue.service", &fs);
+        assert_eq!(
+            unescape_value(
+                "traefik.http.routers.app-%i.rule=Host(`%i.example.com`)",
+                &specifiers
+            )
+            .unwrap(),
+            "traefik.http.routers.app-blue.rule=Host(`blue.example.com`)"
+        );
+    }
+
+    #[test]
+    fn test_section_directives() {
+        let text = "[Unit]\n\
+                    Label=not-traefik\n\
+                    [X-Traefik]\n\
+                    ; a comment\n\
+                    Label = a=1\n\
+                    Label=b=Host(`a`) \\\n\
+                    # a comment between continuation lines\n\
+                    \x20   && Path(`/b`)\n\
+                    Other=x\n\
+                    no assignment\n\
+                    [Install]\n\
+                    Label=not-traefik\n\
+                    [X-Traefik]\n\
+                    Label=c=\\\\\n\
+                    Label=\n";
+        let directive = |key: &str, value: &str, line| Directive {
+            key: key.to_string(),
+            value: value.to_string(),
+            line,
+        };
+
+        assert_eq!(
+            section_directives(text, "X-Traefik").unwrap(),
+            vec![
+                directive("Label", "a=1", 5),
+                directive("Label", "b=Host(`a`)  && Path(`/b`)", 6),
+                directive("Other", "x", 9),
+                directive("Label", "c=\\\\", 14),
+                directive("Label", "", 15),
+            ]
+        );
+        assert_eq!(section_directives(text, "Service"), None);
+        assert_eq!(
+            section_directives("[X-Traefik]\n", "X-Traefik"),
+            Some(vec![])
+        );
+    }
+}
+
+#[cfg(all(test, feature = "proptests"))]
+mod proptests {
+    use super::tests::{FIXTURES, unescape};
+    use proptest::prelude::*;
+
+    fn valid_fixture() -> impl Strategy<Value = (&'static str, &'static str)> {
+        prop::sample::select(
+            FIXTURES
+                .iter()
+                .filter_map(|(value, expected)| expected.map(|e| (*value, e)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Quotes and escapes a string so systemd reads it back as it is.
+    fn quote(s: &str) -> String {
+        let mut quoted = String::from("\"");
+        for c in s.chars() {
+            match c {
+                '\\' | '"' => {
+                    quoted.push('\\');
+                    quoted.push(c);
+                }
+                '%' => quoted.push_str("%%"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    proptest! {
+        #[test]
+        fn prop_words_unescape_like_fixtures(
+            words in prop::collection::vec((valid_fixture(), "[ \t]{1,3}"), 1..6)
+        ) {
+            let value = words
+                .iter()
+                .map(|((value, _), separator)| format!("{value}{separator}"))
+                .collect::<String>();
+            let expected = words
+                .iter()
+                .map(|((_, expected), _)| *expected)
+                .collect::<Vec<_>>()
+                .join(" ");
+            prop_assert_eq!(unescape(&value).unwrap(), expected);
+        }
+
+        #[test]
+        fn prop_quoted_values_round_trip(s in "[^\\x00]*") {
+            prop_assert_eq!(unescape(&quote(&s)).unwrap(), s);
+        }
+
+        #[test]
+        fn prop_unescape_value_never_panics(s in ".*") {
+            let _ = unescape(&s);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::{StreamExt, future::BoxFuture};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    args::Bus,
+    dbus::{DBusContext, SessionEnd},
+    generation_engine::{GenerationSettings, remove_generated_files},
+    infra::FileSystem,
+    supervisor::{Backoff, Session, reconnecting, supervise},
+};
+
+/// Connects to the bus of the user manager of a UID.
+pub type UserConnector =
+    Arc<dyn Fn(u32) -> BoxFuture<'static, Result<DBusContext<'static>>> + Send + Sync>;
+
+/// The private socket of a user manager. It is used rather than the user's session bus, as it
+/// exists whenever `user@UID.service` runs, even without a session D-Bus daemon.
+pub fn user_bus(uid: u32) -> Bus {
+    Bus::Private(format!("unix:path=/run/user/{uid}/systemd/private"))
+}
+
+/// The subdirectory of `traefik_dir` the files of a user's units are written to.
+pub fn user_out_dir(traefik_dir: &Path, uid: u32) -> PathBuf {
+    traefik_dir.join(format!("user-{uid}"))
+}
+
+/// The UID of a directory made by `user_out_dir`.
+fn uid_from_out_dir(dir: &Path) -> Option<u32> {
+    dir.file_name()?
+        .to_str()?
+        .strip_prefix("user-")?
+        .parse()
+        .ok()
+}
+
+struct RunningUser {
+    shutdown: watch::Sender<bool>,
+    handle: JoinHandle<Result<()>>,
+}
+
+/// Runs a supervisor for each running user manager (`user@UID.service`), starting and stopping them
+/// along with the user managers.
+pub struct UserManagers {
+    connect_user: UserConnector,
+    fs: Arc<dyn FileSystem>,
+    traefik_dir: PathBuf,
+    settings: Arc<GenerationSettings>,
+    user_backoff: Backoff,
+    running: HashMap<u32, RunningUser>,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl UserManagers {
+    pub fn new(
+        connect_user: UserConnector,
+        fs: Arc<dyn FileSystem>,
+        traefik_dir: PathBuf,
+        settings: Arc<GenerationSettings>,
+        user_backoff: Backoff,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            connect_user,
+            fs,
+            traefik_dir,
+            settings,
+            user_backoff,
+            running: HashMap::new(),
+            shutdown,
+        }
+    }
+
+    /// Tracks the user managers of the system manager reached with `connect` until shutdown.
+    pub async fn supervise<C, Fut>(mut self, connect: C, backoff: Backoff) -> Result<()>
+    where
+        C: Fn() -> Fut,
+        Fut: Future<Output = Result<DBusContext<'static>>>,
+    {
+        let shutdown = self.shutdown.clone();
+        let result = reconnecting(connect, &mut self, backoff, shutdown).await;
+        for (uid, user) in self.running.drain() {
+            trace!("Stopping supervisor of user {uid}");
+            let _ = user.shutdown.send(true);
+            if let Err(e) = user.handle.await {
+                error!("Supervisor of user {uid} failed: {:#}", e);
+            }
+        }
+        result
+    }
+
+    fn start(&mut self, uid: u32) {
+        if self.running.contains_key(&uid) {
+            return;
+        }
+        let dir = user_out_dir(&self.traefik_dir, uid);
+        if let Err(e) = self.fs.create_dir_all(&dir) {
+            error!("Error creating {} for user {uid}: {:#}", dir.display(), e);
+            return;
+        }
+        info!(
+            "Watching user manager of {uid}, writing to {}",
+            dir.display()
+        );
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let connect_user = self.connect_user.clone();
+        let fs = self.fs.clone();
+        let settings = Arc::new(self.settings.for_user(uid));
+        let backoff = self.user_backoff.clone();
+        let handle = tokio::spawn(async move {
+            supervise(
+                move || connect_user(uid),
+                fs,
+                &dir,
+                settings,
+                backoff,
+                rx_shutdown,
+            )
+            .await
+        });
+        self.running.insert(
+            uid,
+            RunningUser {
+                shutdown: tx_shutdown,
+                handle,
+            },
+        );
+    }
+
+    /// Stops watching a user manager and removes the files of its units, which stopped with it.
+    async fn stop(&mut self, uid: u32) {
+        if let Some(user) = self.running.remove(&uid) {
+            info!("User manager of {uid} stopped");
+            let _ = user.shutdown.send(true);
+            if let Err(e) = user.handle.await {
+                error!("Supervisor of user {uid} failed: {:#}", e);
+            }
+        }
+        let dir = user_out_dir(&self.traefik_dir, uid);
+        self.settings.defined_names.forget_dir(&dir);
+        if !self.fs.dir_exists(&dir) {
+            return;
+        }
+        if let Err(e) = remove_generated_files(self.fs.as_ref(), &dir, &self.settings.defined_names)
+        {
+            error!("Error removing files of user {uid}: {:#}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl Session for UserManagers {
+    async fn run(&mut self, dbus: DBusContext<'static>) -> Result<SessionEnd> {
+        let mut shutdown = self.shutdown.clone();
+        // listen before listing, so no change in between is missed
+        let mut changes = dbus.receive_user_manager_changes().await?;
+        let running = dbus.running_user_managers().await?;
+        info!("Running user managers: {running:?}");
+        // directories of users that stopped while disconnected or before this provider started
+        let left_over = match self.fs.read_subdirs(&self.traefik_dir) {
+            Ok(dirs) => dirs
+                .iter()
+                .filter_map(|dir| uid_from_out_dir(dir))
+                .collect(),
+            Err(e) => {
+                warn!("Error listing user directories: {:#}", e);
+                vec![]
+            }
+        };
+        let mut stopped = self
+            .running
+            .keys()
+            .copied()
+            .chain(left_over)
+            .filter(|uid| !running.contains(uid))
+            .collect::<Vec<_>>();
+        stopped.sort();
+        stopped.dedup();
+        for uid in stopped {
+            self.stop(uid).await;
+        }
+        for uid in running {
+            self.start(uid);
+        }
+        loop {
+            tokio::select! {
+                change = changes.next() => match change {
+                    Some((uid, true)) => self.start(uid),
+                    Some((uid, false)) => self.stop(uid).await,
+                    None => return Ok(SessionEnd::Disconnected),
+                },
+                res = shutdown.changed() => {
+                    if res.is_err() || *shutdown.borrow() {
+                        return Ok(SessionEnd::Shutdown);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbus::MockSystemdManager;
+    use crate::dbus::tests::{listed_unit, unit_properties_changed};
+    use crate::infra::tests::MockFileSystem;
+    use crate::supervisor::tests::session;
+    use std::time::Duration;
+
+    async fn wait_until(what: &str, condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_millis(500), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("Timeout waiting for {what}"));
+    }
+
+    #[tokio::test]
+    async fn test_user_managers_follow_user_manager_units() {
+        let fs = Arc::new(MockFileSystem::new());
+        fs.add_file(
+            "/etc/systemd/system/app.service",
+            "[X-Traefik]\nLabel=traefik.http.routers.app.rule=Host(`app`)",
+        );
+        let (tx_changes, rx_changes) = futures::channel::mpsc::unbounded();
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager.expect_subscribe().returning(|| Ok(()));
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(|_, _| Ok(vec![listed_unit("user@1000.service")]));
+        mock_manager
+            .expect_receive_unit_properties_changed()
+            .return_once(move || Ok(Box::pin(rx_changes)));
+        let system = DBusContext::new_test_context(Arc::new(mock_manager), fs.clone());
+        let system = std::sync::Mutex::new(Some(system));
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let connect_user: UserConnector = {
+            let fs = fs.clone();
+            Arc::new(move |_uid| {
+                let fs = fs.clone();
+                Box::pin(async move { Ok(session("active", false, fs)) })
+            })
+        };
+        let users = UserManagers::new(
+            connect_user,
+            fs.clone(),
+            PathBuf::from("/out"),
+            Default::default(),
+            Backoff::new(Duration::from_millis(1), Duration::from_millis(5)),
+            rx_shutdown,
+        );
+        let supervisor = tokio::spawn(async move {
+            users
+                .supervise(
+                    || {
+                        let system = system.lock().unwrap().take();
+                        async move { system.ok_or_else(|| anyhow::anyhow!("connected twice")) }
+                    },
+                    Backoff::new(Duration::from_millis(1), Duration::from_millis(5)),
+                )
+                .await
+        });
+
+        wait_until("user 1000 units", || {
+            fs.file_exists_in_memory("/out/user-1000/app.service.yml")
+        })
+        .await;
+        let yaml = fs
+            .get_file_content("/out/user-1000/app.service.yml")
+            .unwrap();
+        assert!(yaml.contains("app-u1000:"), "{yaml}");
+        tx_changes
+            .unbounded_send(Ok(unit_properties_changed("user@1001.service", "active")))
+            .unwrap();
+        tx_changes
+            .unbounded_send(Ok(unit_properties_changed("user@1000.service", "inactive")))
+            .unwrap();
+        wait_until("user 1001 units", || {
+            fs.file_exists_in_memory("/out/user-1001/app.service.yml")
+        })
+        .await;
+        wait_until("user 1000 units to be removed", || {
+            !fs.file_exists_in_memory("/out/user-1000/app.service.yml")
+        })
+        .await;
+
+        tx_shutdown.send(true).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), supervisor)
+            .await
+            .expect("Timeout waiting for the supervisor to stop")
+            .unwrap()
+            .unwrap();
+        assert!(fs.file_exists_in_memory("/out/user-1001/app.service.yml"));
+    }
+
+    #[tokio::test]
+    async fn test_user_managers_remove_files_of_users_stopped_before_start() {
+        let fs = Arc::new(MockFileSystem::new());
+        let provenance = |unit: &str| {
+            format!(
+                "# generated-by: {}\n# source-unit: {unit}\n# hash: 0\nhttp: {{}}\n",
+                env!("CARGO_PKG_NAME")
+            )
+        };
+        fs.add_file("/out/user-1000/app.service.yml", provenance("app.service"));
+        fs.add_file("/out/user-1001/app.service.yml", provenance("app.service"));
+        fs.add_file("/out/user-1001/hand-written.yml", "http: {}\n");
+        let mut mock_manager = MockSystemdManager::new();
+        mock_manager.expect_subscribe().returning(|| Ok(()));
+        mock_manager
+            .expect_list_units_by_patterns()
+            .returning(|_, _| Ok(vec![listed_unit("user@1000.service")]));
+        mock_manager
+            .expect_receive_unit_properties_changed()
+            .return_once(|| Ok(Box::pin(futures::stream::pending())));
+        let system = DBusContext::new_test_context(Arc::new(mock_manager), fs.clone());
+        let (tx_shutdown, rx_shutdown) = watch::channel(false);
+        let connect_user: UserConnector =
+            Arc::new(|_uid| Box::pin(async { Err(anyhow::anyhow!("no user bus")) }));
+        let mut users = UserManagers::new(
+            connect_user,
+            fs.clone(),
+            PathBuf::from("/out"),
+            Default::default(),
+            Backoff::new(Duration::from_secs(10), Duration::from_secs(10)),
+            rx_shutdown,
+        );
+        let session = tokio::spawn(async move { users.run(system).await });
+
+        wait_until("user 1001 units to be removed", || {
+            !fs.file_exists_in_memory("/out/user-1001/app.service.yml")
+        })
+        .await;
+        tx_shutdown.send(true).unwrap();
+        session.await.unwrap().unwrap();
+        assert!(fs.file_exists_in_memory("/out/user-1000/app.service.yml"));
+        assert!(fs.file_exists_in_memory("/out/user-1001/hand-written.yml"));
+    }
+
+    #[test]
+    fn test_user_paths() {
+        assert_eq!(
+            user_bus(1000),
+            Bus::Private("unix:path=/run/user/1000/systemd/private".to_string())
+        );
+        assert_eq!(
+            user_out_dir(Path::new("/out"), 1000),
+            PathBuf::from("/out/user-1000")
+        );
+        assert_eq!(uid_from_out_dir(Path::new("/out/user-1000")), Some(1000));
+        assert_eq!(uid_from_out_dir(Path::new("/out/users")), None);
+    }
+}
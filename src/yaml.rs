@@ -1,17 +1,107 @@
-use anyhow::{Result, anyhow};
+use anyhow::{Context, Result, anyhow, bail};
 use serde_yaml::{Mapping, Value};
+use std::collections::{BTreeMap, HashSet};
 
-pub fn build_traefik_file_yaml(lines: Vec<impl Into<String>>) -> Result<String> {
+use crate::{helpers::normalize, schema, unit_file::unit_name_without_type};
+
+/// Label that turns publishing a unit on or off, as with Traefik's Docker provider. It is not
+/// written to the generated YAML.
+pub const ENABLE_LABEL: &str = "traefik.enable";
+
+/// Whether the labels enable the unit: the last `traefik.enable` wins, and without one it is
+/// `exposed_by_default`.
+pub fn is_enabled(lines: &[String], exposed_by_default: bool) -> Result<bool> {
+    let mut enabled = exposed_by_default;
+    for line in lines {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if !is_enable_label(key) {
+            continue;
+        }
+        enabled = match value.trim().to_ascii_lowercase().as_str() {
+            "true" => true,
+            "false" => false,
+            other => {
+                return Err(anyhow!(
+                    "invalid {ENABLE_LABEL} value '{other}', expected true or false"
+                ));
+            }
+        };
+    }
+    Ok(enabled)
+}
+
+/// Whether a label key is `traefik.enable`, case-insensitive like all label keys.
+fn is_enable_label(key: &str) -> bool {
+    key.trim().eq_ignore_ascii_case(ENABLE_LABEL)
+}
+
+/// Address of the server created from a bare `loadBalancer.server.port` label.
+const LOCALHOST: &str = "127.0.0.1";
+
+/// Highest list index a label can use. Lists are filled up to the index, so an unbounded one could
+/// take all the memory.
+const MAX_INDEX: usize = 1024;
+
+/// Router a unit gets when its labels do not define any, as with Traefik's Docker provider.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnitDefaults {
+    /// Name of the router, from the unit's name.
+    pub name: String,
+    /// Rule of the router and of routers without one. When empty, no router is created.
+    pub rule: String,
+    /// Appended to the names of the routers, services and middlewares, e.g. `-u1000` for the
+    /// units of a user, whose names would otherwise clash with those of other users.
+    pub name_suffix: String,
+}
+
+impl UnitDefaults {
+    pub fn new(unit: &str, rule: String) -> Self {
+        let name = unit_router_name(unit);
+        Self {
+            name,
+            rule,
+            name_suffix: String::new(),
+        }
+    }
+
+    pub fn with_name_suffix(self, name_suffix: impl Into<String>) -> Self {
+        Self {
+            name_suffix: name_suffix.into(),
+            ..self
+        }
+    }
+}
+
+/// Name of the router and service generated for a unit, e.g. `app-blue` for `app@blue.service`.
+pub fn unit_router_name(unit: &str) -> String {
+    normalize(unit_name_without_type(unit))
+}
+
+/// Builds the YAML of a unit's labels. With `defaults`, what the labels leave out is filled in as
+/// the Docker provider does (see `apply_defaults`).
+pub fn build_traefik_file_yaml(
+    lines: Vec<impl Into<String>>,
+    defaults: Option<&UnitDefaults>,
+) -> Result<String> {
     use serde_yaml::{Mapping, Value};
 
     let mut root = Value::Mapping(Mapping::new());
 
     for line in lines {
-        let (path, value) = parse_assignment(line.into())?;
-        insert(&mut root, &path, value);
+        let line = line.into();
+        if line
+            .split_once('=')
+            .is_some_and(|(key, _)| is_enable_label(key))
+        {
+            continue;
+        }
+        let (path, value) = parse_assignment(line)?;
+        insert(&mut root, &path, value)?;
     }
 
-    let unwrapped = match root {
+    let mut unwrapped = match root {
         Value::Mapping(mut map) => match map.remove(Value::String("traefik".to_string())) {
             Some(Value::Mapping(inner)) => Value::Mapping(inner),
             Some(other) => other,
@@ -19,33 +109,293 @@ pub fn build_traefik_file_yaml(lines: Vec<impl Into<String>>) -> Result<String>
         },
         other => other,
     };
+    compact_sequences(&mut unwrapped, "");
+    if let Some(defaults) = defaults {
+        apply_defaults(&mut unwrapped, defaults)?;
+        if !defaults.name_suffix.is_empty() {
+            suffix_names(&mut unwrapped, &defaults.name_suffix);
+        }
+    }
 
     Ok(serde_yaml::to_string(&unwrapped)?)
 }
 
+/// Fills in the HTTP configuration the way the Docker provider does for a container: a
+/// `loadBalancer.server.port` (and `server.scheme`) becomes a server on localhost, routers without
+/// a rule get the default rule and routers without a service the unit's only service, and when
+/// there is no router one is created for it.
+fn apply_defaults(config: &mut Value, defaults: &UnitDefaults) -> Result<()> {
+    let Some(http) = config.get_mut("http").and_then(Value::as_mapping_mut) else {
+        return Ok(());
+    };
+    let mut services = vec![];
+    if let Some(map) = http.get_mut("services").and_then(Value::as_mapping_mut) {
+        for (name, service) in map.iter_mut() {
+            let name = name.as_str().unwrap_or_default().to_string();
+            if let Some(load_balancer) = service
+                .get_mut("loadBalancer")
+                .and_then(Value::as_mapping_mut)
+            {
+                add_server(&name, load_balancer)?;
+            }
+            services.push(name);
+        }
+    }
+    let sole_service = match services.as_slice() {
+        [service] => Some(service.clone()),
+        _ => None,
+    };
+    let routers = ensure_mapping(ensure_mapping_for_key(http, "routers"));
+    if routers.is_empty() && !defaults.rule.is_empty() {
+        match &sole_service {
+            Some(service) => {
+                let mut router = Mapping::new();
+                router.insert("rule".into(), defaults.rule.clone().into());
+                router.insert("service".into(), service.clone().into());
+                routers.insert(defaults.name.clone().into(), Value::Mapping(router));
+            }
+            None if services.len() > 1 => warn!(
+                "Not creating router {} as the unit has {} services, define its routers",
+                defaults.name,
+                services.len()
+            ),
+            None => {}
+        }
+    }
+    for (name, router) in routers.iter_mut() {
+        let Some(router) = router.as_mapping_mut() else {
+            continue;
+        };
+        if !router.contains_key("rule") && !defaults.rule.is_empty() {
+            router.insert("rule".into(), defaults.rule.clone().into());
+        }
+        if router.contains_key("service") {
+            continue;
+        }
+        match &sole_service {
+            Some(service) => {
+                router.insert("service".into(), service.clone().into());
+            }
+            None if services.len() > 1 => warn!(
+                "Router {} has no service and the unit has {} services to choose from",
+                name.as_str().unwrap_or_default(),
+                services.len()
+            ),
+            None => {}
+        }
+    }
+    if routers.is_empty() {
+        http.remove("routers");
+    }
+    Ok(())
+}
+
+/// Kinds of objects whose names get the suffix of `UnitDefaults::name_suffix`.
+const SUFFIXED_KINDS: [&str; 3] = ["routers", "services", "middlewares"];
+
+/// Where objects refer to others by name: the kind of the referring object, the path to the name in
+/// it (`[]` standing for every list element) and the kind of the object referred to.
+const REFERENCES: [(&str, &[&str], &str); 8] = [
+    ("routers", &["service"], "services"),
+    ("routers", &["middlewares", "[]"], "middlewares"),
+    (
+        "middlewares",
+        &["chain", "middlewares", "[]"],
+        "middlewares",
+    ),
+    (
+        "services",
+        &["weighted", "services", "[]", "name"],
+        "services",
+    ),
+    ("services", &["mirroring", "service"], "services"),
+    (
+        "services",
+        &["mirroring", "mirrors", "[]", "name"],
+        "services",
+    ),
+    ("services", &["failover", "service"], "services"),
+    ("services", &["failover", "fallback"], "services"),
+];
+
+/// Appends `suffix` to the names of the routers, services and middlewares of `config`, and to the
+/// references to them. References to objects the file does not define, e.g. `auth@file`, are kept.
+fn suffix_names(config: &mut Value, suffix: &str) {
+    let Some(sections) = config.as_mapping_mut() else {
+        return;
+    };
+    for (_, section) in sections.iter_mut() {
+        let Some(section) = section.as_mapping_mut() else {
+            continue;
+        };
+        let mut defined = BTreeMap::new();
+        for kind in SUFFIXED_KINDS {
+            let Some(objects) = section.get_mut(kind).and_then(Value::as_mapping_mut) else {
+                continue;
+            };
+            let names = objects
+                .keys()
+                .filter_map(Value::as_str)
+                .map(str::to_string)
+                .collect::<HashSet<_>>();
+            *objects = std::mem::take(objects)
+                .into_iter()
+                .map(|(name, object)| match name {
+                    Value::String(name) => (Value::String(format!("{name}{suffix}")), object),
+                    name => (name, object),
+                })
+                .collect();
+            defined.insert(kind, names);
+        }
+        for (kind, path, target) in REFERENCES {
+            let (Some(names), Some(objects)) = (
+                defined.get(target),
+                section.get_mut(kind).and_then(Value::as_mapping_mut),
+            ) else {
+                continue;
+            };
+            for (_, object) in objects.iter_mut() {
+                let mut references = vec![];
+                values_at(object, path, &mut references);
+                for reference in references {
+                    if let Value::String(name) = reference
+                        && names.contains(name.as_str())
+                    {
+                        name.push_str(suffix);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The values at `path` in `value`, where `[]` stands for every element of a list.
+fn values_at<'a>(value: &'a mut Value, path: &[&str], out: &mut Vec<&'a mut Value>) {
+    match path.split_first() {
+        None => out.push(value),
+        Some((&"[]", rest)) => {
+            if let Value::Sequence(elements) = value {
+                for element in elements {
+                    values_at(element, rest, out);
+                }
+            }
+        }
+        Some((key, rest)) => {
+            if let Some(value) = value.get_mut(*key) {
+                values_at(value, rest, out);
+            }
+        }
+    }
+}
+
+/// Turns the single `server` of a load balancer, which Traefik only reads from labels, into its
+/// `servers` list.
+fn add_server(service: &str, load_balancer: &mut Mapping) -> Result<()> {
+    let Some(server) = load_balancer.remove("server") else {
+        return Ok(());
+    };
+    if load_balancer.contains_key("servers") {
+        warn!("Ignoring loadBalancer.server of service {service}, it also has servers");
+        return Ok(());
+    }
+    let port = server
+        .get("port")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("loadBalancer.server of service {service} has no port"))?;
+    let scheme = server
+        .get("scheme")
+        .and_then(Value::as_str)
+        .unwrap_or("http");
+    let mut entry = Mapping::new();
+    entry.insert(
+        "url".into(),
+        format!("{scheme}://{LOCALHOST}:{port}").into(),
+    );
+    load_balancer.insert(
+        "servers".into(),
+        Value::Sequence(vec![Value::Mapping(entry)]),
+    );
+    Ok(())
+}
+
 #[derive(Debug)]
-enum PathItem {
+pub enum PathItem {
     Key(String),
-    KeyIndex(String, usize),
+    KeyIndex(String, Index),
 }
 
-fn parse_path(s: &str) -> Vec<PathItem> {
-    s.split('.')
-        .map(|part| {
-            if let Some(open) = part.find('[') {
-                if part.ends_with(']') {
-                    let name = &part[..open];
-                    let idx_str = &part[open + 1..part.len() - 1];
-                    let idx = idx_str.parse::<usize>().unwrap_or(0);
-                    PathItem::KeyIndex(name.to_string(), idx)
-                } else {
-                    PathItem::Key(part.to_string())
+/// Index of a list element in a label key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Index {
+    /// `[N]`, the element at N.
+    At(usize),
+    /// `[]`, the last element, or a new one if the list is empty.
+    Last,
+    /// `[+]`, a new element at the end.
+    New,
+}
+
+/// Splits a label key into its segments at dots. A segment can hold dots and other special
+/// characters when quoted in backticks (`` headers.customRequestHeaders.`X.Y` ``) or escaped with a
+/// backslash (`X\.Y`). A segment can end with an index: `servers[0]`, `servers[]` or `servers[+]`.
+fn parse_path(s: &str) -> Result<Vec<PathItem>> {
+    let mut items = vec![];
+    let mut name = String::new();
+    let mut index = None;
+    let mut chars = s.chars();
+    loop {
+        match chars.next() {
+            c @ (None | Some('.')) => {
+                if name.is_empty() {
+                    bail!("empty segment in key '{s}'");
+                }
+                let name = std::mem::take(&mut name);
+                items.push(match index.take() {
+                    Some(idx) => PathItem::KeyIndex(name, idx),
+                    None => PathItem::Key(name),
+                });
+                if c.is_none() {
+                    return Ok(items);
+                }
+            }
+            Some(c) if index.is_some() => bail!("unexpected '{c}' after index in key '{s}'"),
+            Some('\\') => match chars.next() {
+                Some(c @ ('.' | '\\' | '`' | '[')) => name.push(c),
+                Some(c) => {
+                    name.push('\\');
+                    name.push(c);
+                }
+                None => name.push('\\'),
+            },
+            Some('`') => loop {
+                match chars.next() {
+                    Some('`') => break,
+                    Some(c) => name.push(c),
+                    None => bail!("unterminated '`' in key '{s}'"),
+                }
+            },
+            Some('[') => {
+                let mut inner = String::new();
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => inner.push(c),
+                        None => bail!("unterminated '[' in key '{s}'"),
+                    }
                 }
-            } else {
-                PathItem::Key(part.to_string())
+                index = Some(match inner.as_str() {
+                    "" => Index::Last,
+                    "+" => Index::New,
+                    _ if inner.chars().all(|c| c.is_ascii_digit()) => match inner.parse() {
+                        Ok(i) if i <= MAX_INDEX => Index::At(i),
+                        _ => bail!("index '[{inner}]' in key '{s}' is above {MAX_INDEX}"),
+                    },
+                    _ => bail!("invalid index '[{inner}]' in key '{s}'"),
+                });
             }
-        })
-        .collect()
+            Some(c) => name.push(c),
+        }
+    }
 }
 
 fn parse_assignment(line: String) -> Result<(Vec<PathItem>, Value)> {
@@ -55,25 +405,31 @@ fn parse_assignment(line: String) -> Result<(Vec<PathItem>, Value)> {
     }
     let key = parts[0].trim();
     let raw_value = parts[1].trim();
+    let mut path = parse_path(key)?;
+    if !schema::canonicalize(&mut path) {
+        warn!("Unknown Traefik configuration key {key}");
+    }
 
-    let value = match serde_yaml::from_str::<Value>(raw_value) {
-        Ok(v) => v,
-        Err(_) => {
-            let s = raw_value
-                .strip_prefix('"')
-                .and_then(|s| s.strip_suffix('"'))
-                .or_else(|| {
-                    raw_value
-                        .strip_prefix('\'')
-                        .and_then(|s| s.strip_suffix('\''))
-                })
-                .unwrap_or(raw_value)
-                .to_string();
-            Value::String(s)
-        }
+    let value = if let Some(rest) = schema::forced_string(raw_value) {
+        Value::String(rest.to_string())
+    } else if let Some(value_type) = schema::value_type(&path) {
+        value_type
+            .parse(raw_value)
+            .with_context(|| format!("invalid value for {key}"))?
+    } else {
+        infer_value(raw_value)
     };
 
-    Ok((parse_path(key), value))
+    Ok((path, value))
+}
+
+/// Types a value of a key the schema does not know as YAML would. Strings are kept as they are,
+/// quotes included, as the ones meant for systemd were removed when the unit file was read.
+fn infer_value(raw_value: &str) -> Value {
+    match serde_yaml::from_str::<Value>(raw_value) {
+        Ok(Value::String(_)) | Err(_) => Value::String(raw_value.to_string()),
+        Ok(v) => v,
+    }
 }
 
 fn ensure_mapping(v: &mut Value) -> &mut Mapping {
@@ -83,7 +439,10 @@ fn ensure_mapping(v: &mut Value) -> &mut Mapping {
     v.as_mapping_mut().unwrap()
 }
 
-fn ensure_sequence_for_key<'a>(mapping: &'a mut Mapping, key: &'a str) -> &'a mut Vec<Value> {
+fn ensure_sequence_for_key<'a>(
+    mapping: &'a mut Mapping,
+    key: &'a str,
+) -> Result<&'a mut Vec<Value>> {
     let k = Value::String(key.to_string());
     if !mapping.contains_key(&k) {
         mapping.insert(k.clone(), Value::Sequence(Vec::new()));
@@ -92,7 +451,7 @@ fn ensure_sequence_for_key<'a>(mapping: &'a mut Mapping, key: &'a str) -> &'a mu
         .get_mut(&k)
         .unwrap()
         .as_sequence_mut()
-        .expect("value is not a sequence")
+        .ok_or_else(|| anyhow!("key {key} used both as a value and a list"))
 }
 
 fn ensure_mapping_for_key<'a>(mapping: &'a mut Mapping, key: &'a str) -> &'a mut Value {
@@ -103,10 +462,10 @@ fn ensure_mapping_for_key<'a>(mapping: &'a mut Mapping, key: &'a str) -> &'a mut
     mapping.get_mut(&k).unwrap()
 }
 
-fn insert(root: &mut Value, path: &[PathItem], val: Value) {
+fn insert(root: &mut Value, path: &[PathItem], val: Value) -> Result<()> {
     if path.is_empty() {
         *root = val;
-        return;
+        return Ok(());
     }
 
     let mut cur = root;
@@ -117,29 +476,151 @@ fn insert(root: &mut Value, path: &[PathItem], val: Value) {
                 let mapping = ensure_mapping(cur);
                 if is_last {
                     mapping.insert(Value::String(k.clone()), val);
-                    return;
+                    return Ok(());
                 } else {
                     cur = ensure_mapping_for_key(mapping, k);
                 }
             }
-            PathItem::KeyIndex(k, idx) => {
+            PathItem::KeyIndex(k, index) => {
                 let mapping = ensure_mapping(cur);
-                let seq = ensure_sequence_for_key(mapping, k);
-                while seq.len() <= *idx {
+                let seq = ensure_sequence_for_key(mapping, k)?;
+                let idx = match *index {
+                    Index::At(idx) => idx,
+                    Index::Last if !seq.is_empty() => seq.len() - 1,
+                    Index::Last | Index::New => seq.len(),
+                };
+                while seq.len() <= idx {
                     seq.push(Value::Null);
                 }
                 if is_last {
-                    seq[*idx] = val;
-                    return;
+                    seq[idx] = val;
+                    return Ok(());
                 } else {
-                    if !seq[*idx].is_mapping() {
-                        seq[*idx] = Value::Mapping(Mapping::new());
+                    if !seq[idx].is_mapping() {
+                        seq[idx] = Value::Mapping(Mapping::new());
                     }
-                    cur = &mut seq[*idx];
+                    cur = &mut seq[idx];
                 }
             }
         }
     }
+    Ok(())
+}
+
+/// Removes the `null` entries that skipped indexes leave in lists, as Traefik rejects them.
+fn compact_sequences(value: &mut Value, path: &str) {
+    match value {
+        Value::Mapping(map) => {
+            for (k, v) in map.iter_mut() {
+                let key = k.as_str().unwrap_or_default();
+                let child = if path.is_empty() {
+                    key.to_string()
+                } else {
+                    format!("{path}.{key}")
+                };
+                compact_sequences(v, &child);
+            }
+        }
+        Value::Sequence(seq) => {
+            let len = seq.len();
+            seq.retain(|v| !v.is_null());
+            if seq.len() != len {
+                warn!(
+                    "Removed {} empty entries from list {path}, check its indexes",
+                    len - seq.len()
+                );
+            }
+            for (i, v) in seq.iter_mut().enumerate() {
+                compact_sequences(v, &format!("{path}[{i}]"));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Keys (as dotted paths) that differ between two YAML documents.
+#[derive(Debug, Default, PartialEq)]
+pub struct YamlDiff {
+    pub added: Vec<String>,
+    pub changed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl YamlDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+pub fn diff_yaml(old: &Value, new: &Value) -> YamlDiff {
+    let mut old_leaves = BTreeMap::new();
+    flatten(old, String::new(), &mut old_leaves);
+    let mut new_leaves = BTreeMap::new();
+    flatten(new, String::new(), &mut new_leaves);
+
+    let mut diff = YamlDiff::default();
+    for (key, new_value) in &new_leaves {
+        match old_leaves.get(key) {
+            None => diff.added.push(key.clone()),
+            Some(old_value) if old_value != new_value => diff.changed.push(key.clone()),
+            Some(_) => {}
+        }
+    }
+    diff.removed = old_leaves
+        .into_keys()
+        .filter(|key| !new_leaves.contains_key(key))
+        .collect();
+    diff
+}
+
+/// The named objects a file defines, e.g. `http.routers.app`, with their configuration. Traefik's
+/// file provider merges all files, so these names have to be unique across them.
+pub fn definitions(yaml: &Value) -> BTreeMap<String, Value> {
+    let mut definitions = BTreeMap::new();
+    let sections = yaml.as_mapping().into_iter().flatten();
+    for (section, kinds) in sections.filter_map(|(k, v)| Some((k.as_str()?, v.as_mapping()?))) {
+        for (kind, objects) in kinds
+            .iter()
+            .filter_map(|(k, v)| Some((k.as_str()?, v.as_mapping()?)))
+        {
+            for (name, object) in objects {
+                if let Some(name) = name.as_str() {
+                    definitions.insert(format!("{section}.{kind}.{name}"), object.clone());
+                }
+            }
+        }
+    }
+    definitions
+}
+
+fn flatten<'a>(value: &'a Value, prefix: String, out: &mut BTreeMap<String, &'a Value>) {
+    match value {
+        Value::Mapping(map) if !map.is_empty() => {
+            for (k, v) in map {
+                let key = match k {
+                    Value::String(s) => s.clone(),
+                    other => serde_yaml::to_string(other)
+                        .unwrap_or_default()
+                        .trim()
+                        .to_string(),
+                };
+                let path = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                flatten(v, path, out);
+            }
+        }
+        Value::Sequence(seq) if !seq.is_empty() => {
+            for (i, v) in seq.iter().enumerate() {
+                flatten(v, format!("{prefix}[{i}]"), out);
+            }
+        }
+        other => {
+            out.insert(prefix, other);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -152,14 +633,14 @@ mod tests {
         let mut root = Value::Mapping(Mapping::new());
         for line in input {
             let (path, value) = parse_assignment(line.to_string()).unwrap();
-            insert(&mut root, &path, value);
+            insert(&mut root, &path, value).unwrap();
         }
         root
     }
 
     #[test]
     fn simple_nested_keys() {
-        let v = yaml(&[r#"a.b.c = "value""#]);
+        let v = yaml(&[r#"a.b.c = value"#]);
 
         let expected = serde_yaml::from_str::<Value>(
             r#"
@@ -175,7 +656,7 @@ a:
 
     #[test]
     fn array_index_creates_sequence() {
-        let v = yaml(&[r#"a.items[0].name = "foo""#]);
+        let v = yaml(&[r#"a.items[0].name = foo"#]);
 
         let expected = serde_yaml::from_str::<Value>(
             r#"
@@ -191,7 +672,7 @@ a:
 
     #[test]
     fn sparse_array_is_filled_with_nulls() {
-        let v = yaml(&[r#"a.items[2] = "x""#]);
+        let v = yaml(&[r#"a.items[2] = x"#]);
 
         let expected = serde_yaml::from_str::<Value>(
             r#"
@@ -207,6 +688,352 @@ a:
         assert_eq!(v, expected);
     }
 
+    #[test]
+    fn quoted_and_escaped_segments_keep_dots() {
+        let v = yaml(&[
+            r#"a.`b.example.com`.c = 1"#,
+            r#"a.b\.example\.com.d = 2"#,
+            r#"a.`x[0]` = 3"#,
+        ]);
+
+        let expected = serde_yaml::from_str::<Value>(
+            r#"
+a:
+  b.example.com:
+    c: 1
+    d: 2
+  x[0]: 3
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn invalid_paths_are_errors() {
+        for key in [
+            "a.servers[x].url",
+            "a.servers[++].url",
+            "a.servers[1",
+            "a.servers[0]x",
+            "a..b",
+            ".a",
+            "a.`b",
+            "a.servers[1025].url",
+            "a.servers[4000000000].url",
+            "a.servers[99999999999999999999999].url",
+        ] {
+            assert!(
+                parse_assignment(format!("{key} = 1")).is_err(),
+                "should not parse: {key}"
+            );
+        }
+        assert!(parse_assignment("a.servers[1024].url = 1".to_string()).is_ok());
+    }
+
+    #[test]
+    fn append_syntax_adds_and_continues_elements() {
+        let v = yaml(&[
+            r#"a.servers[+].url = http://a"#,
+            r#"a.servers[].weight = 2"#,
+            r#"a.servers[+].url = http://b"#,
+            r#"a.servers[0].weight = 1"#,
+            r#"a.servers[].weight = 3"#,
+            r#"a.names[] = x"#,
+            r#"a.names[+] = y"#,
+        ]);
+
+        let expected = serde_yaml::from_str::<Value>(
+            r#"
+a:
+  servers:
+    - url: http://a
+      weight: 1
+    - url: http://b
+      weight: 3
+  names: [x, y]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn keys_used_as_a_value_and_a_list_are_errors() {
+        let error = |lines: &[&str]| {
+            build_traefik_file_yaml(lines.to_vec(), None)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(&[
+                "traefik.http.routers.r.rule=x",
+                "traefik.http.routers.r.rule[]=y"
+            ]),
+            "key rule used both as a value and a list"
+        );
+        assert_eq!(
+            error(&["traefik.a.b.c=x", "traefik.a.b[+]=y"]),
+            "key b used both as a value and a list"
+        );
+    }
+
+    #[test]
+    fn null_holes_are_removed_from_lists() {
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.services.s1.loadbalancer.servers[0].url=http://a",
+                "traefik.http.services.s1.loadbalancer.servers[2].url=http://b",
+            ],
+            None,
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  services:
+    s1:
+      loadBalancer:
+        servers:
+          - url: http://a
+          - url: http://b
+"#,
+        );
+
+        assert_eq!(normalize_yaml(&yaml), expected);
+    }
+
+    fn app_defaults() -> UnitDefaults {
+        UnitDefaults::new("app.service", "Host(`app`)".to_string())
+    }
+
+    #[test]
+    fn unit_defaults_are_named_after_the_unit() {
+        assert_eq!(
+            UnitDefaults::new("app@blue.service", String::new()).name,
+            "app-blue"
+        );
+    }
+
+    #[test]
+    fn name_suffix_renames_objects_and_references_to_them() {
+        let defaults = app_defaults().with_name_suffix("-u1000");
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.routers.public.middlewares[0]=auth",
+                "traefik.http.routers.public.middlewares[1]=shared@file",
+                "traefik.http.routers.admin.rule=Host(`admin.example.com`)",
+                "traefik.http.routers.admin.service=noop@internal",
+                "traefik.http.middlewares.auth.chain.middlewares[0]=strip",
+                "traefik.http.middlewares.strip.stripprefix.prefixes[0]=/app",
+                "traefik.http.services.web.loadbalancer.server.port=8080",
+                "traefik.tcp.routers.db.service=db",
+                "traefik.tcp.services.db.loadbalancer.servers[0].address=127.0.0.1:5432",
+            ],
+            Some(&defaults),
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  routers:
+    public-u1000:
+      rule: Host(`app`)
+      service: web-u1000
+      middlewares: [auth-u1000, shared@file]
+    admin-u1000:
+      rule: Host(`admin.example.com`)
+      service: noop@internal
+  middlewares:
+    auth-u1000:
+      chain:
+        middlewares: [strip-u1000]
+    strip-u1000:
+      stripPrefix:
+        prefixes: [/app]
+  services:
+    web-u1000:
+      loadBalancer:
+        servers:
+          - url: http://127.0.0.1:8080
+tcp:
+  routers:
+    db-u1000:
+      service: db-u1000
+  services:
+    db-u1000:
+      loadBalancer:
+        servers:
+          - address: 127.0.0.1:5432
+"#,
+        );
+        assert_eq!(normalize_yaml(&yaml), expected);
+
+        let yaml = build_traefik_file_yaml(
+            vec!["traefik.http.services.web.loadbalancer.server.port=8080"],
+            Some(&defaults),
+        )
+        .unwrap();
+        assert_eq!(
+            normalize_yaml(&yaml)["http"]["routers"]["app-u1000"]["service"],
+            Value::from("web-u1000")
+        );
+    }
+
+    #[test]
+    fn bare_port_gets_a_server_and_a_router() {
+        let defaults = app_defaults();
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.services.web.loadbalancer.server.port=8080",
+                "traefik.http.services.web.loadbalancer.passhostheader=false",
+            ],
+            Some(&defaults),
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  services:
+    web:
+      loadBalancer:
+        passHostHeader: false
+        servers:
+          - url: http://127.0.0.1:8080
+  routers:
+    app:
+      rule: Host(`app`)
+      service: web
+"#,
+        );
+        assert_eq!(normalize_yaml(&yaml), expected);
+
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.services.web.loadbalancer.server.port=8443",
+                "traefik.http.services.web.loadbalancer.server.scheme=https",
+            ],
+            Some(&defaults),
+        )
+        .unwrap();
+        assert_eq!(
+            normalize_yaml(&yaml)["http"]["services"]["web"]["loadBalancer"]["servers"][0]["url"],
+            Value::String("https://127.0.0.1:8443".to_string())
+        );
+    }
+
+    #[test]
+    fn routers_without_service_use_the_sole_service() {
+        let defaults = app_defaults();
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.routers.public.rule=Host(`app.example.com`)",
+                "traefik.http.routers.admin.rule=Host(`admin.example.com`)",
+                "traefik.http.routers.admin.service=noop@internal",
+                "traefik.http.services.web.loadbalancer.servers[0].url=http://10.0.0.1",
+            ],
+            Some(&defaults),
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  routers:
+    public:
+      rule: Host(`app.example.com`)
+      service: web
+    admin:
+      rule: Host(`admin.example.com`)
+      service: noop@internal
+  services:
+    web:
+      loadBalancer:
+        servers:
+          - url: http://10.0.0.1
+"#,
+        );
+        assert_eq!(normalize_yaml(&yaml), expected);
+    }
+
+    #[test]
+    fn routers_without_rule_get_the_default_rule() {
+        let labels = vec![
+            "traefik.http.routers.app.entrypoints=websecure",
+            "traefik.http.routers.admin.rule=Host(`admin.example.com`)",
+            "traefik.http.services.web.loadbalancer.server.port=8080",
+        ];
+        let yaml = build_traefik_file_yaml(labels.clone(), Some(&app_defaults())).unwrap();
+        let routers = &normalize_yaml(&yaml)["http"]["routers"];
+        assert_eq!(routers["app"]["rule"], Value::from("Host(`app`)"));
+        assert_eq!(
+            routers["admin"]["rule"],
+            Value::from("Host(`admin.example.com`)")
+        );
+
+        // an empty default rule leaves routers as they are and creates none
+        let defaults = UnitDefaults::new("app.service", String::new());
+        let yaml = build_traefik_file_yaml(labels, Some(&defaults)).unwrap();
+        assert!(
+            normalize_yaml(&yaml)["http"]["routers"]["app"]
+                .get("rule")
+                .is_none()
+        );
+        let yaml = build_traefik_file_yaml(
+            vec!["traefik.http.services.web.loadbalancer.server.port=8080"],
+            Some(&defaults),
+        )
+        .unwrap();
+        assert!(normalize_yaml(&yaml)["http"].get("routers").is_none());
+    }
+
+    #[test]
+    fn defaults_need_a_single_service() {
+        let defaults = app_defaults();
+        let labels = vec![
+            "traefik.http.routers.public.rule=Host(`app.example.com`)",
+            "traefik.http.services.web.loadbalancer.server.port=8080",
+            "traefik.http.services.api.loadbalancer.server.port=9090",
+        ];
+        let yaml = normalize_yaml(&build_traefik_file_yaml(labels, Some(&defaults)).unwrap());
+        assert_eq!(
+            yaml["http"]["routers"],
+            normalize_yaml("public:\n  rule: Host(`app.example.com`)")
+        );
+
+        let labels = vec![
+            "traefik.http.services.web.loadbalancer.server.port=8080",
+            "traefik.http.services.api.loadbalancer.server.port=9090",
+        ];
+        let yaml = normalize_yaml(&build_traefik_file_yaml(labels, Some(&defaults)).unwrap());
+        assert!(yaml["http"].get("routers").is_none());
+
+        let labels = vec!["traefik.tcp.routers.db.rule=HostSNI(`*`)"];
+        let yaml = normalize_yaml(&build_traefik_file_yaml(labels, Some(&defaults)).unwrap());
+        assert_eq!(
+            yaml,
+            normalize_yaml("tcp:\n  routers:\n    db:\n      rule: HostSNI(`*`)")
+        );
+    }
+
+    #[test]
+    fn server_without_port_is_an_error() {
+        let error = build_traefik_file_yaml(
+            vec!["traefik.http.services.web.loadbalancer.server.scheme=https"],
+            Some(&app_defaults()),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "loadBalancer.server of service web has no port"
+        );
+    }
+
     #[test]
     fn multiple_assignments_merge_tree() {
         let v = yaml(&[r#"a.b.c = 1"#, r#"a.b.d = 2"#, r#"a.e = 3"#]);
@@ -227,7 +1054,7 @@ a:
 
     #[test]
     fn overwrite_scalar_with_mapping() {
-        let v = yaml(&[r#"a.b = "scalar""#, r#"a.b.c = "nested""#]);
+        let v = yaml(&[r#"a.b = scalar"#, r#"a.b.c = nested"#]);
 
         let expected = serde_yaml::from_str::<Value>(
             r#"
@@ -243,7 +1070,7 @@ a:
 
     #[test]
     fn overwrite_mapping_with_scalar() {
-        let v = yaml(&[r#"a.b.c = "nested""#, r#"a.b = "scalar""#]);
+        let v = yaml(&[r#"a.b.c = nested"#, r#"a.b = scalar"#]);
 
         let expected = serde_yaml::from_str::<Value>(
             r#"
@@ -285,8 +1112,8 @@ x:
     #[test]
     fn example_from_traefik() {
         let v = yaml(&[
-            r#"traefik.http.routers.my_router.tls.domains[0].main = "*.some.com""#,
-            r#"traefik.http.routers.my_router.entrypoints = "websecure""#,
+            r#"traefik.http.routers.my_router.tls.domains[0].main = *.some.com"#,
+            r#"traefik.http.routers.my_router.entrypoints = websecure"#,
         ]);
 
         let expected = serde_yaml::from_str::<Value>(
@@ -295,7 +1122,8 @@ traefik:
   http:
     routers:
       my_router:
-        entrypoints: websecure
+        entryPoints:
+          - websecure
         tls:
           domains:
             - main: "*.some.com"
@@ -312,10 +1140,13 @@ traefik:
 
     #[test]
     fn unwraps_traefik_root_basic() {
-        let yaml = build_traefik_file_yaml(vec![
-            r#"traefik.http.routers.my_router.entrypoints = "websecure""#,
-            r#"traefik.http.routers.my_router.rule = "Host(`example.com`)""#,
-        ])
+        let yaml = build_traefik_file_yaml(
+            vec![
+                r#"traefik.http.routers.my_router.entrypoints = websecure"#,
+                r#"traefik.http.routers.my_router.rule = Host(`example.com`)"#,
+            ],
+            None,
+        )
         .unwrap();
 
         let expected = normalize_yaml(
@@ -323,7 +1154,8 @@ traefik:
 http:
   routers:
     my_router:
-      entrypoints: websecure
+      entryPoints:
+        - websecure
       rule: Host(`example.com`)
 "#,
         );
@@ -333,11 +1165,14 @@ http:
 
     #[test]
     fn preserves_multiple_traefik_children() {
-        let yaml = build_traefik_file_yaml(vec![
-            r#"traefik.http.routers.r1.rule = "Host(`a.example.com`)""#,
-            r#"traefik.http.services.s1.loadbalancer.servers[0].url = "http://1.1.1.1""#,
-            r#"traefik.tcp.routers.t1.rule = "HostSNI(`*`)""#,
-        ])
+        let yaml = build_traefik_file_yaml(
+            vec![
+                r#"traefik.http.routers.r1.rule = Host(`a.example.com`)"#,
+                r#"traefik.http.services.s1.loadbalancer.servers[0].url = http://1.1.1.1"#,
+                r#"traefik.tcp.routers.t1.rule = HostSNI(`*`)"#,
+            ],
+            None,
+        )
         .unwrap();
 
         let expected = normalize_yaml(
@@ -348,7 +1183,7 @@ http:
       rule: Host(`a.example.com`)
   services:
     s1:
-      loadbalancer:
+      loadBalancer:
         servers:
           - url: http://1.1.1.1
 tcp:
@@ -363,11 +1198,14 @@ tcp:
 
     #[test]
     fn matches_docs_style_example() {
-        let yaml = build_traefik_file_yaml(vec![
-            r#"traefik.http.routers.router0.rule = "Host(`foo.bar`)""#,
-            r#"traefik.http.routers.router0.service = "service0""#,
-            r#"traefik.http.services.service0.loadbalancer.servers[0].url = "http://10.0.0.1""#,
-        ])
+        let yaml = build_traefik_file_yaml(
+            vec![
+                r#"traefik.http.routers.router0.rule = Host(`foo.bar`)"#,
+                r#"traefik.http.routers.router0.service = service0"#,
+                r#"traefik.http.services.service0.loadbalancer.servers[0].url = http://10.0.0.1"#,
+            ],
+            None,
+        )
         .unwrap();
 
         let expected = normalize_yaml(
@@ -379,7 +1217,7 @@ http:
       service: service0
   services:
     service0:
-      loadbalancer:
+      loadBalancer:
         servers:
           - url: http://10.0.0.1
 "#,
@@ -388,9 +1226,285 @@ http:
         assert_eq!(normalize_yaml(&yaml), expected);
     }
 
+    #[test]
+    fn values_are_typed_by_the_schema() {
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.routers.r1.priority=10",
+                "traefik.http.routers.r1.service=0123",
+                "traefik.http.routers.r1.entrypoints=web,websecure",
+                "traefik.http.routers.r1.tls.domains[0].sans=a.example.com, b.example.com",
+                "traefik.http.middlewares.auth.basicauth.users=admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/,user:$apr1$d9hr9HBB$4HxwgUir3HP4EsggP/QNo0",
+                "traefik.http.middlewares.retry.retry.initialinterval=100ms",
+                "traefik.http.services.s1.loadbalancer.passhostheader=False",
+            ],
+            None,
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  routers:
+    r1:
+      priority: 10
+      service: "0123"
+      entryPoints: [web, websecure]
+      tls:
+        domains:
+          - sans: [a.example.com, b.example.com]
+  middlewares:
+    auth:
+      basicAuth:
+        users:
+          - admin:$apr1$H6uskkkW$IgXLP6ewTrSuBkTrqE8wj/
+          - user:$apr1$d9hr9HBB$4HxwgUir3HP4EsggP/QNo0
+    retry:
+      retry:
+        initialInterval: 100ms
+  services:
+    s1:
+      loadBalancer:
+        passHostHeader: false
+"#,
+        );
+
+        assert_eq!(normalize_yaml(&yaml), expected);
+    }
+
+    #[test]
+    fn keys_are_canonicalized_and_case_variants_merged() {
+        let yaml = build_traefik_file_yaml(
+            vec![
+                "traefik.http.routers.App.tls.certresolver=le",
+                "traefik.http.services.App.loadbalancer.server.port=8080",
+                "traefik.http.services.App.loadBalancer.passhostheader=true",
+                "traefik.http.services.App.LOADBALANCER.PassHostHeader=false",
+                "traefik.http.middlewares.h.headers.customrequestheaders.X-Custom=a",
+            ],
+            None,
+        )
+        .unwrap();
+
+        let expected = normalize_yaml(
+            r#"
+http:
+  routers:
+    App:
+      tls:
+        certResolver: le
+  services:
+    App:
+      loadBalancer:
+        server:
+          port: "8080"
+        passHostHeader: false
+  middlewares:
+    h:
+      headers:
+        customRequestHeaders:
+          X-Custom: a
+"#,
+        );
+
+        assert_eq!(normalize_yaml(&yaml), expected);
+    }
+
+    #[test]
+    fn str_tag_forces_a_string() {
+        let v = yaml(&[
+            "traefik.http.routers.r1.entrypoints=!!str web,websecure",
+            "traefik.http.routers.r1.priority=!!str 10",
+            "traefik.other=!!str true",
+        ]);
+
+        let expected = serde_yaml::from_str::<Value>(
+            r#"
+traefik:
+  http:
+    routers:
+      r1:
+        entryPoints: web,websecure
+        priority: "10"
+  other: "true"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(v, expected);
+    }
+
+    #[test]
+    fn invalid_typed_values_are_errors() {
+        let error = build_traefik_file_yaml(vec!["traefik.http.routers.r1.priority=high"], None)
+            .unwrap_err();
+        assert_eq!(
+            format!("{error:#}"),
+            "invalid value for traefik.http.routers.r1.priority: expected an integer, got 'high'"
+        );
+        assert!(
+            build_traefik_file_yaml(vec!["traefik.http.routers.r1.tls.passthrough=yes"], None)
+                .is_ok()
+        );
+        assert!(
+            build_traefik_file_yaml(vec!["traefik.tcp.routers.r1.tls.passthrough=yes"], None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn quotes_are_part_of_values() {
+        // the quotes meant for systemd were removed when the unit file was read, these are escaped
+        let result = build_traefik_file_yaml(
+            vec![
+                r#"traefik.http.routers.r.rule="Host(`a`)""#,
+                r#"traefik.http.routers.r.entrypoints='web'"#,
+                r#"traefik.other="x""#,
+            ],
+            None,
+        )
+        .unwrap();
+        let result = serde_yaml::from_str::<Value>(&result).unwrap();
+        assert_eq!(result["http"]["routers"]["r"]["rule"], r#""Host(`a`)""#);
+        assert_eq!(result["http"]["routers"]["r"]["entryPoints"][0], "'web'");
+        assert_eq!(result["other"], r#""x""#);
+    }
+
+    #[test]
+    fn plugin_settings_keep_their_names() {
+        let result = build_traefik_file_yaml(
+            vec![
+                "traefik.http.middlewares.m.plugin.rewriteHeaders.rewrites[0].Header=X-A",
+                "traefik.http.middlewares.m.plugin.rewriteHeaders.maxAge=60",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            normalize_yaml(&result),
+            normalize_yaml(
+                "http:\n  middlewares:\n    m:\n      plugin:\n        rewriteHeaders:\n          \
+                 rewrites:\n            - Header: X-A\n          maxAge: 60\n"
+            )
+        );
+    }
+
+    #[test]
+    fn enable_label_is_not_written() {
+        let result = build_traefik_file_yaml(
+            vec![
+                "traefik.enable=true",
+                "Traefik.Enable=true",
+                "traefik.http.routers.r1.rule=Host(`a`)",
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            normalize_yaml(&result),
+            normalize_yaml("http:\n  routers:\n    r1:\n      rule: Host(`a`)\n")
+        );
+    }
+
+    #[test]
+    fn is_enabled_uses_last_enable_label_or_default() {
+        let labels = |l: &[&str]| l.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(is_enabled(&labels(&["traefik.http.a=1"]), true).unwrap());
+        assert!(!is_enabled(&labels(&["traefik.http.a=1"]), false).unwrap());
+        assert!(is_enabled(&labels(&["traefik.enable=true"]), false).unwrap());
+        assert!(is_enabled(&labels(&["traefik.enable = TRUE"]), false).unwrap());
+        assert!(!is_enabled(&labels(&["traefik.enable=false"]), true).unwrap());
+        assert!(!is_enabled(&labels(&["Traefik.Enable=false"]), true).unwrap());
+        assert!(
+            !is_enabled(
+                &labels(&["traefik.enable=true", "traefik.enable=false"]),
+                true
+            )
+            .unwrap()
+        );
+        assert!(is_enabled(&labels(&["traefik.enable=yes"]), true).is_err());
+    }
+
+    #[test]
+    fn diff_yaml_reports_added_changed_and_removed_keys() {
+        let old = normalize_yaml(
+            r#"
+http:
+  routers:
+    r1:
+      rule: Host(`a`)
+      entrypoints: web
+  services:
+    s1:
+      loadbalancer:
+        servers:
+          - url: http://1.1.1.1
+"#,
+        );
+        let new = normalize_yaml(
+            r#"
+http:
+  routers:
+    r1:
+      rule: Host(`b`)
+      tls: true
+  services:
+    s1:
+      loadbalancer:
+        servers:
+          - url: http://1.1.1.1
+"#,
+        );
+
+        let diff = diff_yaml(&old, &new);
+
+        assert_eq!(
+            diff,
+            YamlDiff {
+                added: vec!["http.routers.r1.tls".to_string()],
+                changed: vec!["http.routers.r1.rule".to_string()],
+                removed: vec!["http.routers.r1.entrypoints".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn diff_yaml_of_equal_documents_is_empty() {
+        let v = normalize_yaml("a:\n  b: [1, 2]\n");
+        assert!(diff_yaml(&v, &v).is_empty());
+    }
+
+    #[test]
+    fn definitions_are_the_named_objects_of_each_section() {
+        let yaml = normalize_yaml(
+            r#"
+http:
+  routers:
+    r1:
+      rule: Host(`a`)
+  middlewares:
+    m1:
+      compress: {}
+tcp:
+  services:
+    s1:
+      weighted: {}
+tls:
+  certificates:
+    - certFile: a.crt
+"#,
+        );
+
+        assert_eq!(
+            definitions(&yaml).into_keys().collect::<Vec<_>>(),
+            vec!["http.middlewares.m1", "http.routers.r1", "tcp.services.s1"]
+        );
+    }
+
     #[test]
     fn no_traefik_root_is_left_untouched() {
-        let yaml = build_traefik_file_yaml(vec![r#"http.routers.r1.rule = "Host(`x`)""#]).unwrap();
+        let yaml =
+            build_traefik_file_yaml(vec![r#"http.routers.r1.rule = Host(`x`)"#], None).unwrap();
 
         let expected = normalize_yaml(
             r#"
@@ -431,10 +1545,55 @@ mod proptests {
 
     proptest! {
         #[test]
-        fn prop_parse_path_never_panics(path in path_strategy()) {
+        fn prop_parse_path_never_panics(path in ".*") {
             let _ = parse_path(&path);
         }
 
+        #[test]
+        fn prop_quoted_segments_round_trip(segments in prop::collection::vec("[^`]{1,10}", 1..5)) {
+            let key = segments.iter().map(|s| format!("`{s}`")).collect::<Vec<_>>().join(".");
+            let items = parse_path(&key).unwrap();
+            let names = items
+                .into_iter()
+                .map(|item| match item {
+                    PathItem::Key(k) => k,
+                    PathItem::KeyIndex(k, _) => panic!("unexpected index in {k}"),
+                })
+                .collect::<Vec<_>>();
+            prop_assert_eq!(names, segments);
+        }
+
+        #[test]
+        fn prop_escaped_segments_round_trip(segments in prop::collection::vec("[a-z.`\\\\\\[]{1,10}", 1..5)) {
+            let key = segments
+                .iter()
+                .map(|s| {
+                    s.chars()
+                        .flat_map(|c| match c {
+                            '.' | '`' | '\\' | '[' => vec!['\\', c],
+                            c => vec![c],
+                        })
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>()
+                .join(".");
+            let items = parse_path(&key).unwrap();
+            prop_assert_eq!(items.len(), segments.len());
+            for (item, segment) in items.into_iter().zip(&segments) {
+                match item {
+                    PathItem::Key(k) => prop_assert_eq!(&k, segment),
+                    PathItem::KeyIndex(k, _) => prop_assert!(false, "unexpected index in {}", k),
+                }
+            }
+        }
+
+        #[test]
+        fn prop_non_numeric_index_is_an_error(idx in "[^0-9\\]]*[^0-9\\]][^\\]]*") {
+            prop_assume!(idx != "+");
+            let key = format!("servers[{idx}].url");
+            prop_assert!(parse_path(&key).is_err(), "should not parse: {}", key);
+        }
+
         #[test]
         fn prop_parse_assignment_with_valid_input_succeeds(
             path in path_strategy(),
@@ -447,7 +1606,7 @@ mod proptests {
 
         #[test]
         fn prop_parse_path_items_are_valid(path in path_strategy()) {
-            let items = parse_path(&path);
+            let items = parse_path(&path).unwrap();
             prop_assert!(!items.is_empty() || path.is_empty());
             for item in items {
                 match item {
@@ -468,7 +1627,7 @@ mod proptests {
                 .iter()
                 .map(|(path, value)| format!("{} = {}", path, value))
                 .collect();
-            let result = build_traefik_file_yaml(lines);
+            let result = build_traefik_file_yaml(lines, None);
             prop_assert!(result.is_ok(), "Failed to build YAML");
 
             let yaml_str = result.unwrap();
@@ -479,9 +1638,9 @@ mod proptests {
         #[test]
         fn prop_index_parsing_never_panics(idx_str in r"[0-9]{1,3}") {
             let path = format!("key[{}]", idx_str);
-            let items = parse_path(&path);
+            let items = parse_path(&path).unwrap();
             prop_assert!(items.len() == 1);
-            if let PathItem::KeyIndex(_, idx) = &items[0] {
+            if let PathItem::KeyIndex(_, Index::At(idx)) = &items[0] {
                 prop_assert!(idx >= &0);
             }
         }
@@ -491,7 +1650,7 @@ mod proptests {
             let assignment = format!("items[{}] = \"value\"", sparse_idx);
             let (path, value) = parse_assignment(assignment).unwrap();
             let mut root = Value::Mapping(serde_yaml::Mapping::new());
-            insert(&mut root, &path, value);
+            insert(&mut root, &path, value).unwrap();
 
             if let Value::Mapping(map) = &root {
                 let key = Value::String("items".to_string());
@@ -508,7 +1667,7 @@ mod proptests {
 
         #[test]
         fn prop_parse_path_with_empty_components(s in "a(\\.a){0,3}") {
-            let items = parse_path(&s);
+            let items = parse_path(&s).unwrap();
             for item in items {
                 match item {
                     PathItem::Key(k) => prop_assert!(!k.is_empty()),
@@ -529,7 +1688,7 @@ mod proptests {
                 .map(|(path, value)| format!("traefik.{} = {}", path, value))
                 .collect();
 
-            let yaml = build_traefik_file_yaml(lines).unwrap();
+            let yaml = build_traefik_file_yaml(lines, None).unwrap();
 
             let parsed = serde_yaml::from_str::<Value>(&yaml).unwrap();
             if let Value::Mapping(map) = parsed {